@@ -0,0 +1,123 @@
+//! Translates Brainf*ck into a standalone C programme, so that any C compiler can build it.
+//!
+//! The C programme behaves the same as the other engines. The tape has `u16::MAX + 1` cells
+//! and the pointer is `uint16_t` so that it wraps around. EOF reads as 0, and io errors end the programme.
+//! Newlines need nothing special as C streams in text mode already convert "\r\n" on Windows,
+//! the same as [`crate::putchar`] and [`crate::getchar`].
+
+use crate::ir::{Node, NodeKind};
+use std::fmt::Write;
+
+const PRELUDE: &str = r#"#include <stdint.h>
+#include <stdio.h>
+
+static unsigned char tape[65536];
+
+/* Writes `cell` into the stdout. Returns 0 on errors. */
+static int output(unsigned char cell) {
+    return putchar(cell) != EOF;
+}
+
+/* Reads a byte from the stdin into `cell`, or 0 at EOF. Returns 0 on errors.
+   Flushes the stdout first, so that a prompt appears before waiting for the answer. */
+static int input(unsigned char *cell) {
+    if (fflush(stdout) == EOF) {
+        return 0;
+    }
+    int c = getchar();
+    if (c == EOF) {
+        if (ferror(stdin)) {
+            return 0;
+        }
+        c = 0;
+    }
+    *cell = (unsigned char)c;
+    return 1;
+}
+
+int main(void) {
+    uint16_t p = 0;
+"#;
+
+const POSTLUDE: &str = r#"    if (fflush(stdout) == EOF) {
+        goto error;
+    }
+    return 0;
+error:
+    fputs("io-error during execution\n", stderr);
+    return 1;
+}
+"#;
+
+/// Returns the source of a C programme equivalent to `program`.
+pub fn transpile(program: &[Node]) -> String {
+    let mut source = String::from(PRELUDE);
+    lower(&mut source, program, 1);
+    source.push_str(POSTLUDE);
+    source
+}
+
+/// Writes statements for `nodes` into `source`, indented by `depth` levels.
+fn lower(source: &mut String, nodes: &[Node], depth: usize) {
+    let indent = "    ".repeat(depth);
+    for node in nodes {
+        // `fmt::Write` for `String` never fails.
+        let _ = match node.kind {
+            NodeKind::Move { amount } if amount < 0 => {
+                writeln!(source, "{indent}p -= {};", amount.unsigned_abs())
+            }
+            NodeKind::Move { amount } => writeln!(source, "{indent}p += {amount};"),
+            // The cells are 8 bits, so amounts are truncated to them.
+            // Subtracting looks better than adding large numbers, while both wrap around the same.
+            NodeKind::Add { offset, amount } if (amount as i8) < 0 => writeln!(
+                source,
+                "{indent}{} -= {};",
+                cell(offset),
+                (amount as i8).unsigned_abs()
+            ),
+            NodeKind::Add { offset, amount } => {
+                writeln!(source, "{indent}{} += {};", cell(offset), amount as i8)
+            }
+            NodeKind::Set { offset, value } => {
+                writeln!(source, "{indent}{} = {};", cell(offset), value as u8)
+            }
+            NodeKind::Scan { stride } => writeln!(
+                source,
+                "{indent}while (tape[p]) {{\n{indent}    p += {stride};\n{indent}}}"
+            ),
+            NodeKind::MulAdd { offset, factor } => {
+                writeln!(
+                    source,
+                    "{indent}{} += tape[p] * {};",
+                    cell(offset),
+                    factor as u8
+                )
+            }
+            NodeKind::Output => writeln!(
+                source,
+                "{indent}if (!output(tape[p])) {{\n{indent}    goto error;\n{indent}}}"
+            ),
+            NodeKind::Input => writeln!(
+                source,
+                "{indent}if (!input(&tape[p])) {{\n{indent}    goto error;\n{indent}}}"
+            ),
+            // The C programme has nothing to show the tape with.
+            NodeKind::Dump => Ok(()),
+            NodeKind::Loop { ref body } => {
+                let _ = writeln!(source, "{indent}while (tape[p]) {{");
+                lower(source, body, depth + 1);
+                writeln!(source, "{indent}}}")
+            }
+        };
+    }
+}
+
+/// Returns the C expression for the cell `offset` cells away, wrapping around the same as the pointer.
+fn cell(offset: i32) -> String {
+    match offset {
+        0 => "tape[p]".to_string(),
+        // Converting to `uint16_t` wraps around both ends of the tape.
+        _ if offset < 0 => format!("tape[(uint16_t)(p - {})]", offset.unsigned_abs()),
+        _ => format!("tape[(uint16_t)(p + {offset})]"),
+    }
+}
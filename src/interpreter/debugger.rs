@@ -0,0 +1,272 @@
+use super::{Ins, State, Steps};
+use crate::{ir::Node, window, Budget, Cell, CellWidth, Error, Options, RADIUS};
+use std::{
+    collections::BTreeSet,
+    fmt,
+    io::{BufRead, Write},
+};
+
+/// What `help` writes.
+const HELP: &str = "\
+step [n], s      run the next instruction, or the next n
+next, n          run the next instruction, or the whole loop starting at it
+continue, c      run until a breakpoint or the end
+break [offset]   stop at the instruction from the byte offset in the source, or list the breakpoints
+delete [offset]  remove the breakpoint at the offset, or all of them
+tape [radius]    show the cells around the pointer, 8 on either side by default
+print, p         show the pointer and the cell at it
+quit, q          end the programme
+help, h          show this";
+
+pub fn debug(
+    program: &[Node],
+    options: &Options,
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+    prompt: &mut dyn Write,
+) -> Result<(), Error> {
+    match options.cell_width {
+        CellWidth::U8 => debug_cells::<u8>(program, options, input, output, prompt),
+        CellWidth::U16 => debug_cells::<u16>(program, options, input, output, prompt),
+        CellWidth::U32 => debug_cells::<u32>(program, options, input, output, prompt),
+    }
+}
+
+/// Runs `program` on a tape of cells of the type `C` as the commands from `input` say.
+fn debug_cells<C: Cell>(
+    program: &[Node],
+    options: &Options,
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+    prompt: &mut dyn Write,
+) -> Result<(), Error> {
+    let mut debugger = Debugger {
+        state: State::<C>::new(program, options),
+        steps: Steps::new(Budget::unlimited()),
+        breakpoints: BTreeSet::new(),
+        options,
+    };
+    debugger.show(prompt)?;
+    loop {
+        // Let the output so far appear before the prompt.
+        output.flush()?;
+        write!(prompt, "(bf) ")?;
+        prompt.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            writeln!(prompt)?;
+            return Ok(());
+        }
+
+        let mut words = line.split_whitespace();
+        let (command, argument) = (words.next(), words.next());
+        let number = |default| argument.map_or(Ok(default), str::parse::<usize>);
+        // Whether the programme has ended, for the commands running it.
+        let ended = match command {
+            None => continue,
+            Some("step" | "s") => match number(1) {
+                Ok(count) => Some(debugger.step(count, input, output)?),
+                Err(_) => invalid(prompt, argument)?,
+            },
+            Some("next" | "n") => Some(debugger.next(input, output, prompt)?),
+            Some("continue" | "c") => Some(debugger.resume(None, input, output, prompt)?),
+            Some(command) => {
+                match (command, argument) {
+                    ("break" | "b", None) => debugger.list(prompt)?,
+                    ("break" | "b", Some(offset)) => match offset.parse() {
+                        Ok(offset) => debugger.add(offset, prompt)?,
+                        Err(_) => invalid(prompt, argument)?,
+                    },
+                    ("delete" | "d", None) => debugger.breakpoints.clear(),
+                    ("delete" | "d", Some(offset)) => match offset.parse() {
+                        Ok(offset) => debugger.remove(offset, prompt)?,
+                        Err(_) => invalid(prompt, argument)?,
+                    },
+                    ("tape" | "t", _) => match number(RADIUS) {
+                        Ok(radius) => debugger.tape(radius, prompt)?,
+                        Err(_) => invalid(prompt, argument)?,
+                    },
+                    ("print" | "p", _) => writeln!(prompt, "{}", debugger.cell())?,
+                    ("quit" | "q", _) => return Ok(()),
+                    ("help" | "h", _) => writeln!(prompt, "{HELP}")?,
+                    _ => writeln!(prompt, "unknown command {command}, try help")?,
+                }
+                None
+            }
+        };
+        match ended {
+            Some(true) => {
+                writeln!(prompt, "the programme ended")?;
+                return Ok(());
+            }
+            Some(false) => debugger.show(prompt)?,
+            None => {}
+        }
+    }
+}
+
+/// Reports `argument`, which a command can't take.
+fn invalid<T: Default>(prompt: &mut dyn Write, argument: Option<&str>) -> Result<T, Error> {
+    writeln!(prompt, "invalid argument {}", argument.unwrap_or_default())?;
+    Ok(T::default())
+}
+
+/// The programme being debugged, along with where to stop it.
+/// The methods running it return whether it has ended.
+struct Debugger<'a, C> {
+    state: State<C>,
+    /// The steps of the programme, which aren't limited as it stops for the commands.
+    steps: Steps,
+    /// The byte offsets in the source of the instructions to stop at.
+    breakpoints: BTreeSet<usize>,
+    options: &'a Options,
+}
+
+impl<C: Cell> Debugger<'_, C> {
+    /// Returns the instruction to run next, along with the byte offset in the source it comes from.
+    fn current(&self) -> (Ins<C>, usize) {
+        self.state.instructions[self.state.programming_counter]
+    }
+
+    /// Runs `count` instructions, without stopping at breakpoints.
+    fn step(
+        &mut self,
+        count: usize,
+        input: &mut dyn BufRead,
+        output: &mut dyn Write,
+    ) -> Result<bool, Error> {
+        for _ in 0..count {
+            if self
+                .state
+                .step(self.options, &mut self.steps, input, output)?
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Runs the next instruction, or the whole loop if it starts one.
+    fn next(
+        &mut self,
+        input: &mut dyn BufRead,
+        output: &mut dyn Write,
+        prompt: &mut dyn Write,
+    ) -> Result<bool, Error> {
+        match self.current().0 {
+            // The loop jumps to the instruction after it only when it ends, whether it runs or not.
+            Ins::JmpFwd { to } => self.resume(Some(to), input, output, prompt),
+            _ => self.step(1, input, output),
+        }
+    }
+
+    /// Runs until the instruction `until` or a breakpoint is next, or the programme ends.
+    /// It runs at least one instruction, so that it goes on from a breakpoint.
+    fn resume(
+        &mut self,
+        until: Option<usize>,
+        input: &mut dyn BufRead,
+        output: &mut dyn Write,
+        prompt: &mut dyn Write,
+    ) -> Result<bool, Error> {
+        loop {
+            if self
+                .state
+                .step(self.options, &mut self.steps, input, output)?
+            {
+                return Ok(true);
+            }
+            if until == Some(self.state.programming_counter) {
+                return Ok(false);
+            }
+            let (ins, position) = self.current();
+            if !matches!(ins, Ins::End) && self.breakpoints.contains(&position) {
+                writeln!(prompt, "breakpoint at {position}")?;
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Adds a breakpoint at the first instruction from `offset` or after in the source,
+    /// as the optimisations merge commands into instructions from the first of them.
+    fn add(&mut self, offset: usize, prompt: &mut dyn Write) -> Result<(), Error> {
+        let found = self
+            .state
+            .instructions
+            .iter()
+            .find(|&&(ins, position)| !matches!(ins, Ins::End) && position >= offset);
+        match found {
+            Some(&(_, position)) => {
+                self.breakpoints.insert(position);
+                writeln!(prompt, "breakpoint at {position}")?;
+            }
+            None => writeln!(prompt, "no instruction at {offset} or after")?,
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, offset: usize, prompt: &mut dyn Write) -> Result<(), Error> {
+        if !self.breakpoints.remove(&offset) {
+            writeln!(prompt, "no breakpoint at {offset}")?;
+        }
+        Ok(())
+    }
+
+    fn list(&self, prompt: &mut dyn Write) -> Result<(), Error> {
+        if self.breakpoints.is_empty() {
+            writeln!(prompt, "no breakpoints")?;
+        } else {
+            let offsets: Vec<_> = self.breakpoints.iter().map(usize::to_string).collect();
+            writeln!(prompt, "breakpoints at {}", offsets.join(" "))?;
+        }
+        Ok(())
+    }
+
+    /// Shows the cells within `radius` of the pointer, with the one at it in brackets.
+    fn tape(&self, radius: usize, prompt: &mut dyn Write) -> Result<(), Error> {
+        match window(&self.state.tape.cells, self.state.pointer, radius) {
+            Some(window) => writeln!(prompt, "{window}")?,
+            None => writeln!(prompt, "the pointer is off the tape")?,
+        }
+        Ok(())
+    }
+
+    /// Returns the pointer and the cell at it.
+    fn cell(&self) -> String {
+        let pointer = self.state.pointer as isize;
+        match usize::try_from(pointer)
+            .ok()
+            .and_then(|index| self.state.tape.cells.get(index))
+        {
+            Some(cell) => format!("pointer {pointer}, cell {cell}"),
+            None => format!("pointer {pointer}, off the tape"),
+        }
+    }
+
+    /// Shows the instruction to run next, along with the pointer and the cell at it.
+    fn show(&self, prompt: &mut dyn Write) -> Result<(), Error> {
+        match self.current() {
+            (Ins::End, _) => writeln!(prompt, "at the end ({})", self.cell())?,
+            (ins, position) => writeln!(prompt, "{position}: {ins} ({})", self.cell())?,
+        }
+        Ok(())
+    }
+}
+
+impl<C: Cell> fmt::Display for Ins<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Ins::Move { amount } => write!(f, "move {amount:+}"),
+            Ins::AddCell { offset, amount } => write!(f, "add {amount} at {offset:+}"),
+            Ins::SetCell { offset, value } => write!(f, "set {value} at {offset:+}"),
+            Ins::Scan { stride } => write!(f, "scan {stride:+}"),
+            Ins::MulAddCell { offset, factor } => write!(f, "multiply by {factor} into {offset:+}"),
+            Ins::Output => write!(f, "output"),
+            Ins::Input => write!(f, "input"),
+            Ins::Dump => write!(f, "dump"),
+            Ins::JmpFwd { .. } => write!(f, "start loop"),
+            Ins::JmpBwd { .. } => write!(f, "end loop"),
+            Ins::End => write!(f, "end"),
+        }
+    }
+}
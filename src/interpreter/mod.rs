@@ -0,0 +1,331 @@
+mod debugger;
+
+pub use debugger::debug;
+
+use crate::{
+    dump, getchar,
+    ir::{Node, NodeKind},
+    putchar, scan,
+    trace::{self, Op, Record},
+    Bounds, Budget, Cell, CellWidth, Error, Options,
+};
+use std::io::{Read, Write};
+
+/// An instruction working on cells of the type `C`.
+#[derive(Clone, Copy)]
+enum Ins<C> {
+    Move { amount: isize },
+    AddCell { offset: isize, amount: C },
+    SetCell { offset: isize, value: C },
+    Scan { stride: i32 },
+    MulAddCell { offset: isize, factor: C },
+    Output,
+    Input,
+    Dump,
+    JmpFwd { to: usize },
+    JmpBwd { to: usize },
+    End,
+}
+
+impl<C> Ins<C> {
+    fn op(&self) -> Op {
+        match self {
+            Self::Move { .. } => Op::Move,
+            Self::AddCell { .. } => Op::Add,
+            Self::SetCell { .. } => Op::Set,
+            Self::Scan { .. } => Op::Scan,
+            Self::MulAddCell { .. } => Op::MulAdd,
+            Self::Output => Op::Output,
+            Self::Input => Op::Input,
+            Self::Dump => Op::Dump,
+            Self::JmpFwd { .. } => Op::LoopStart,
+            Self::JmpBwd { .. } => Op::LoopEnd,
+            Self::End => Op::End,
+        }
+    }
+}
+
+/// Lowers `program` into instructions, each along with the byte offset in the source it comes from.
+/// The end comes from the end of the last node.
+fn compile<C: Cell>(program: &[Node]) -> Vec<(Ins<C>, usize)> {
+    let mut instructions = Vec::with_capacity(program.len());
+    lower(&mut instructions, program);
+    instructions.push((Ins::End, program.last().map_or(0, |node| node.span.end)));
+    instructions
+}
+
+fn lower<C: Cell>(instructions: &mut Vec<(Ins<C>, usize)>, nodes: &[Node]) {
+    for node in nodes {
+        let ins = match node.kind {
+            NodeKind::Move { amount } => Ins::Move {
+                amount: amount as isize,
+            },
+            NodeKind::Add { offset, amount } => Ins::AddCell {
+                offset: offset as isize,
+                amount: C::truncate(amount),
+            },
+            NodeKind::Set { offset, value } => Ins::SetCell {
+                offset: offset as isize,
+                value: C::truncate(value),
+            },
+            NodeKind::Scan { stride } => Ins::Scan { stride },
+            NodeKind::MulAdd { offset, factor } => Ins::MulAddCell {
+                offset: offset as isize,
+                factor: C::truncate(factor),
+            },
+            NodeKind::Output => Ins::Output,
+            NodeKind::Input => Ins::Input,
+            NodeKind::Dump => Ins::Dump,
+            NodeKind::Loop { ref body } => {
+                let start_pos = instructions.len();
+                instructions.push((Ins::JmpFwd { to: 0 }, node.span.start)); // stub
+                lower(instructions, body);
+                instructions[start_pos].0 = Ins::JmpFwd {
+                    to: instructions.len() + 1,
+                };
+                // The end of the loop checks the cell at `]`.
+                instructions.push((Ins::JmpBwd { to: start_pos }, node.span.end - 1));
+                continue;
+            }
+        };
+
+        instructions.push((ins, node.span.start));
+    }
+}
+
+/// The cells, along with what accessing one beyond either end does.
+struct Tape<C> {
+    cells: Vec<C>,
+    bounds: Bounds,
+    /// What indices are masked with, which wraps them around with [`Bounds::Wrap`] and does nothing otherwise.
+    mask: usize,
+}
+
+impl<C: Cell> Tape<C> {
+    fn new(options: &Options) -> Self {
+        Self {
+            cells: vec![C::from(0); options.tape_size],
+            bounds: options.bounds,
+            mask: match options.bounds {
+                Bounds::Wrap => options.tape_size - 1,
+                Bounds::Error | Bounds::Grow | Bounds::Guard => usize::MAX,
+            },
+        }
+    }
+
+    /// Returns `index` as an index into the cells, wrapping it around or growing the tape as the bounds say.
+    /// `position` is where the cell is accessed from, for the error.
+    #[inline(always)]
+    fn index(&mut self, index: usize, position: usize) -> Result<usize, Error> {
+        let index = index & self.mask;
+        if index >= self.cells.len() {
+            let len = self.bounds.reach(self.cells.len(), index, position)?;
+            self.cells.resize(len, C::from(0));
+        }
+        Ok(index)
+    }
+
+    /// Returns the cell `index`, the same as [`Tape::index`].
+    #[inline(always)]
+    fn get(&mut self, index: usize, position: usize) -> Result<C, Error> {
+        let index = self.index(index, position)?;
+        Ok(self.cells[index])
+    }
+}
+
+/// Runs `program`, writing the record of each instruction into `trace` if any.
+pub fn run(
+    program: &[Node],
+    options: &Options,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    trace: Option<&mut trace::Writer<dyn Write + '_>>,
+) -> Result<(), Error> {
+    match options.cell_width {
+        CellWidth::U8 => run_cells::<u8>(program, options, input, output, trace),
+        CellWidth::U16 => run_cells::<u16>(program, options, input, output, trace),
+        CellWidth::U32 => run_cells::<u32>(program, options, input, output, trace),
+    }
+}
+
+/// Runs `program` on a tape of cells of the type `C`.
+fn run_cells<C: Cell>(
+    program: &[Node],
+    options: &Options,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    trace: Option<&mut trace::Writer<dyn Write + '_>>,
+) -> Result<(), Error> {
+    let mut state = State::<C>::new(program, options);
+    let mut steps = Steps::new(Budget::new(options));
+
+    // Choose whether to trace once, so that running without it checks nothing more for each instruction.
+    match trace {
+        None => while !state.step(options, &mut steps, input, output)? {},
+        Some(trace) => loop {
+            trace.write(&state.record())?;
+            if state.step(options, &mut steps, input, output)? {
+                break;
+            }
+        },
+    }
+
+    Ok(())
+}
+
+/// The steps handed out by the budget which aren't run yet.
+/// Each iteration of loops takes a step as it does on the JIT compilers,
+/// and the time is checked between the chunks of them.
+struct Steps {
+    budget: Budget,
+    left: u64,
+}
+
+impl Steps {
+    fn new(budget: Budget) -> Self {
+        Self { budget, left: 0 }
+    }
+
+    /// Takes a step, or returns the error if there are none left or the time is up.
+    #[inline(always)]
+    fn take(&mut self) -> Result<(), Error> {
+        if self.left == 0 {
+            self.left = self.budget.next()?;
+        }
+        self.left -= 1;
+        Ok(())
+    }
+}
+
+/// A programme running on the interpreter, which runs an instruction at a time.
+struct State<C> {
+    instructions: Vec<(Ins<C>, usize)>,
+    tape: Tape<C>,
+    pointer: usize,
+    /// The index of the instruction to run next.
+    programming_counter: usize,
+}
+
+impl<C: Cell> State<C> {
+    fn new(program: &[Node], options: &Options) -> Self {
+        Self {
+            instructions: compile(program),
+            tape: Tape::new(options),
+            pointer: 0,
+            programming_counter: 0,
+        }
+    }
+
+    /// Returns the record of the instruction at the programming counter for the trace, before it runs.
+    fn record(&self) -> Record {
+        let (ins, position) = self.instructions[self.programming_counter];
+        Record {
+            position: position as u32,
+            op: ins.op(),
+            pointer: self.pointer as i32,
+            cell: self.tape.cells.get(self.pointer).map(|&cell| cell.into()),
+        }
+    }
+
+    /// Runs the instruction at the programming counter, and returns whether it ended the programme.
+    #[inline(always)]
+    fn step(
+        &mut self,
+        options: &Options,
+        steps: &mut Steps,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<bool, Error> {
+        let Self {
+            instructions,
+            tape,
+            pointer,
+            programming_counter,
+        } = self;
+        // The programming counter should always be in-bounds as
+        // it increments by one, there's `Ins::End` at the end of the list
+        // and `Ins::JmpFwd/Bwd { to }` is in-bounds.
+        // TODO: introduce a fuzzer to find an UB here as well as the JIT version.
+        let (ins, position) = *unsafe { instructions.get_unchecked(*programming_counter) };
+        *programming_counter += 1;
+        match ins {
+            Ins::Move { amount } => *pointer = pointer.wrapping_add_signed(amount) & tape.mask,
+            Ins::AddCell { offset, amount } => {
+                let target = tape.index(pointer.wrapping_add_signed(offset), position)?;
+                tape.cells[target] = tape.cells[target].wrapping_add(amount)
+            }
+            Ins::SetCell { offset, value } => {
+                let target = tape.index(pointer.wrapping_add_signed(offset), position)?;
+                tape.cells[target] = value
+            }
+            Ins::Scan { stride } if tape.bounds == Bounds::Wrap => {
+                if tape.cells[*pointer] != C::from(0) {
+                    steps.take()?;
+                }
+                match scan(&tape.cells, *pointer, stride) {
+                    Some(found) => *pointer = found,
+                    // Nothing to stop at. Run this instruction again to loop forever as the programme says.
+                    None => {
+                        check_cancel(options)?;
+                        *programming_counter -= 1
+                    }
+                }
+            }
+            // Check every cell on the way, which is where it can go out of the tape.
+            Ins::Scan { stride } => {
+                while tape.get(*pointer, position)? != C::from(0) {
+                    steps.take()?;
+                    *pointer = pointer.wrapping_add_signed(stride as isize);
+                }
+            }
+            Ins::MulAddCell { offset, factor } => {
+                let current = tape.get(*pointer, position)?;
+                // Leave the other cell alone as the loop this comes from doesn't run.
+                if current != C::from(0) {
+                    let target = tape.index(pointer.wrapping_add_signed(offset), position)?;
+                    tape.cells[target] =
+                        tape.cells[target].wrapping_add(current.wrapping_mul(factor))
+                }
+            }
+            Ins::Output => {
+                let current = tape.index(*pointer, position)?;
+                putchar(output, &tape.cells[current].low_byte())?
+            }
+            Ins::Input => {
+                let current = tape.index(*pointer, position)?;
+                getchar(input, output, options.eof, &mut tape.cells[current])?
+            }
+            // The pointer can be off the tape, as it doesn't access the cell at it.
+            Ins::Dump => dump(
+                output,
+                &options.diagnostics,
+                &tape.cells,
+                *pointer,
+                position,
+            )?,
+            Ins::JmpFwd { to } => {
+                if tape.get(*pointer, position)? == C::from(0) {
+                    *programming_counter = to;
+                }
+            }
+            Ins::JmpBwd { to } => {
+                check_cancel(options)?;
+                steps.take()?;
+                if tape.get(*pointer, position)? != C::from(0) {
+                    *programming_counter = to;
+                }
+            }
+            Ins::End => return Ok(true),
+        }
+        Ok(false)
+    }
+}
+
+/// Returns [`Error::Cancelled`] if the programme is cancelled, which is checked at the end of each iteration of loops.
+#[inline(always)]
+fn check_cancel(options: &Options) -> Result<(), Error> {
+    match &options.cancel {
+        Some(cancel) if cancel.is_cancelled() => Err(Error::Cancelled),
+        _ => Ok(()),
+    }
+}
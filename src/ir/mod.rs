@@ -0,0 +1,144 @@
+//! The intermediate representation shared by every engine.
+//!
+//! The front end, [`parse`], reads Brainf*ck source once and produces a tree of [`Node`]s,
+//! each of which remembers the part of the source it comes from.
+//! Each engine lowers from this tree rather than reading the source by itself,
+//! so that the parser and the optimisations in [`optimize`] are shared between all of them.
+
+mod opt;
+
+pub use opt::{optimize, OptLevel};
+
+use crate::{Error, Location};
+use std::{mem, ops::Range};
+
+/// A single operation of a Brainf*ck programme, along with where it comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Node {
+    pub kind: NodeKind,
+    /// The byte offsets of the source the node is made from.
+    /// A node made of several others by the optimisations spans over all of them.
+    pub span: Range<usize>,
+}
+
+impl Node {
+    pub fn new(kind: NodeKind, span: Range<usize>) -> Self {
+        Self { kind, span }
+    }
+
+    /// Returns the offset of the cell this changes if it is either [`NodeKind::Add`] or [`NodeKind::Set`].
+    /// These never move the pointer, so engines can look up the cell without moving it.
+    pub fn cell_offset(&self) -> Option<i32> {
+        match self.kind {
+            NodeKind::Add { offset, .. } | NodeKind::Set { offset, .. } => Some(offset),
+            _ => None,
+        }
+    }
+}
+
+/// What a [`Node`] does.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    /// Moves the pointer by `amount` cells. The pointer wraps around the tape.
+    Move { amount: i32 },
+    /// Adds `amount` to the cell `offset` cells away, wrapping around on overflow.
+    /// Amounts, values and factors are kept in 32 bits, and engines truncate them to the width of cells,
+    /// which gives the same result as wrapping around at the width all along.
+    Add { offset: i32, amount: i32 },
+    /// Sets the cell `offset` cells away to `value`.
+    Set { offset: i32, value: i32 },
+    /// Moves the pointer by `stride` cells until the current cell is zero.
+    /// The current cell is checked before moving, so this does nothing if it is already zero.
+    Scan { stride: i32 },
+    /// Adds the current cell multiplied by `factor` to the cell `offset` cells away.
+    /// The pointer doesn't move, and the current cell is left unchanged.
+    MulAdd { offset: i32, factor: i32 },
+    /// Writes the current cell into the output.
+    Output,
+    /// Reads a byte from the input into the current cell.
+    Input,
+    /// Runs `body` while the current cell is not zero.
+    Loop { body: Vec<Node> },
+    /// Shows the pointer and the cells around it on [`Options::diagnostics`](crate::Options::diagnostics), for `#` with [`parse_with`].
+    /// The engines writing programmes out skip it.
+    Dump,
+}
+
+trait Consumer {
+    fn consume_while(&mut self, target: u8) -> usize;
+}
+
+impl Consumer for std::slice::Iter<'_, u8> {
+    #[inline(always)]
+    fn consume_while(&mut self, target: u8) -> usize {
+        // Calculate the span of continuous copies of the target.
+        let span = self.clone().take_while(|c| **c == target).count();
+
+        // FIXME: unstable `Iterator::advance_by` is better.
+        // Need https://github.com/rust-lang/rust/issues/77404
+        if span != 0 {
+            self.nth(span - 1);
+        }
+        // self.advance_by(span);
+        span
+    }
+}
+
+/// Parses `program` into a tree of [`Node`]s.
+/// Runs of the same command are folded into one node, and anything other than the 8 commands is skipped.
+/// Brackets which don't match end parsing with where they are.
+pub fn parse(program: &[u8]) -> Result<Vec<Node>, Error> {
+    parse_with(program, false)
+}
+
+/// Parses `program` the same as [`parse`], but also turns `#` into [`NodeKind::Dump`] if `debug_char` is set,
+/// as many debuggers do.
+pub fn parse_with(program: &[u8], debug_char: bool) -> Result<Vec<Node>, Error> {
+    // The bodies of the loops that are not closed yet along with where they start, the outermost first.
+    let mut loops = Vec::new();
+    let mut nodes = Vec::new();
+
+    let mut iter = program.iter();
+    while let Some(&c) = iter.next() {
+        let start = program.len() - iter.len() - 1;
+        let kind = match c {
+            b'>' => NodeKind::Move {
+                amount: (iter.consume_while(b'>') + 1) as i32,
+            },
+            b'<' => NodeKind::Move {
+                amount: -((iter.consume_while(b'<') + 1) as i32),
+            },
+            b'+' => NodeKind::Add {
+                offset: 0,
+                amount: (iter.consume_while(b'+') + 1) as i32,
+            },
+            b'-' => NodeKind::Add {
+                offset: 0,
+                amount: ((iter.consume_while(b'-') + 1) as i32).wrapping_neg(),
+            },
+            b'.' => NodeKind::Output,
+            b',' => NodeKind::Input,
+            b'#' if debug_char => NodeKind::Dump,
+            b'[' => {
+                loops.push((start, mem::take(&mut nodes)));
+                continue;
+            }
+            b']' => {
+                let (loop_start, outer) = loops
+                    .pop()
+                    .ok_or_else(|| Error::UnmatchedRight(Location::new(program, start)))?;
+                let body = mem::replace(&mut nodes, outer);
+                nodes.push(Node::new(NodeKind::Loop { body }, loop_start..start + 1));
+                continue;
+            }
+            _ => continue,
+        };
+
+        nodes.push(Node::new(kind, start..program.len() - iter.len()));
+    }
+
+    match loops.last() {
+        None => Ok(nodes),
+        Some(&(start, _)) => Err(Error::UnmatchedLeft(Location::new(program, start))),
+    }
+}
@@ -0,0 +1,357 @@
+//! Optimisations on the tree produced by [`super::parse`].
+//! Every pass must keep the observable behaviour of a programme, including the pointer wrapping around.
+//! The passes know the tape the programme runs on, as offsets from the pointer reach the same cells
+//! on a tape wrapping around within them, and accesses to cells beyond a tape that doesn't wrap end the programme.
+
+use super::{Node, NodeKind};
+use crate::Bounds;
+use std::{ops::Range, str::FromStr};
+
+/// Which optimisation passes to run. Each level runs the passes of the levels below as well.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    /// Only folds runs of the same command, which the parser always does.
+    O0,
+    /// Replaces clear loops and scan loops.
+    O1,
+    /// Replaces multiplication loops and addresses cells with offsets.
+    O2,
+    /// Removes code that never runs or never affects the output.
+    O3,
+}
+
+impl OptLevel {
+    pub const ALL: [OptLevel; 4] = [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3];
+}
+
+impl FromStr for OptLevel {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, &'static str> {
+        match s {
+            "0" => Ok(Self::O0),
+            "1" => Ok(Self::O1),
+            "2" => Ok(Self::O2),
+            "3" => Ok(Self::O3),
+            _ => Err("Invalid optimisation level"),
+        }
+    }
+}
+
+/// Runs the optimisation passes for `level` on `program`, which runs on a tape of `tape_size` cells with `bounds`.
+pub fn optimize(
+    program: Vec<Node>,
+    level: OptLevel,
+    tape_size: usize,
+    bounds: Bounds,
+) -> Vec<Node> {
+    let tape = Tape {
+        size: tape_size,
+        bounds,
+    };
+    let mut program = program;
+    if level >= OptLevel::O1 {
+        program = simple_loops(program);
+    }
+    if level >= OptLevel::O2 {
+        program = multiply_loops(program, tape);
+        program = offset_cells(program, tape);
+    }
+    if level >= OptLevel::O3 {
+        program = dead_code(program, tape);
+    }
+    program
+}
+
+/// The tape a programme is optimised for.
+#[derive(Clone, Copy)]
+struct Tape {
+    size: usize,
+    bounds: Bounds,
+}
+
+impl Tape {
+    /// Wraps `offset` around into the half of the tape on either side of the pointer if the pointer wraps around,
+    /// so that offsets reaching the same cell are equal.
+    fn wrap(self, offset: i32) -> i32 {
+        if self.bounds != Bounds::Wrap {
+            return offset;
+        }
+        let size = self.size as i64;
+        ((i64::from(offset) + size / 2).rem_euclid(size) - size / 2) as i32
+    }
+}
+
+/// Replaces loops like `[-]` and `[+]` with [`NodeKind::Set`], and folds
+/// the additions around it, such as `[-]+++`, into the value to set.
+/// Also replaces loops like `[>]` and `[<<]` with [`NodeKind::Scan`].
+fn simple_loops(program: Vec<Node>) -> Vec<Node> {
+    let mut nodes = Vec::with_capacity(program.len());
+    for Node { kind, span } in program {
+        let kind = match kind {
+            NodeKind::Loop { body } => {
+                let body = simple_loops(body);
+                match body[..] {
+                    // Adding an odd number always reaches zero at some point, as it is coprime to 256,
+                    // and to the number of values of wider cells too.
+                    // Even numbers can loop forever, so leave them as they are.
+                    [Node {
+                        kind: NodeKind::Add { offset: 0, amount },
+                        ..
+                    }] if amount % 2 != 0 => NodeKind::Set {
+                        offset: 0,
+                        value: 0,
+                    },
+                    [Node {
+                        kind: NodeKind::Move { amount },
+                        ..
+                    }] => NodeKind::Scan { stride: amount },
+                    _ => NodeKind::Loop { body },
+                }
+            }
+            kind => kind,
+        };
+        push(&mut nodes, Node::new(kind, span));
+    }
+    nodes
+}
+
+/// Replaces loops like `[->+>++<<]` with [`NodeKind::MulAdd`]s followed by a clear.
+/// The loop must only move the pointer and add to cells, come back to where it started,
+/// and subtract exactly one from the starting cell per iteration.
+fn multiply_loops(program: Vec<Node>, tape: Tape) -> Vec<Node> {
+    let mut nodes = Vec::with_capacity(program.len());
+    for Node { kind, span } in program {
+        match kind {
+            NodeKind::Loop { body } => {
+                let body = multiply_loops(body, tape);
+                match multiply(&body, tape) {
+                    Some(muls) => {
+                        for mul in muls {
+                            push(&mut nodes, Node::new(mul, span.clone()));
+                        }
+                        push(
+                            &mut nodes,
+                            Node::new(
+                                NodeKind::Set {
+                                    offset: 0,
+                                    value: 0,
+                                },
+                                span,
+                            ),
+                        );
+                    }
+                    None => push(&mut nodes, Node::new(NodeKind::Loop { body }, span)),
+                }
+            }
+            kind => push(&mut nodes, Node::new(kind, span)),
+        }
+    }
+    nodes
+}
+
+/// Returns the [`NodeKind::MulAdd`]s equivalent to the loop `body` if it is a multiplication loop.
+fn multiply(body: &[Node], tape: Tape) -> Option<Vec<NodeKind>> {
+    let mut offset = 0i32;
+    // The sum of additions to each cell, in the order they first appear.
+    let mut sums: Vec<(i32, i32)> = Vec::new();
+    for node in body {
+        match node.kind {
+            NodeKind::Move { amount } => offset = tape.wrap(offset.wrapping_add(amount)),
+            NodeKind::Add { offset: 0, amount } => {
+                match sums.iter_mut().find(|(o, _)| *o == offset) {
+                    Some((_, sum)) => *sum = sum.wrapping_add(amount),
+                    None => sums.push((offset, amount)),
+                }
+            }
+            _ => return None,
+        }
+    }
+
+    let counter = sums.iter().find(|(o, _)| *o == 0).map(|(_, sum)| *sum);
+    if offset != 0 || counter != Some(-1) {
+        return None;
+    }
+
+    Some(
+        sums.into_iter()
+            .filter(|&(offset, factor)| offset != 0 && factor != 0)
+            .map(|(offset, factor)| NodeKind::MulAdd { offset, factor })
+            .collect(),
+    )
+}
+
+/// Turns moving the pointer followed by changing cells, like `>+>++<<-`, into changing the cells
+/// at offsets from the pointer. The pointer is only moved before loops, io and the other nodes
+/// that need the pointer to be at the right place, as well as at the end of the programme.
+fn offset_cells(program: Vec<Node>, tape: Tape) -> Vec<Node> {
+    let mut nodes = Vec::with_capacity(program.len());
+    // The pointer moves not made yet, and where they come from.
+    let mut offset = 0i32;
+    let mut moves = None;
+    for Node { kind, span } in program {
+        match kind {
+            NodeKind::Move { amount } => {
+                offset = tape.wrap(offset.wrapping_add(amount));
+                moves = Some(moves.map_or(span.clone(), |moves| join(&moves, &span)));
+            }
+            NodeKind::Add { offset: o, amount } => push(
+                &mut nodes,
+                Node::new(
+                    NodeKind::Add {
+                        offset: tape.wrap(offset.wrapping_add(o)),
+                        amount,
+                    },
+                    span,
+                ),
+            ),
+            NodeKind::Set { offset: o, value } => push(
+                &mut nodes,
+                Node::new(
+                    NodeKind::Set {
+                        offset: tape.wrap(offset.wrapping_add(o)),
+                        value,
+                    },
+                    span,
+                ),
+            ),
+            kind => {
+                if let Some(moves) = moves.take() {
+                    push(
+                        &mut nodes,
+                        Node::new(NodeKind::Move { amount: offset }, moves),
+                    );
+                }
+                offset = 0;
+                let kind = match kind {
+                    NodeKind::Loop { body } => NodeKind::Loop {
+                        body: offset_cells(body, tape),
+                    },
+                    kind => kind,
+                };
+                push(&mut nodes, Node::new(kind, span));
+            }
+        }
+    }
+    if let Some(moves) = moves {
+        push(
+            &mut nodes,
+            Node::new(NodeKind::Move { amount: offset }, moves),
+        );
+    }
+    nodes
+}
+
+/// Removes loops and the like that start at a cell known to be zero, which never run,
+/// and changes to the tape at the end of the programme, which nothing can observe
+/// unless accessing a cell beyond the tape ends the programme.
+fn dead_code(program: Vec<Node>, tape: Tape) -> Vec<Node> {
+    // Every cell is zero at the start.
+    let mut nodes = unreachable_loops(program, true);
+    if tape.bounds != Bounds::Wrap {
+        return nodes;
+    }
+    while let Some(
+        NodeKind::Move { .. }
+        | NodeKind::Add { .. }
+        | NodeKind::Set { .. }
+        | NodeKind::MulAdd { .. },
+    ) = nodes.last().map(|node| &node.kind)
+    {
+        nodes.pop();
+    }
+    nodes
+}
+
+/// Removes nodes that do nothing when the current cell is zero, where they are known to.
+/// `zero` tells if the current cell is zero at the start of `program`.
+fn unreachable_loops(program: Vec<Node>, mut zero: bool) -> Vec<Node> {
+    let mut nodes = Vec::with_capacity(program.len());
+    for node in program {
+        match node.kind {
+            NodeKind::Loop { .. }
+            | NodeKind::Scan { .. }
+            | NodeKind::MulAdd { .. }
+            | NodeKind::Set {
+                offset: 0,
+                value: 0,
+            } if zero => continue,
+            // The current cell is not zero at the start of the body, and is zero at the end of the loop.
+            NodeKind::Loop { body } => {
+                let body = unreachable_loops(body, false);
+                nodes.push(Node::new(NodeKind::Loop { body }, node.span));
+                zero = true;
+                continue;
+            }
+            NodeKind::Scan { .. } => zero = true,
+            NodeKind::Set { offset: 0, value } => zero = value == 0,
+            NodeKind::Move { .. } | NodeKind::Add { offset: 0, .. } | NodeKind::Input => {
+                zero = false
+            }
+            NodeKind::Add { .. }
+            | NodeKind::Set { .. }
+            | NodeKind::MulAdd { .. }
+            | NodeKind::Output
+            | NodeKind::Dump => {}
+        }
+        nodes.push(node);
+    }
+    nodes
+}
+
+/// Pushes `node` to `nodes`, folding it into the last node if possible.
+/// The folded node spans over the source of both.
+fn push(nodes: &mut Vec<Node>, node: Node) {
+    if let NodeKind::Move { amount: 0 } = node.kind {
+        return;
+    }
+    let Some(last) = nodes.last_mut() else {
+        nodes.push(node);
+        return;
+    };
+
+    let folded = match (&last.kind, &node.kind) {
+        (&NodeKind::Move { amount: last }, &NodeKind::Move { amount }) => Some(NodeKind::Move {
+            amount: last + amount,
+        }),
+        (
+            &NodeKind::Add {
+                offset: o,
+                amount: last,
+            },
+            &NodeKind::Add { offset, amount },
+        ) if o == offset => Some(NodeKind::Add {
+            offset,
+            amount: last.wrapping_add(amount),
+        }),
+        (&NodeKind::Set { offset: o, value }, &NodeKind::Add { offset, amount }) if o == offset => {
+            Some(NodeKind::Set {
+                offset,
+                value: value.wrapping_add(amount),
+            })
+        }
+        // The previous value doesn't matter as it is overwritten.
+        (NodeKind::Add { .. } | NodeKind::Set { .. }, NodeKind::Set { offset, .. })
+            if last.cell_offset() == Some(*offset) =>
+        {
+            Some(node.kind.clone())
+        }
+        _ => None,
+    };
+
+    match folded {
+        Some(NodeKind::Move { amount: 0 }) => {
+            nodes.pop();
+        }
+        Some(kind) => {
+            last.span = join(&last.span, &node.span);
+            last.kind = kind;
+        }
+        None => nodes.push(node),
+    }
+}
+
+/// Returns the smallest range that covers both `a` and `b`.
+fn join(a: &Range<usize>, b: &Range<usize>) -> Range<usize> {
+    a.start.min(b.start)..a.end.max(b.end)
+}
@@ -1,119 +1,673 @@
 use crate::{
-    jit::{getchar, putchar, run_opcode},
-    Consumer as _, Error,
+    ir::{Node, NodeKind},
+    jit::{
+        bounds, dump, getchar, guarded, limit,
+        listing::{Listing, Ops},
+        putchar, run_opcode, scan, Faults,
+    },
+    trace::Op,
+    Bounds, CellWidth, Error, Options,
 };
 use dynasm::dynasm;
-use dynasmrt::{DynasmApi, DynasmLabelApi, ExecutableBuffer};
+use dynasmrt::{aarch64::Assembler, DynasmApi, DynasmLabelApi, ExecutableBuffer};
+use std::io::{Read, Write};
 
 macro_rules! my_dynasm {
-    ($ops:ident $($t:tt)*) => {
-        dynasm!($ops
+    ($ops:ident $($t:tt)*) => {{
+        if let Some(listing) = &mut $ops.listing {
+            listing.push(stringify!($($t)*));
+        }
+        dynasm!($ops.asm
             ; .arch aarch64
             ; .alias ptr, x19
             ; .alias idx, w20
             ; .alias xidx, x20
+            ; .alias ctx, x21
+            ; .alias size, w22
+            ; .alias xsize, x22
+            ; .alias budget, x23
             $($t)*
         )
-    }
+    }}
 }
 
-fn compile(program: &[u8]) -> Result<ExecutableBuffer, Error> {
-    let mut ops = dynasmrt::aarch64::Assembler::new()?;
+/// Generates machine code for `program` running with `options`,
+/// writing down what is generated into `listing` if any. With `trace`, it calls [`trace`](crate::jit::trace) at the conditions of loops
+/// and at the end. Returns the machine code along with where it accesses cells between guard pages.
+fn compile(
+    program: &[Node],
+    options: &Options,
+    listing: Option<&mut Listing>,
+    trace: bool,
+) -> Result<(ExecutableBuffer, Faults), Error> {
+    let mut ops = Ops {
+        asm: Assembler::new()?,
+        listing,
+        faults: Faults::default(),
+        trace,
+    };
 
+    ops.block(|| "prelude".to_string());
     my_dynasm!(ops
-        ; sub sp, sp, #32 // allocate an enough stack
-        ; str x30, [sp, #16] // save a special register
+        ; sub sp, sp, #48 // allocate an enough stack
+        ; stp x30, ctx, [sp, #16] // save a special register and the one for the context
 
         ; stp ptr, xidx, [sp] // save callee-saved register
+        ; str xsize, [sp, #32]
         ; mov ptr, x0
+        ; mov ctx, x1 // Keep the context to pass it to `putchar`, `getchar` and `bounds`
         ; mov idx, wzr // Set the array index to 0
+    );
+    // Keep the number of cells, which `bounds` can change.
+    mov_imm(&mut ops, 22, options.tape_size as u32);
+    my_dynasm!(ops
         ; mov w0, #1 // Set the initial return value to 1 in case no io happens.
     );
+    if options.limited() {
+        my_dynasm!(ops
+            ; str budget, [sp, #40]
+            ; mov budget, xzr // Call `limit` for the first steps at the first iteration
+        );
+    }
+
+    lower(&mut ops, program, options);
+    if ops.trace {
+        ops.block(|| "end".to_string());
+        trace_point(
+            &mut ops,
+            program.last().map_or(0, |node| node.span.end),
+            Op::End,
+        );
+    }
+
+    let scan = match options.cell_width {
+        CellWidth::U8 => scan::<u8> as *const (),
+        CellWidth::U16 => scan::<u16> as *const (),
+        CellWidth::U32 => scan::<u32> as *const (),
+    };
+    ops.block(|| "postlude".to_string());
+    ops.faults.throwing = ops.asm.offset().0;
+    my_dynasm!(ops
+        // Keep `x0` set by the functions called as it is for the return value.
+        ;->throwing:
+    );
+    if options.limited() {
+        my_dynasm!(ops
+            ; ldr budget, [sp, #40]
+        );
+    }
+    my_dynasm!(ops
+        ; ldr xsize, [sp, #32]
+        ; ldp ptr, xidx, [sp]
 
-    let mut loops = Vec::new();
-
-    let mut iter = program.iter();
-    while let Some(&c) = iter.next() {
-        match c {
-            b'>' => my_dynasm!(ops
-                ; add idx, idx, (iter.consume_while(b'>') + 1) as u32
-                // Make sure the index stays within 16 bit values for memory protection.
-                // (There's no such thing as 16 bit registers. Zero-extension is the only way.)
-                // `add` has an option to perform `uxth`, but that's a bit different from what I'm doing.
-                ; uxth idx, idx
-            ),
-            b'<' => my_dynasm!(ops
-                ; sub idx, idx, (iter.consume_while(b'<') + 1) as u32
-                ; uxth idx, idx
-            ),
-            b'+' => my_dynasm!(ops
-                ; ldrb w9, [ptr, xidx]
-                ; add w9, w9, (iter.consume_while(b'+') + 1) as u32
-                ; strb w9, [ptr, xidx]
-            ),
-            b'-' => my_dynasm!(ops
-                ; ldrb w9, [ptr, xidx]
-                ; sub w9, w9, (iter.consume_while(b'-') + 1) as u32
-                ; strb w9, [ptr, xidx]
-            ),
-            b'.' => my_dynasm!(ops
-                ; add x0, ptr, idx
-                ; ldr x9, ->putchar_off // use load-literal as a function pointer is too large
-                ; blr x9
-                ; cbz w0, ->throwing
-            ),
-            b',' => my_dynasm!(ops
-                ; add x0, ptr, idx
-                ; ldr x9, ->getchar_off
-                ; blr x9
-                ; cbz w0, ->throwing
-            ),
-            b'[' => {
-                let bwd_label = ops.new_dynamic_label();
-                let fwd_label = ops.new_dynamic_label();
-                loops.push((bwd_label, fwd_label));
-                my_dynasm!(ops
-                    ; ldrb w9, [ptr, xidx]
+        ; ldp x30, ctx, [sp, #16]
+        ; add sp, sp, #48
+        ; ret
+
+        // Literal pool to store 64 bit constants:
+        ; ->putchar_off:
+        ; .qword putchar as *const () as _
+        ; ->getchar_off:
+        ; .qword getchar as *const () as _
+        ; ->scan_off:
+        ; .qword scan as _
+        ; ->bounds_off:
+        ; .qword bounds as *const () as _
+        ; ->limit_off:
+        ; .qword limit as *const () as _
+        ; ->dump_off:
+        ; .qword dump as *const () as _
+    );
+    if let Some(cancel) = &options.cancel {
+        my_dynasm!(ops
+            ; ->cancel_off:
+            ; .qword cancel.flag() as _
+        );
+    }
+    if ops.trace {
+        my_dynasm!(ops
+            ; ->trace_off:
+            ; .qword crate::jit::trace as *const () as _
+        );
+    }
+
+    let code = ops
+        .asm
+        .finalize()
+        .expect("Finalising the exec buffer failed");
+    Ok((code, ops.faults))
+}
+
+fn lower(ops: &mut Ops<Assembler>, nodes: &[Node], options: &Options) {
+    let cell = options.cell_width;
+    // The tape has as many cells as a power of two to wrap around, so the mask is one less.
+    let mask = (options.tape_size - 1) as u32;
+    // Changes to cells in a row are lowered together.
+    for nodes in nodes.chunk_by(|a, b| a.cell_offset().is_some() && b.cell_offset().is_some()) {
+        ops.nodes(nodes);
+        let position = nodes[0].span.start;
+        match nodes[0].kind {
+            NodeKind::Move { amount } => {
+                offset_index(ops, 20, amount);
+                // Make sure the index stays within the tape for memory protection.
+                // The index is only checked when a cell is accessed, unless it wraps around.
+                if options.bounds == Bounds::Wrap {
+                    wrap(ops, 20, mask);
+                }
+            }
+            NodeKind::Add { .. } | NodeKind::Set { .. } => lower_cells(ops, nodes, options),
+            NodeKind::Scan { stride } if options.bounds != Bounds::Wrap => {
+                // Check every cell on the way, which is where it can go out of the tape.
+                let start_label = ops.asm.new_dynamic_label();
+                let end_label = ops.asm.new_dynamic_label();
+                my_dynasm!(ops
+                    ;=>start_label
+                );
+                back_edge(ops, options);
+                checked_index(ops, options, 0, position);
+                load_wrapped(ops, cell);
+                my_dynasm!(ops
+                    ; cbz w9, =>end_label
+                );
+                offset_index(ops, 20, stride);
+                my_dynasm!(ops
+                    ; b =>start_label
+                    ;=>end_label
+                );
+            }
+            NodeKind::Scan { stride } => {
+                let start_label = ops.asm.new_dynamic_label();
+                let end_label = ops.asm.new_dynamic_label();
+                let stride = stride as u32;
+                // Leave searching to `memchr` through `scan`, which uses SIMD for 8 bit cells.
+                load_cell(ops, cell, 0);
+                my_dynasm!(ops
+                    ; cbz w9, =>end_label
+                    ;=>start_label
+                );
+                back_edge(ops, options);
+                my_dynasm!(ops
+                    ; mov x0, ptr
+                );
+                mov_imm(ops, 1, options.tape_size as u32);
+                my_dynasm!(ops
+                    ; mov w2, idx
+                    ; movz w3, stride & 0xffff
+                    ; movk w3, stride >> 16, lsl 16
+                    ; ldr x9, ->scan_off
+                    ; blr x9
+                    // Not finding anything at all means the programme loops forever, so go back to the start.
+                    ; cmn w0, 1
+                    ; b.eq =>start_label
+                    ; mov idx, w0
+                    ; movz w0, 1 // Restore the return value
+                    ;=>end_label
+                );
+            }
+            NodeKind::MulAdd { offset, factor } if options.bounds != Bounds::Wrap => {
+                // Only access the other cell if the loop this comes from runs, as it can be out of the tape.
+                let end_label = ops.asm.new_dynamic_label();
+                checked_index(ops, options, 0, position);
+                load_wrapped(ops, cell);
+                my_dynasm!(ops
+                    ; cbz w9, =>end_label
+                );
+                checked_index(ops, options, offset, position);
+                // Load the current cell again as `bounds` may have been called. It is on the tape as it is checked above.
+                load_cell(ops, cell, 0);
+                multiply_add(ops, cell, factor);
+                my_dynasm!(ops
+                    ;=>end_label
+                );
+            }
+            NodeKind::MulAdd { offset, factor } => {
+                wrapped_index(ops, offset, mask);
+                load_cell(ops, cell, 0);
+                multiply_add(ops, cell, factor);
+            }
+            NodeKind::Output => {
+                current_cell(ops, options, position);
+                probe(ops, options);
+                my_dynasm!(ops
+                    ; mov x0, ctx
+                );
+                // `putchar` takes the lowest byte, which comes first as the tape is little endian.
+                cell_address(ops, options, 1);
+                my_dynasm!(ops
+                    ; ldr x9, ->putchar_off // use load-literal as a function pointer is too large
+                    ; blr x9
+                    ; cbz w0, ->throwing
+                );
+            }
+            NodeKind::Input => {
+                current_cell(ops, options, position);
+                probe(ops, options);
+                my_dynasm!(ops
+                    ; mov x0, ctx
+                );
+                cell_address(ops, options, 1);
+                my_dynasm!(ops
+                    ; ldr x9, ->getchar_off
+                    ; blr x9
+                    ; cbz w0, ->throwing
+                );
+            }
+            // The index isn't checked, as `dump` shows where it is even if it's off the tape.
+            NodeKind::Dump => {
+                my_dynasm!(ops
+                    ; mov x0, ctx
+                    ; mov x1, ptr
+                    ; mov w2, idx
+                );
+                mov_imm(ops, 3, position as u32);
+                my_dynasm!(ops
+                    ; ldr x9, ->dump_off
+                    ; blr x9
+                    ; cbz w0, ->throwing
+                );
+            }
+            NodeKind::Loop { ref body } => {
+                let start_label = ops.asm.new_dynamic_label();
+                let bwd_label = ops.asm.new_dynamic_label();
+                let fwd_label = ops.asm.new_dynamic_label();
+                my_dynasm!(ops
+                    ;=>start_label
+                );
+                trace_point(ops, position, Op::LoopStart);
+                load_current(ops, options, position);
+                my_dynasm!(ops
                     ; cbz w9, =>fwd_label
                     ;=>bwd_label
-                )
-            }
-            b']' => {
-                let (bwd_label, fwd_label) = loops.pop().ok_or(Error::UnmatchedRight)?;
+                );
+                lower(ops, body, options);
+                ops.loop_end(&nodes[0]);
+                trace_point(ops, nodes[0].span.end - 1, Op::LoopEnd);
+                back_edge(ops, options);
+                load_current(ops, options, nodes[0].span.end - 1);
+                // Traced, it goes back to check the cell at `[` again as the interpreter does, so that their traces match.
+                let bwd_label = if ops.trace { start_label } else { bwd_label };
                 my_dynasm!(ops
-                    ; ldrb w9, [ptr, xidx]
                     ; cbnz w9, =>bwd_label
                     ;=>fwd_label
-                )
+                );
             }
-            _ => {}
         }
     }
+}
+
+pub fn run(
+    program: &[Node],
+    options: &Options,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> Result<(), Error> {
+    run_traced(program, options, input, output, None)
+}
 
-    if !loops.is_empty() {
-        return Err(Error::UnmatchedLeft);
+/// Runs `program` the same as [`run`], compiled with calls to write the records of the conditions of loops
+/// and the end into `trace` if any.
+pub(crate) fn run_traced(
+    program: &[Node],
+    options: &Options,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    trace: Option<&mut crate::trace::Writer<dyn Write + '_>>,
+) -> Result<(), Error> {
+    let (opcode, faults) = compile(program, options, None, trace.is_some())?;
+    run_opcode(opcode.as_ref(), &faults, options, input, output, trace)
+}
+
+/// Returns the machine code generated for `program`, with the default [`Options`].
+pub fn code(program: &[Node]) -> Result<Vec<u8>, Error> {
+    Ok(compile(program, &Options::default(), None, false)?
+        .0
+        .to_vec())
+}
+
+/// Returns the annotated assembly listing of the machine code generated for `program`, with the default [`Options`].
+pub fn listing(program: &[Node]) -> Result<String, Error> {
+    let mut listing = Listing::default();
+    let (code, _) = compile(program, &Options::default(), Some(&mut listing), false)?;
+    Ok(listing.render(&code))
+}
+
+/// Lowers `nodes`, which are all either [`NodeKind::Add`] or [`NodeKind::Set`].
+/// The cells are addressed with offsets from the index, but they must wrap around
+/// when they are beyond either end of the tape. So, only if the index is far enough from the ends,
+/// the cells are accessed directly. Otherwise, the index for each cell is wrapped separately.
+/// Without wrapping around, the index for each cell is checked separately instead.
+fn lower_cells(ops: &mut Ops<Assembler>, nodes: &[Node], options: &Options) {
+    let cell = options.cell_width;
+    let mask = (options.tape_size - 1) as u32;
+    let offsets = nodes.iter().filter_map(Node::cell_offset);
+    let min = offsets.clone().min().unwrap_or(0).min(0);
+    let max = offsets.max().unwrap_or(0).max(0);
+
+    let slow_label = ops.asm.new_dynamic_label();
+    let end_label = ops.asm.new_dynamic_label();
+    // `ldrb` and `strb` only take unsigned 12 bit offsets, and `ldurb` and `sturb` take signed 9 bit ones.
+    // The latter are in bytes, so they reach fewer cells as the cells get wider.
+    // Always use the slow path for offsets beyond them, or beyond the tape itself.
+    let range = -256 >> cell.shift()..1 << 12;
+    let direct = range.contains(&min)
+        && range.contains(&max)
+        && min.unsigned_abs() <= mask
+        && max as u32 <= mask
+        && options.bounds == Bounds::Wrap;
+    if direct {
+        if min < 0 {
+            mov_imm(ops, 10, min.unsigned_abs());
+            my_dynasm!(ops
+                ; cmp idx, w10
+                ; b.lo =>slow_label
+            );
+        }
+        if max > 0 {
+            mov_imm(ops, 10, mask - max as u32);
+            my_dynasm!(ops
+                ; cmp idx, w10
+                ; b.hi =>slow_label
+            );
+        }
+        if min < 0 || max > 0 {
+            cell_address(ops, options, 12);
+        }
+
+        for node in nodes {
+            let offset = node.cell_offset().unwrap_or(0);
+            match node.kind {
+                NodeKind::Add { amount, .. } => {
+                    load_cell(ops, cell, offset);
+                    add_amount(ops, cell, amount);
+                    store_cell(ops, cell, offset);
+                }
+                NodeKind::Set { value, .. } => {
+                    mov_imm(ops, 9, cell.truncate(value));
+                    store_cell(ops, cell, offset);
+                }
+                _ => unreachable!("only changes to cells are lowered together"),
+            }
+        }
+
+        if min == 0 && max == 0 {
+            return;
+        }
+        my_dynasm!(ops; b =>end_label);
     }
 
     my_dynasm!(ops
-        // Keep `x0` set by `putchar` and `getchar` functions as it is for the return value.
-        ;->throwing:
-        ; ldp ptr, xidx, [sp]
+        ;=>slow_label
+    );
+    for node in nodes {
+        let offset = node.cell_offset().unwrap_or(0);
+        if options.bounds == Bounds::Wrap {
+            wrapped_index(ops, offset, mask);
+        } else {
+            checked_index(ops, options, offset, node.span.start);
+        }
+        match node.kind {
+            NodeKind::Add { amount, .. } => {
+                load_wrapped(ops, cell);
+                add_amount(ops, cell, amount);
+                store_wrapped(ops, cell);
+            }
+            NodeKind::Set { value, .. } => {
+                mov_imm(ops, 9, cell.truncate(value));
+                store_wrapped(ops, cell);
+            }
+            _ => unreachable!("only changes to cells are lowered together"),
+        }
+    }
+    my_dynasm!(ops
+        ;=>end_label
+    );
+}
 
-        ; ldr x30, [sp, #16]
-        ; add sp, sp, #32
-        ; ret
-        
-        // Literal pool to store 64 bit constants:
-        ; ->putchar_off:
-        ; .qword putchar as _
-        ; ->getchar_off:
-        ; .qword getchar as _
+/// Multiplies w9 by `factor` and adds it to the cell at the index in w11.
+fn multiply_add(ops: &mut Ops<Assembler>, cell: CellWidth, factor: i32) {
+    mov_imm(ops, 10, cell.truncate(factor));
+    my_dynasm!(ops
+        ; mul w9, w9, w10
+        ; mov w10, w9
     );
+    // The other cell is loaded into w9 as it is where `load_wrapped` and `store_wrapped` work.
+    load_wrapped(ops, cell);
+    my_dynasm!(ops
+        ; add w9, w9, w10
+    );
+    store_wrapped(ops, cell);
+}
+
+/// Calculates the index of the cell `offset` cells away into w11, making sure it is on the tape.
+/// If it isn't, `bounds` either grows the tape or ends the programme with the error at `position`.
+/// Between guard pages, the access faults instead, so it is only written down for the signal handler.
+/// This is only for [`Bounds::Error`], [`Bounds::Grow`] and [`Bounds::Guard`], as the index is masked with [`Bounds::Wrap`].
+fn checked_index(ops: &mut Ops<Assembler>, options: &Options, offset: i32, position: usize) {
+    if guarded(options) {
+        let start = ops.asm.offset().0;
+        ops.faults.accesses.push((start, position));
+        // The index is zero-extended into x11, so the cell is within 2^32 cells from the tape.
+        offset_index(ops, 11, offset);
+        return;
+    }
+    let retry_label = ops.asm.new_dynamic_label();
+    let ok_label = ops.asm.new_dynamic_label();
+    my_dynasm!(ops
+        ;=>retry_label
+    );
+    offset_index(ops, 11, offset);
+    my_dynasm!(ops
+        // The index on the left of the tape is negative, which is larger than any size as unsigned.
+        ; cmp w11, size
+        ; b.lo =>ok_label
+        ; mov x0, ctx
+        ; mov w1, w11
+    );
+    mov_imm(ops, 2, position as u32);
+    my_dynasm!(ops
+        ; ldr x9, ->bounds_off
+        ; blr x9
+        ; cbz x0, ->throwing
+        // Take the new tape, and calculate the index again as calling clobbers w11.
+        ; mov ptr, x0
+        ; mov size, w1
+        ; movz w0, 1 // Restore the return value
+        ; b =>retry_label
+        ;=>ok_label
+    );
+}
+
+/// Checks at the end of each iteration of loops whether the programme is cancelled, ending it if so,
+/// and takes a step from the budget with [`Options::max_steps`] or [`Options::timeout`].
+/// When the steps handed out run out, `limit` either hands out more or ends the programme.
+fn back_edge(ops: &mut Ops<Assembler>, options: &Options) {
+    if options.cancel.is_some() {
+        let running_label = ops.asm.new_dynamic_label();
+        my_dynasm!(ops
+            ; ldr x9, ->cancel_off
+            ; ldrb w9, [x9]
+            ; cbz w9, =>running_label
+            ; mov w0, wzr
+            ; b ->throwing
+            ;=>running_label
+        );
+    }
+    if !options.limited() {
+        return;
+    }
+    let ok_label = ops.asm.new_dynamic_label();
+    my_dynasm!(ops
+        // It only borrows, clearing the carry flag, when there were no steps left.
+        ; subs budget, budget, #1
+        ; b.hs =>ok_label
+        ; mov x0, ctx
+        ; ldr x9, ->limit_off
+        ; blr x9
+        ; cbz x0, ->throwing
+        // Take this step from the ones handed out.
+        ; sub budget, x0, #1
+        ; movz w0, 1 // Restore the return value
+        ;=>ok_label
+    );
+}
+
+/// Calls `trace` with the record of `op` at `position` in the source, if the programme is compiled with tracing.
+/// The index isn't checked, as the record tells where it is even if it's off the tape.
+fn trace_point(ops: &mut Ops<Assembler>, position: usize, op: Op) {
+    if ops.trace {
+        my_dynasm!(ops
+            ; mov x0, ctx
+            ; mov x1, ptr
+            ; mov w2, idx
+        );
+        mov_imm(ops, 3, position as u32);
+        my_dynasm!(ops
+            ; movz w4, op as u32
+            ; ldr x9, ->trace_off
+            ; blr x9
+            ; cbz w0, ->throwing
+        );
+    }
+}
+
+/// Makes sure the current cell is on the tape without wrapping around, as [`checked_index`] does.
+/// The index is in w11 then, which is where [`cell_address`] takes it from.
+fn current_cell(ops: &mut Ops<Assembler>, options: &Options, position: usize) {
+    if options.bounds != Bounds::Wrap {
+        checked_index(ops, options, 0, position);
+    }
+}
+
+/// Reads the current cell between guard pages before passing it to `putchar` or `getchar`,
+/// so that it faults in the machine code rather than in them.
+fn probe(ops: &mut Ops<Assembler>, options: &Options) {
+    if guarded(options) {
+        load_wrapped(ops, options.cell_width);
+    }
+}
+
+/// Loads the current cell into w9, making sure it is on the tape as [`current_cell`] does.
+fn load_current(ops: &mut Ops<Assembler>, options: &Options, position: usize) {
+    if options.bounds == Bounds::Wrap {
+        load_cell(ops, options.cell_width, 0);
+    } else {
+        checked_index(ops, options, 0, position);
+        load_wrapped(ops, options.cell_width);
+    }
+}
 
-    Ok(ops.finalize().expect("Finalising the exec buffer failed"))
+/// Loads the cell `offset` cells away into w9.
+/// Unless `offset` is 0, x12 must be the address of the current cell.
+fn load_cell(ops: &mut Ops<Assembler>, cell: CellWidth, offset: i32) {
+    let bytes = offset * cell.bytes() as i32;
+    match cell {
+        CellWidth::U8 if offset == 0 => my_dynasm!(ops; ldrb w9, [ptr, xidx]),
+        CellWidth::U16 if offset == 0 => my_dynasm!(ops; ldrh w9, [ptr, xidx, lsl 1]),
+        CellWidth::U32 if offset == 0 => my_dynasm!(ops; ldr w9, [ptr, xidx, lsl 2]),
+        CellWidth::U8 if offset > 0 => my_dynasm!(ops; ldrb w9, [x12, bytes as u32]),
+        CellWidth::U16 if offset > 0 => my_dynasm!(ops; ldrh w9, [x12, bytes as u32]),
+        CellWidth::U32 if offset > 0 => my_dynasm!(ops; ldr w9, [x12, bytes as u32]),
+        CellWidth::U8 => my_dynasm!(ops; ldurb w9, [x12, bytes]),
+        CellWidth::U16 => my_dynasm!(ops; ldurh w9, [x12, bytes]),
+        CellWidth::U32 => my_dynasm!(ops; ldur w9, [x12, bytes]),
+    }
+}
+
+/// Stores w9 into the cell `offset` cells away, the same way as [`load_cell`].
+fn store_cell(ops: &mut Ops<Assembler>, cell: CellWidth, offset: i32) {
+    let bytes = offset * cell.bytes() as i32;
+    match cell {
+        CellWidth::U8 if offset == 0 => my_dynasm!(ops; strb w9, [ptr, xidx]),
+        CellWidth::U16 if offset == 0 => my_dynasm!(ops; strh w9, [ptr, xidx, lsl 1]),
+        CellWidth::U32 if offset == 0 => my_dynasm!(ops; str w9, [ptr, xidx, lsl 2]),
+        CellWidth::U8 if offset > 0 => my_dynasm!(ops; strb w9, [x12, bytes as u32]),
+        CellWidth::U16 if offset > 0 => my_dynasm!(ops; strh w9, [x12, bytes as u32]),
+        CellWidth::U32 if offset > 0 => my_dynasm!(ops; str w9, [x12, bytes as u32]),
+        CellWidth::U8 => my_dynasm!(ops; sturb w9, [x12, bytes]),
+        CellWidth::U16 => my_dynasm!(ops; sturh w9, [x12, bytes]),
+        CellWidth::U32 => my_dynasm!(ops; stur w9, [x12, bytes]),
+    }
 }
 
-pub fn run(program: &[u8]) -> Result<(), Error> {
-    let opcode = compile(program)?;
-    run_opcode(opcode.as_ref())
+/// Loads the cell at the index in w11 into w9, which is either wrapped around or checked.
+fn load_wrapped(ops: &mut Ops<Assembler>, cell: CellWidth) {
+    match cell {
+        CellWidth::U8 => my_dynasm!(ops; ldrb w9, [ptr, x11]),
+        CellWidth::U16 => my_dynasm!(ops; ldrh w9, [ptr, x11, lsl 1]),
+        CellWidth::U32 => my_dynasm!(ops; ldr w9, [ptr, x11, lsl 2]),
+    }
+}
+
+/// Stores w9 into the cell at the index in w11.
+fn store_wrapped(ops: &mut Ops<Assembler>, cell: CellWidth) {
+    match cell {
+        CellWidth::U8 => my_dynasm!(ops; strb w9, [ptr, x11]),
+        CellWidth::U16 => my_dynasm!(ops; strh w9, [ptr, x11, lsl 1]),
+        CellWidth::U32 => my_dynasm!(ops; str w9, [ptr, x11, lsl 2]),
+    }
+}
+
+/// Calculates the address of the current cell into the register `rd`,
+/// where the index is in w11 after [`current_cell`] unless it wraps around.
+/// The upper halves of `xidx` and x11 are always 0 as writing to the lower halves clears them.
+fn cell_address(ops: &mut Ops<Assembler>, options: &Options, rd: u32) {
+    match (options.cell_width, options.bounds) {
+        (CellWidth::U8, Bounds::Wrap) => my_dynasm!(ops; add X(rd), ptr, xidx),
+        (CellWidth::U16, Bounds::Wrap) => my_dynasm!(ops; add X(rd), ptr, xidx, lsl 1),
+        (CellWidth::U32, Bounds::Wrap) => my_dynasm!(ops; add X(rd), ptr, xidx, lsl 2),
+        (CellWidth::U8, _) => my_dynasm!(ops; add X(rd), ptr, x11),
+        (CellWidth::U16, _) => my_dynasm!(ops; add X(rd), ptr, x11, lsl 1),
+        (CellWidth::U32, _) => my_dynasm!(ops; add X(rd), ptr, x11, lsl 2),
+    }
+}
+
+/// Adds `amount` to w9. Storing truncates the sum, so a negative amount can be subtracted instead.
+fn add_amount(ops: &mut Ops<Assembler>, cell: CellWidth, amount: i32) {
+    let value = cell.truncate(amount);
+    // `add` and `sub` only take a 12 bit immediate. Use a register for larger amounts.
+    if value < 1 << 12 {
+        my_dynasm!(ops; add w9, w9, value);
+    } else if amount < 0 && amount.unsigned_abs() < 1 << 12 {
+        my_dynasm!(ops; sub w9, w9, amount.unsigned_abs());
+    } else {
+        mov_imm(ops, 10, value);
+        my_dynasm!(ops; add w9, w9, w10);
+    }
+}
+
+/// Sets `value` to the register `rd`, with `movk` for the upper half if it isn't 0.
+fn mov_imm(ops: &mut Ops<Assembler>, rd: u32, value: u32) {
+    my_dynasm!(ops; movz W(rd), value & 0xffff);
+    if value >> 16 != 0 {
+        my_dynasm!(ops; movk W(rd), value >> 16, lsl 16);
+    }
+}
+
+/// Calculates the index of the cell `offset` cells away into w11, wrapping it the same way as the pointer.
+fn wrapped_index(ops: &mut Ops<Assembler>, offset: i32, mask: u32) {
+    offset_index(ops, 11, offset);
+    wrap(ops, 11, mask);
+}
+
+/// Calculates the index `offset` cells away from the current one into the register `rd`,
+/// going through w9 if `offset` doesn't fit in the 12 bit immediate.
+fn offset_index(ops: &mut Ops<Assembler>, rd: u32, offset: i32) {
+    if offset.unsigned_abs() >= 1 << 12 {
+        mov_imm(ops, 9, offset as u32);
+        my_dynasm!(ops; add W(rd), idx, w9);
+    } else if offset < 0 {
+        my_dynasm!(ops; sub W(rd), idx, offset.unsigned_abs());
+    } else {
+        my_dynasm!(ops; add W(rd), idx, offset as u32);
+    }
+}
+
+/// Wraps the index in the register `rd` around the tape, where `mask` is one less than its size.
+fn wrap(ops: &mut Ops<Assembler>, rd: u32, mask: u32) {
+    // `and` can't take 0 as the immediate value, which is for a tape of one cell.
+    if mask == 0 {
+        my_dynasm!(ops; mov W(rd), wzr);
+    } else {
+        my_dynasm!(ops; and W(rd), W(rd), mask);
+    }
 }
@@ -0,0 +1,799 @@
+use crate::{
+    ir::{Node, NodeKind},
+    jit::{bounds, dump, getchar, guarded, limit, putchar, run_opcode, scan, trace, Faults},
+    trace::Op,
+    Bounds, CellWidth, Error, Options,
+};
+use memmap2::{Mmap, MmapMut};
+use std::io::{Read, Write};
+
+// A64 instructions are all 32 bit words, with the registers in 5 bit fields.
+// The destination is usually the lowest field, followed by the first and the second operand.
+// 31 is either the zero register or the stack pointer depending on the instruction.
+
+/// The pointer to the tape.
+const PTR: u32 = 19;
+/// The index of the current cell, which is kept on the tape unless it is checked on access.
+const IDX: u32 = 20;
+/// The functions called, loaded from the literal pool in the prelude.
+const PUTCHAR: u32 = 21;
+const GETCHAR: u32 = 22;
+const SCAN: u32 = 23;
+/// The context for the functions called.
+const CTX: u32 = 24;
+/// The number of cells on the tape, which `bounds` can change.
+const SIZE: u32 = 25;
+/// `bounds`, also loaded from the literal pool.
+const BOUNDS: u32 = 26;
+/// The steps handed out by `limit`, with [`Options::max_steps`] or [`Options::timeout`].
+const BUDGET: u32 = 27;
+/// `limit`, also loaded from the literal pool with the limits.
+const LIMIT: u32 = 28;
+/// The link register.
+const LR: u32 = 30;
+const SP: u32 = 31;
+const ZR: u32 = 31;
+
+/// Conditions of `b.cond`, which are the same as the ones of `cmp` for unsigned numbers.
+const EQ: u32 = 0b0000;
+const HS: u32 = 0b0010;
+const LO: u32 = 0b0011;
+const HI: u32 = 0b1000;
+
+const NOP: u32 = 0xd503_201f;
+const RET: u32 = 0xd65f_03c0;
+
+fn compile(program: &[Node], options: &Options, trace: bool) -> Result<(Mmap, Faults), Error> {
+    let mut faults = Faults::default();
+    let writer = assemble(program, options, &mut faults, trace);
+
+    // The use of `mmap` is neccessary as POSIX defines `mprotect` only for `mmap`.
+    // It also aligns the code to a page, which `adrp` in the prelude relies on.
+    // Linux keeps the instruction cache coherent for the new executable mapping.
+    let mut opcode = MmapMut::map_anon(writer.len() * 4)?;
+    for (bytes, instruction) in opcode.chunks_exact_mut(4).zip(writer) {
+        // A64 instructions are always little endian.
+        bytes.copy_from_slice(&instruction.to_le_bytes());
+    }
+    Ok((opcode.make_exec()?, faults))
+}
+
+/// Writes machine code for `program` running with `options`, calling [`trace`] at the conditions of loops
+/// and at the end with `trace`. Where it accesses cells between guard pages is written down in `faults`.
+fn assemble(program: &[Node], options: &Options, faults: &mut Faults, trace: bool) -> Vec<u32> {
+    // Although the number of nodes doesn't tell the size of machine code, it is still a good indicator.
+    let mut writer = Vec::with_capacity(program.len() * 4);
+    let mut throwing_dsts = Vec::new();
+
+    // The signature of compiled routine is `fn(*mut u8, *mut Context)`, taking the arguments in x0 and x1.
+    // Save x19 to x26 as they are callee-saved, along with the link register used by `blr`,
+    // and x27 and x28 as well with the limits.
+    // The frame is in units of 8 bytes, which is how store pairs scale their offset, so it is 96 or 80 bytes.
+    // The stack pointer must stay aligned to 16 bytes, so the number of units is even.
+    let frame = if options.limited() { 12 } else { 10 };
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        // Store pairs take the offset scaled by 8 as a signed 7 bit value.
+        // 0xa980_0000 writes the address back to the base register before storing.
+        0xa980_0000 | (-frame as u32 & 0x7f) << 15 | IDX << 10 | SP << 5 | PTR, // stp x19, x20, [sp, #-frame]!
+        0xa900_0000 | 2 << 15 | GETCHAR << 10 | SP << 5 | PUTCHAR, // stp x21, x22, [sp, #16]
+        0xa900_0000 | 4 << 15 | CTX << 10 | SP << 5 | SCAN, // stp x23, x24, [sp, #32]
+        0xa900_0000 | 6 << 15 | BOUNDS << 10 | SP << 5 | SIZE, // stp x25, x26, [sp, #48]
+        // The offset of a single store is unsigned and scaled by 8.
+        0xf900_0000 | 8 << 10 | SP << 5 | LR, // str x30, [sp, #64]
+    ]);
+    if options.limited() {
+        writer.push(0xa900_0000 | 9 << 15 | LIMIT << 10 | SP << 5 | BUDGET); // stp x27, x28, [sp, #72]
+    }
+    mov_imm(&mut writer, SIZE, options.tape_size as u32); // mov w25, #tape_size
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        mov_x(PTR, 0), // mov x19, x0
+        mov_x(CTX, 1), // mov x24, x1
+        mov_w(IDX, ZR), // mov w20, wzr
+    ]);
+    // The literal pool is at the end of the code, which can be further than `ldr` with a literal reaches.
+    // So, take the page of it with `adrp` and load from there. The offsets are filled in later.
+    let pool_dst = writer.len();
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        0x9000_0000 | 9, // adrp x9, pool
+        // Loads take the offset the same way as the store above.
+        0xf940_0000 | 9 << 5 | PUTCHAR, // ldr x21, [x9, #pool]
+        0xf940_0000 | 9 << 5 | GETCHAR, // ldr x22, [x9, #pool + 8]
+        0xf940_0000 | 9 << 5 | SCAN, // ldr x23, [x9, #pool + 16]
+        0xf940_0000 | 9 << 5 | BOUNDS, // ldr x26, [x9, #pool + 24]
+    ]);
+    if options.limited() {
+        #[rustfmt::skip]
+        writer.extend_from_slice(&[
+            0xf940_0000 | 9 << 5 | LIMIT, // ldr x28, [x9, #pool + 32]
+            // Call `limit` for the first steps at the first iteration.
+            mov_x(BUDGET, ZR), // mov x27, xzr
+        ]);
+    }
+    let loads = if options.limited() { 5 } else { 4 };
+    writer.push(movz(0, 1)); // mov w0, #1
+
+    lower(
+        &mut writer,
+        program,
+        &mut throwing_dsts,
+        faults,
+        options,
+        trace,
+    );
+    if trace {
+        let end = program.last().map_or(0, |node| node.span.end);
+        trace_point(&mut writer, &mut throwing_dsts, end, Op::End);
+    }
+
+    for throwing_dst in throwing_dsts {
+        patch(&mut writer, throwing_dst);
+    }
+    faults.throwing = writer.len() * 4;
+
+    // Keep w0 set by the functions called as it is for the return value.
+    if options.limited() {
+        writer.push(0xa940_0000 | 9 << 15 | LIMIT << 10 | SP << 5 | BUDGET); // ldp x27, x28, [sp, #72]
+    }
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        0xf940_0000 | 8 << 10 | SP << 5 | LR, // ldr x30, [sp, #64]
+        0xa940_0000 | 6 << 15 | BOUNDS << 10 | SP << 5 | SIZE, // ldp x25, x26, [sp, #48]
+        0xa940_0000 | 4 << 15 | CTX << 10 | SP << 5 | SCAN, // ldp x23, x24, [sp, #32]
+        0xa940_0000 | 2 << 15 | GETCHAR << 10 | SP << 5 | PUTCHAR, // ldp x21, x22, [sp, #16]
+        // 0xa8c0_0000 adds the offset to the base register after loading.
+        0xa8c0_0000 | (frame as u32) << 15 | IDX << 10 | SP << 5 | PTR, // ldp x19, x20, [sp], #frame
+        RET,
+    ]);
+
+    // Align the literal pool to 8 bytes so that the scaled offsets can point to it.
+    if writer.len() % 2 != 0 {
+        writer.push(NOP);
+    }
+    let pool = writer.len() * 4;
+    let functions = [
+        putchar as *const () as u64,
+        getchar as *const () as u64,
+        match options.cell_width {
+            CellWidth::U8 => scan::<u8> as *const () as u64,
+            CellWidth::U16 => scan::<u16> as *const () as u64,
+            CellWidth::U32 => scan::<u32> as *const () as u64,
+        },
+        bounds as *const () as u64,
+        limit as *const () as u64,
+    ];
+    for &function in &functions[..loads] {
+        writer.extend_from_slice(&[function as u32, (function >> 32) as u32]);
+    }
+
+    // The code starts at the beginning of a page, so the distance in pages only depends on the offsets.
+    let pages = (pool >> 12) - ((pool_dst * 4) >> 12);
+    // `adrp` takes the lowest 2 bits of the 21 bit distance apart from the rest.
+    writer[pool_dst] |= (pages as u32 & 0b11) << 29 | (pages as u32 >> 2 & 0x7_ffff) << 5;
+    for (i, load) in writer[pool_dst + 1..pool_dst + 1 + loads]
+        .iter_mut()
+        .enumerate()
+    {
+        *load |= (((pool & 0xfff) / 8 + i) as u32) << 10;
+    }
+
+    writer
+}
+
+/// Writes machine code for `nodes` into `writer`, calling [`trace`] at the conditions of loops with `trace`.
+/// The locations of the branches to the postlude on errors are pushed to `throwing_dsts`,
+/// and the accesses to cells between guard pages to `faults`.
+fn lower(
+    writer: &mut Vec<u32>,
+    nodes: &[Node],
+    throwing_dsts: &mut Vec<usize>,
+    faults: &mut Faults,
+    options: &Options,
+    trace: bool,
+) {
+    let cell = options.cell_width;
+    // The tape has as many cells as a power of two to wrap around, so the mask is one less.
+    let mask = (options.tape_size - 1) as u32;
+    // Changes to cells in a row are lowered together.
+    for nodes in nodes.chunk_by(|a, b| a.cell_offset().is_some() && b.cell_offset().is_some()) {
+        let position = nodes[0].span.start;
+        match nodes[0].kind {
+            NodeKind::Move { amount } => {
+                // The index is only checked when a cell is accessed, unless it wraps around.
+                offset_index(writer, IDX, amount);
+                if options.bounds == Bounds::Wrap {
+                    wrap(writer, IDX, mask);
+                }
+            }
+            NodeKind::Add { .. } | NodeKind::Set { .. } => {
+                lower_cells(writer, nodes, throwing_dsts, faults, options)
+            }
+            NodeKind::Scan { stride } if options.bounds != Bounds::Wrap => {
+                // Check every cell on the way, which is where it can go out of the tape.
+                let start_label = writer.len();
+                back_edge(writer, throwing_dsts, options);
+                checked_index(writer, throwing_dsts, faults, options, 0, position);
+                writer.push(ldr_reg(cell, 9, PTR, 11)); // ldrb w9, [x19, x11]
+                let end_label_dst = branch_fwd(writer, cbz(9)); // cbz w9, end
+                offset_index(writer, IDX, stride);
+                branch_bwd(writer, b_always(), start_label); // b start
+                patch(writer, end_label_dst);
+            }
+            NodeKind::Scan { stride } => {
+                // See `asm.rs` for how this works.
+                writer.push(ldr_reg(cell, 9, PTR, IDX)); // ldrb w9, [x19, x20]
+                let end_label_dst = branch_fwd(writer, cbz(9)); // cbz w9, end
+                let start_label = writer.len();
+                back_edge(writer, throwing_dsts, options);
+                let stride = stride as u32;
+                #[rustfmt::skip]
+                writer.extend_from_slice(&[
+                    mov_x(0, PTR), // mov x0, x19
+                    mov_w(1, SIZE), // mov w1, w25
+                    mov_w(2, IDX), // mov w2, w20
+                    movz(3, stride & 0xffff), // movz w3, #stride
+                    // 0x7280_0000 is `movk`, which keeps the other bits. 1 << 21 shifts the value by 16.
+                    0x7280_0000 | 1 << 21 | (stride >> 16) << 5 | 3, // movk w3, #stride, lsl #16
+                    // 0xd63f_0000 is `blr`, calling the address in a register.
+                    0xd63f_0000 | SCAN << 5, // blr x23
+                    // `cmn` is `adds` to the zero register. The immediate value is at the bit 10.
+                    0x3100_0000 | 1 << 10 | ZR, // cmn w0, #1
+                ]);
+                branch_bwd(writer, b_cond(EQ), start_label); // b.eq start
+                #[rustfmt::skip]
+                writer.extend_from_slice(&[
+                    mov_w(IDX, 0), // mov w20, w0
+                    movz(0, 1), // mov w0, #1
+                ]);
+                patch(writer, end_label_dst);
+            }
+            NodeKind::MulAdd { offset, factor } if options.bounds != Bounds::Wrap => {
+                // Only access the other cell if the loop this comes from runs, as it can be out of the tape.
+                checked_index(writer, throwing_dsts, faults, options, 0, position);
+                writer.push(ldr_reg(cell, 9, PTR, 11)); // ldrb w9, [x19, x11]
+                let end_label_dst = branch_fwd(writer, cbz(9)); // cbz w9, end
+                checked_index(writer, throwing_dsts, faults, options, offset, position);
+                // Load the current cell again as `bounds` may have been called. It is on the tape as it is checked above.
+                writer.push(ldr_reg(cell, 9, PTR, IDX)); // ldrb w9, [x19, x20]
+                multiply_add(writer, cell, factor);
+                patch(writer, end_label_dst);
+            }
+            NodeKind::MulAdd { offset, factor } => {
+                wrapped_index(writer, offset, mask);
+                writer.push(ldr_reg(cell, 9, PTR, IDX)); // ldrb w9, [x19, x20]
+                multiply_add(writer, cell, factor);
+            }
+            NodeKind::Output | NodeKind::Input => {
+                let function = if let NodeKind::Output = nodes[0].kind {
+                    PUTCHAR
+                } else {
+                    GETCHAR
+                };
+                current_cell(writer, throwing_dsts, faults, options, position);
+                if guarded(options) {
+                    // Read the cell so that it faults here rather than in `putchar` or `getchar`.
+                    writer.push(ldr_reg(cell, 9, PTR, 11)); // ldrb w9, [x19, x11]
+                }
+                #[rustfmt::skip]
+                writer.extend_from_slice(&[
+                    mov_x(0, CTX), // mov x0, x24
+                    // The upper halves of x20 and x11 are always 0 as writing to the lower halves clears them.
+                    // For wider cells, this points to the lowest byte as the tape is little endian.
+                    add_x_shifted(cell, 1, PTR, current_index(options)), // add x1, x19, x20
+                    0xd63f_0000 | function << 5, // blr x21 or x22
+                ]);
+                throwing_dsts.push(branch_fwd(writer, cbz(0))); // cbz w0, throwing
+            }
+            // The index isn't checked, as `dump` shows where it is even if it's off the tape.
+            // There's no register left for it, so the address comes with the call rather than from the literal pool.
+            NodeKind::Dump => {
+                #[rustfmt::skip]
+                writer.extend_from_slice(&[
+                    mov_x(0, CTX), // mov x0, x24
+                    mov_x(1, PTR), // mov x1, x19
+                    mov_w(2, IDX), // mov w2, w20
+                ]);
+                mov_imm(writer, 3, position as u32); // mov w3, #position
+                mov_address(writer, 9, dump as *const () as u64); // mov x9, #dump
+                writer.push(0xd63f_0000 | 9 << 5); // blr x9
+                throwing_dsts.push(branch_fwd(writer, cbz(0))); // cbz w0, throwing
+            }
+            NodeKind::Loop { ref body } => {
+                let start_label = writer.len();
+                if trace {
+                    trace_point(writer, throwing_dsts, position, Op::LoopStart);
+                }
+                load_current(writer, throwing_dsts, faults, options, position);
+                let fwd_label_dst = branch_fwd(writer, cbz(9)); // cbz w9, fwd
+                let bwd_label = writer.len();
+
+                lower(writer, body, throwing_dsts, faults, options, trace);
+
+                if trace {
+                    let end = nodes[0].span.end - 1;
+                    trace_point(writer, throwing_dsts, end, Op::LoopEnd);
+                }
+                back_edge(writer, throwing_dsts, options);
+                load_current(
+                    writer,
+                    throwing_dsts,
+                    faults,
+                    options,
+                    nodes[0].span.end - 1,
+                );
+                // Traced, it goes back to check the cell at `[` again as the interpreter does, so that their traces match.
+                let bwd_label = if trace { start_label } else { bwd_label };
+                branch_bwd(writer, cbnz(9), bwd_label); // cbnz w9, bwd
+                patch(writer, fwd_label_dst);
+            }
+        }
+    }
+}
+
+pub fn run(
+    program: &[Node],
+    options: &Options,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> Result<(), Error> {
+    run_traced(program, options, input, output, None)
+}
+
+/// Runs `program` the same as [`run`], compiled with calls to write the records of the conditions of loops
+/// and the end into `trace` if any.
+pub(crate) fn run_traced(
+    program: &[Node],
+    options: &Options,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    trace: Option<&mut crate::trace::Writer<dyn Write + '_>>,
+) -> Result<(), Error> {
+    let (opcode, faults) = compile(program, options, trace.is_some())?;
+    run_opcode(opcode.as_ref(), &faults, options, input, output, trace)
+}
+
+/// Returns the machine code generated for `program`, with the default [`Options`].
+pub fn code(program: &[Node]) -> Result<Vec<u8>, Error> {
+    Ok(compile(program, &Options::default(), false)?.0.to_vec())
+}
+
+/// Calls [`trace`] with the record of `op` at `position` in the source.
+/// The index isn't checked, as the record tells where it is even if it's off the tape.
+/// The address comes with the call the same as for [`dump`].
+fn trace_point(writer: &mut Vec<u32>, throwing_dsts: &mut Vec<usize>, position: usize, op: Op) {
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        mov_x(0, CTX), // mov x0, x24
+        mov_x(1, PTR), // mov x1, x19
+        mov_w(2, IDX), // mov w2, w20
+    ]);
+    mov_imm(writer, 3, position as u32); // mov w3, #position
+    writer.push(movz(4, op as u32)); // mov w4, #op
+    mov_address(writer, 9, trace as *const () as u64); // mov x9, #trace
+    writer.push(0xd63f_0000 | 9 << 5); // blr x9
+    throwing_dsts.push(branch_fwd(writer, cbz(0))); // cbz w0, throwing
+}
+
+/// Writes machine code for `nodes`, which are all either [`NodeKind::Add`] or [`NodeKind::Set`].
+/// See `asm.rs` for how this works.
+fn lower_cells(
+    writer: &mut Vec<u32>,
+    nodes: &[Node],
+    throwing_dsts: &mut Vec<usize>,
+    faults: &mut Faults,
+    options: &Options,
+) {
+    let cell = options.cell_width;
+    let mask = (options.tape_size - 1) as u32;
+    let offsets = nodes.iter().filter_map(Node::cell_offset);
+    let min = offsets.clone().min().unwrap_or(0).min(0);
+    let max = offsets.max().unwrap_or(0).max(0);
+
+    let mut slow_label_dsts = Vec::new();
+    let mut end_label_dst = None;
+    // The unscaled offsets are in bytes, so they reach fewer cells as they get wider.
+    // Without wrapping around, or with offsets beyond the tape itself, always take the slow path.
+    let range = -256 >> cell.shift()..1 << 12;
+    let direct = range.contains(&min)
+        && range.contains(&max)
+        && min.unsigned_abs() <= mask
+        && max as u32 <= mask
+        && options.bounds == Bounds::Wrap;
+    if direct {
+        if min < 0 {
+            mov_imm(writer, 10, min.unsigned_abs()); // mov w10, #-min
+            writer.push(cmp_reg(IDX, 10)); // cmp w20, w10
+            slow_label_dsts.push(branch_fwd(writer, b_cond(LO))); // b.lo slow
+        }
+        if max > 0 {
+            mov_imm(writer, 10, mask - max as u32); // mov w10, #mask - max
+            writer.push(cmp_reg(IDX, 10)); // cmp w20, w10
+            slow_label_dsts.push(branch_fwd(writer, b_cond(HI))); // b.hi slow
+        }
+        if !slow_label_dsts.is_empty() {
+            writer.push(add_x_shifted(cell, 12, PTR, IDX)); // add x12, x19, x20
+        }
+
+        // The bits 30 and 31 are the size of loads and stores, which is 0 for bytes.
+        let size = cell.shift() << 30;
+        for node in nodes {
+            // Offsets from x12, where 0x3940_0000 is `ldrb` and 0x3900_0000 is `strb`,
+            // which take the offset scaled by the size.
+            // The unscaled versions, `ldurb` and `sturb`, take signed 9 bit offsets at the bit 12.
+            let (load, store) = match node.cell_offset() {
+                Some(0) => (ldr_reg(cell, 9, PTR, IDX), str_reg(cell, 9, PTR, IDX)),
+                Some(offset) if offset > 0 => {
+                    let offset = (offset as u32) << 10 | 12 << 5 | 9;
+                    (0x3940_0000 | size | offset, 0x3900_0000 | size | offset)
+                }
+                Some(offset) => {
+                    let offset =
+                        ((offset * cell.bytes() as i32) as u32 & 0x1ff) << 12 | 12 << 5 | 9;
+                    (0x3840_0000 | size | offset, 0x3800_0000 | size | offset)
+                }
+                None => unreachable!("only changes to cells are lowered together"),
+            };
+            change_cell(writer, cell, node, load, store);
+        }
+
+        if slow_label_dsts.is_empty() {
+            return;
+        }
+        end_label_dst = Some(branch_fwd(writer, b_always())); // b end
+    }
+
+    for slow_label_dst in slow_label_dsts {
+        patch(writer, slow_label_dst);
+    }
+
+    for node in nodes {
+        let offset = node.cell_offset().unwrap_or(0);
+        if options.bounds == Bounds::Wrap {
+            wrapped_index(writer, offset, mask);
+        } else {
+            checked_index(
+                writer,
+                throwing_dsts,
+                faults,
+                options,
+                offset,
+                node.span.start,
+            );
+        }
+        let (load, store) = (ldr_reg(cell, 9, PTR, 11), str_reg(cell, 9, PTR, 11));
+        change_cell(writer, cell, node, load, store);
+    }
+
+    if let Some(end_label_dst) = end_label_dst {
+        patch(writer, end_label_dst);
+    }
+}
+
+/// Writes machine code for `node`, which is either [`NodeKind::Add`] or [`NodeKind::Set`],
+/// where `load` and `store` access the cell with w9.
+fn change_cell(writer: &mut Vec<u32>, cell: CellWidth, node: &Node, load: u32, store: u32) {
+    match node.kind {
+        NodeKind::Add { amount, .. } => {
+            // Storing truncates the sum, so a negative amount can be subtracted instead.
+            writer.push(load); // ldrb w9, [cell]
+            let value = cell.truncate(amount);
+            if value < 1 << 12 {
+                writer.push(0x1100_0000 | value << 10 | 9 << 5 | 9); // add w9, w9, #amount
+            } else if amount < 0 && amount.unsigned_abs() < 1 << 12 {
+                // 0x5100_0000 is `sub`.
+                writer.push(0x5100_0000 | amount.unsigned_abs() << 10 | 9 << 5 | 9);
+            // sub w9, w9, #-amount
+            } else {
+                mov_imm(writer, 10, value); // mov w10, #amount
+                writer.push(add_reg(9, 9, 10)); // add w9, w9, w10
+            }
+            writer.push(store); // strb w9, [cell]
+        }
+        NodeKind::Set { value, .. } => {
+            mov_imm(writer, 9, cell.truncate(value)); // mov w9, #value
+            writer.push(store); // strb w9, [cell]
+        }
+        _ => unreachable!("only changes to cells are lowered together"),
+    }
+}
+
+/// Writes machine code multiplying w9 by `factor` and adding it to the cell at the index in w11.
+fn multiply_add(writer: &mut Vec<u32>, cell: CellWidth, factor: i32) {
+    mov_imm(writer, 10, cell.truncate(factor)); // mov w10, #factor
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        // `mul` is `madd` adding the zero register.
+        0x1b00_7c00 | 10 << 16 | 9 << 5 | 9, // mul w9, w9, w10
+        ldr_reg(cell, 10, PTR, 11), // ldrb w10, [x19, x11]
+        add_reg(10, 10, 9), // add w10, w10, w9
+        str_reg(cell, 10, PTR, 11), // strb w10, [x19, x11]
+    ]);
+}
+
+/// Writes the index of the cell `offset` cells away into w11, making sure it is on the tape.
+/// If it isn't, `bounds` either grows the tape or ends the programme with the error at `position`.
+/// Between guard pages, the access is written down in `faults` instead.
+/// This is only for [`Bounds::Error`], [`Bounds::Grow`] and [`Bounds::Guard`], as the index is masked with [`Bounds::Wrap`].
+fn checked_index(
+    writer: &mut Vec<u32>,
+    throwing_dsts: &mut Vec<usize>,
+    faults: &mut Faults,
+    options: &Options,
+    offset: i32,
+    position: usize,
+) {
+    let retry_label = writer.len();
+    if guarded(options) {
+        faults.accesses.push((writer.len() * 4, position));
+    }
+    offset_index(writer, 11, offset);
+    if guarded(options) {
+        return;
+    }
+    // The index on the left of the tape is negative, which is larger than any size as unsigned.
+    writer.push(cmp_reg(11, SIZE)); // cmp w11, w25
+    let ok_label_dst = branch_fwd(writer, b_cond(LO)); // b.lo ok
+    writer.push(mov_x(0, CTX)); // mov x0, x24
+    writer.push(mov_w(1, 11)); // mov w1, w11
+    mov_imm(writer, 2, position as u32); // mov w2, #position
+                                         // `bounds` returns the new tape in x0 and x1, or the null pointer on errors.
+                                         // 0x8000_0000 makes `cbz` look at the whole x0.
+    writer.push(0xd63f_0000 | BOUNDS << 5); // blr x26
+    throwing_dsts.push(branch_fwd(writer, 0x8000_0000 | cbz(0))); // cbz x0, throwing
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        mov_x(PTR, 0), // mov x19, x0
+        mov_w(SIZE, 1), // mov w25, w1
+        movz(0, 1), // mov w0, #1
+    ]);
+    // Calculate the index again as calling clobbers w11.
+    branch_bwd(writer, b_always(), retry_label); // b retry
+    patch(writer, ok_label_dst);
+}
+
+/// Writes machine code checking at the end of each iteration of loops whether the programme is cancelled,
+/// and taking a step from the budget with [`Options::max_steps`] or [`Options::timeout`]. See `asm.rs` for how this works.
+fn back_edge(writer: &mut Vec<u32>, throwing_dsts: &mut Vec<usize>, options: &Options) {
+    if let Some(cancel) = &options.cancel {
+        mov_address(writer, 9, cancel.flag() as u64); // mov x9, #flag
+        writer.push(0x3940_0000 | 9 << 5 | 9); // ldrb w9, [x9]
+        let running_label_dst = branch_fwd(writer, cbz(9)); // cbz w9, running
+        writer.push(mov_w(0, ZR)); // mov w0, wzr
+        throwing_dsts.push(branch_fwd(writer, b_always())); // b throwing
+        patch(writer, running_label_dst);
+    }
+    if !options.limited() {
+        return;
+    }
+    // 0xf100_0000 is the 64 bit `subs`.
+    writer.push(0xf100_0000 | 1 << 10 | BUDGET << 5 | BUDGET); // subs x27, x27, #1
+    let ok_label_dst = branch_fwd(writer, b_cond(HS)); // b.hs ok
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        mov_x(0, CTX), // mov x0, x24
+        0xd63f_0000 | LIMIT << 5, // blr x28
+    ]);
+    throwing_dsts.push(branch_fwd(writer, 0x8000_0000 | cbz(0))); // cbz x0, throwing
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        // 0xd100_0000 is the 64 bit `sub`.
+        0xd100_0000 | 1 << 10 | BUDGET, // sub x27, x0, #1
+        movz(0, 1), // mov w0, #1
+    ]);
+    patch(writer, ok_label_dst);
+}
+
+/// Makes sure the current cell is on the tape without wrapping around, as [`checked_index`] does.
+/// The index is in w11 then, which [`current_index`] tells.
+fn current_cell(
+    writer: &mut Vec<u32>,
+    throwing_dsts: &mut Vec<usize>,
+    faults: &mut Faults,
+    options: &Options,
+    position: usize,
+) {
+    if options.bounds != Bounds::Wrap {
+        checked_index(writer, throwing_dsts, faults, options, 0, position);
+    }
+}
+
+/// The register with the index of the current cell after [`current_cell`].
+fn current_index(options: &Options) -> u32 {
+    if options.bounds == Bounds::Wrap {
+        IDX
+    } else {
+        11
+    }
+}
+
+/// Writes machine code loading the current cell into w9, making sure it is on the tape as [`current_cell`] does.
+fn load_current(
+    writer: &mut Vec<u32>,
+    throwing_dsts: &mut Vec<usize>,
+    faults: &mut Faults,
+    options: &Options,
+    position: usize,
+) {
+    current_cell(writer, throwing_dsts, faults, options, position);
+    writer.push(ldr_reg(options.cell_width, 9, PTR, current_index(options))); // ldrb w9, [x19, x20]
+}
+
+/// Writes the index of the cell `offset` cells away into w11, wrapping it the same way as the pointer.
+fn wrapped_index(writer: &mut Vec<u32>, offset: i32, mask: u32) {
+    offset_index(writer, 11, offset);
+    wrap(writer, 11, mask);
+}
+
+/// Writes the index `offset` cells away from the current one into the register `rd`,
+/// going through w9 if `offset` doesn't fit in the 12 bit immediate.
+fn offset_index(writer: &mut Vec<u32>, rd: u32, offset: i32) {
+    let value = offset.unsigned_abs();
+    if value >= 1 << 12 {
+        mov_imm(writer, 9, offset as u32); // mov w9, #offset
+        writer.push(add_reg(rd, IDX, 9)); // add wd, w20, w9
+    } else if offset < 0 {
+        // 0x5100_0000 is `sub`.
+        writer.push(0x5100_0000 | value << 10 | IDX << 5 | rd); // sub wd, w20, #-offset
+    } else {
+        writer.push(0x1100_0000 | value << 10 | IDX << 5 | rd); // add wd, w20, #offset
+    }
+}
+
+/// Writes machine code wrapping the index in the register `rd` around the tape, where `mask` is one less than its size.
+fn wrap(writer: &mut Vec<u32>, rd: u32, mask: u32) {
+    if mask == 0 {
+        // `and` can't take 0 as the immediate value, which is for a tape of one cell.
+        writer.push(mov_w(rd, ZR)); // mov wd, wzr
+    } else {
+        // The immediate value of `and` is a pattern, where the field at the bit 10 is one less than the number of ones.
+        // It is always a run of ones from the bit 0 for a power of two.
+        writer.push(0x1200_0000 | (mask.count_ones() - 1) << 10 | rd << 5 | rd);
+        // and wd, wd, #mask
+    }
+}
+
+/// `movz wd, #value`, which sets a 16 bit value.
+fn movz(rd: u32, value: u32) -> u32 {
+    debug_assert!(value <= 0xffff);
+    0x5280_0000 | value << 5 | rd
+}
+
+/// Writes `mov wd, #value`, which is `movz` followed by `movk` if the upper half isn't 0.
+fn mov_imm(writer: &mut Vec<u32>, rd: u32, value: u32) {
+    writer.push(movz(rd, value & 0xffff));
+    if value >> 16 != 0 {
+        // 0x7280_0000 is `movk`, which keeps the other bits. 1 << 21 shifts the value by 16.
+        writer.push(0x7280_0000 | 1 << 21 | (value >> 16) << 5 | rd);
+    }
+}
+
+/// Writes `mov xd, #value` for a 64 bit address, which takes `movz` and three `movk`, 16 bits each.
+fn mov_address(writer: &mut Vec<u32>, rd: u32, value: u64) {
+    // 0xd280_0000 and 0xf280_0000 are the 64 bit versions, which take the shift divided by 16 at the bit 21.
+    writer.push(0xd280_0000 | (value as u32 & 0xffff) << 5 | rd);
+    for shift in 1..4 {
+        let part = (value >> (16 * shift)) as u32 & 0xffff;
+        writer.push(0xf280_0000 | shift << 21 | part << 5 | rd); // movk xd, #part, lsl #16 * shift
+    }
+}
+
+/// `mov xd, xm`, which is `orr` with the zero register.
+fn mov_x(rd: u32, rm: u32) -> u32 {
+    0xaa00_03e0 | rm << 16 | rd
+}
+
+/// `mov wd, wm`.
+fn mov_w(rd: u32, rm: u32) -> u32 {
+    0x2a00_03e0 | rm << 16 | rd
+}
+
+/// `add wd, wn, wm`.
+fn add_reg(rd: u32, rn: u32, rm: u32) -> u32 {
+    0x0b00_0000 | rm << 16 | rn << 5 | rd
+}
+
+/// `cmp wn, wm`, which is `subs` to the zero register.
+fn cmp_reg(rn: u32, rm: u32) -> u32 {
+    0x6b00_0000 | rm << 16 | rn << 5 | ZR
+}
+
+/// `add xd, xn, xm, lsl #shift`, which is the address of the cell at the index xm from xn.
+/// 0x8b00_0000 is the 64 bit version of `add`, taking the shift at the bit 10.
+fn add_x_shifted(cell: CellWidth, rd: u32, rn: u32, rm: u32) -> u32 {
+    0x8b00_0000 | rm << 16 | cell.shift() << 10 | rn << 5 | rd
+}
+
+/// `ldrb wt, [xn, xm]`, or `ldrh` and `ldr` with xm scaled for wider cells.
+fn ldr_reg(cell: CellWidth, rt: u32, rn: u32, rm: u32) -> u32 {
+    0x3860_6800 | index(cell) | rm << 16 | rn << 5 | rt
+}
+
+/// `strb wt, [xn, xm]`, or `strh` and `str` with xm scaled for wider cells.
+fn str_reg(cell: CellWidth, rt: u32, rn: u32, rm: u32) -> u32 {
+    0x3820_6800 | index(cell) | rm << 16 | rn << 5 | rt
+}
+
+/// The size of loads and stores at the bit 30, along with the bit 12 to scale the index by it.
+fn index(cell: CellWidth) -> u32 {
+    let shift = cell.shift();
+    shift << 30 | u32::from(shift != 0) << 12
+}
+
+// Branches without the offsets, which are counted in instructions, not bytes.
+// The offsets of conditional branches are 19 bits, reaching only ±1 MiB,
+// while unconditional ones are 26 bits, reaching ±128 MiB.
+
+/// `cbz wt`, branching if the register is 0.
+fn cbz(rt: u32) -> u32 {
+    0x3400_0000 | rt
+}
+
+/// `cbnz wt`, branching if the register is not 0.
+fn cbnz(rt: u32) -> u32 {
+    0x3500_0000 | rt
+}
+
+/// `b.cond`.
+fn b_cond(cond: u32) -> u32 {
+    0x5400_0000 | cond
+}
+
+/// `b`.
+fn b_always() -> u32 {
+    0x1400_0000
+}
+
+/// Returns the conditional branch with the opposite condition of `branch`.
+fn invert(branch: u32) -> u32 {
+    if branch & 0xff00_0000 == b_cond(0) {
+        // The lowest bit of conditions negates them.
+        branch ^ 1
+    } else {
+        // The bit 24 switches `cbz` and `cbnz`.
+        branch ^ 1 << 24
+    }
+}
+
+/// Writes `branch` to somewhere later in the code, returning the location to [`patch`] once it's known.
+/// As it could be too far for conditional branches, it skips over an unconditional one on the opposite condition.
+fn branch_fwd(writer: &mut Vec<u32>, branch: u32) -> usize {
+    if branch == b_always() {
+        writer.push(branch);
+    } else {
+        writer.push(invert(branch) | 2 << 5);
+        writer.push(b_always());
+    }
+    writer.len() - 1
+}
+
+/// Makes the unconditional branch at `dst` go to the end of `writer`.
+fn patch(writer: &mut [u32], dst: usize) {
+    let offset = writer.len() - dst;
+    assert!(offset < 1 << 25, "the code is too large to branch across");
+    writer[dst] |= offset as u32;
+}
+
+/// Writes `branch` to `label`, which is somewhere earlier in `writer`.
+fn branch_bwd(writer: &mut Vec<u32>, branch: u32, label: usize) {
+    let offset = label as i32 - writer.len() as i32;
+    if branch == b_always() {
+        assert!(
+            offset >= -(1 << 25),
+            "the code is too large to branch across"
+        );
+        writer.push(branch | offset as u32 & 0x3ff_ffff);
+    } else if offset >= -(1 << 18) {
+        writer.push(branch | (offset as u32 & 0x7_ffff) << 5);
+    } else {
+        // Skip over an unconditional branch, which reaches further.
+        writer.push(invert(branch) | 2 << 5);
+        let offset = offset - 1;
+        assert!(
+            offset >= -(1 << 25),
+            "the code is too large to branch across"
+        );
+        writer.push(b_always() | offset as u32 & 0x3ff_ffff);
+    }
+}
@@ -0,0 +1,205 @@
+//! The tape between guard pages for [`Bounds::Guard`], which turns accesses beyond it into faults.
+//!
+//! The machine code keeps the index of the current cell in 32 bits and zero-extends it to access a cell,
+//! so it can only reach 2^32 cells from the start of the tape, where the ones on the left have wrapped around.
+//! The whole range is reserved with no access, and only the pages the tape needs are made readable and writable,
+//! with the tape at the end of them so that the cell after the last one faults.
+//! On a fault, the signal handler checks it comes from the machine code running on this thread and on the tape,
+//! then moves the machine code to its postlude to return 0.
+//!
+//! [`Bounds::Guard`]: crate::Bounds::Guard
+
+use super::Faults;
+use crate::{CellWidth, Error};
+use libc::{c_int, c_void, siginfo_t, ucontext_t};
+use std::{cell::Cell, io, mem, ptr, sync::OnceLock};
+
+/// Where the machine code running on this thread is, for the signal handler to tell its faults from others.
+#[derive(Clone, Copy)]
+struct Running {
+    /// The start and the end of the machine code.
+    code: (usize, usize),
+    /// Where the machine code goes on a fault, which is its postlude.
+    throwing: usize,
+    /// The start and the end of the region reserved for the tape.
+    region: (usize, usize),
+}
+
+thread_local! {
+    static RUNNING: Cell<Option<Running>> = const { Cell::new(None) };
+    /// The offset in the machine code and the address of the last fault.
+    static FAULT: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
+
+/// The actions for `SIGSEGV` and `SIGBUS` before the handler is installed, to pass on the faults not from here.
+static PREVIOUS: OnceLock<[libc::sigaction; 2]> = OnceLock::new();
+
+/// The tape, mapped at the end of the readable and writable pages before the guard pages.
+pub(crate) struct GuardedTape {
+    region: *mut c_void,
+    /// The size of the region in bytes.
+    size: usize,
+    /// The first cell of the tape.
+    cells: *mut u8,
+    cell_width: CellWidth,
+}
+
+impl GuardedTape {
+    /// Maps a tape of `len` cells as wide as `cell_width`, which are all zeros.
+    pub(crate) fn new(len: usize, cell_width: CellWidth) -> io::Result<Self> {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let bytes = len * cell_width.bytes();
+        let accessible = bytes.next_multiple_of(page);
+        // The tape starts less than a page after the region, from which 2^32 cells can be reached.
+        let size = (1 << 32) * cell_width.bytes() + page;
+        // Only reserve the address space, as the pages are never accessed.
+        let region = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if region == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Drop the mapping on errors from here.
+        let tape = Self {
+            region,
+            size,
+            cells: unsafe { region.cast::<u8>().add(accessible - bytes) },
+            cell_width,
+        };
+        if unsafe { libc::mprotect(region, accessible, libc::PROT_READ | libc::PROT_WRITE) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(tape)
+    }
+
+    /// Returns the first cell of the tape for the machine code.
+    pub(crate) fn cells(&self) -> *mut u8 {
+        self.cells
+    }
+
+    /// Calls `execute`, which runs `code` on this tape, catching its faults on the guard pages.
+    /// Returns what `execute` returns, which is 0 on a fault, along with the error for the fault if any.
+    /// `faults` tells where `code` goes on a fault and where in the source the cell accessed is.
+    pub(crate) fn run(
+        &self,
+        code: &[u8],
+        faults: &Faults,
+        execute: impl FnOnce() -> u8,
+    ) -> (u8, Option<Error>) {
+        install();
+        let start = code.as_ptr() as usize;
+        RUNNING.set(Some(Running {
+            code: (start, start + code.len()),
+            throwing: start + faults.throwing,
+            region: (self.region as usize, self.region as usize + self.size),
+        }));
+        FAULT.set(None);
+        let result = execute();
+        RUNNING.set(None);
+
+        let error = FAULT.take().map(|(offset, address)| {
+            // The cells on the left of the tape have wrapped around in 32 bits.
+            let index = (address - self.cells as usize) / self.cell_width.bytes();
+            Error::OutOfBounds {
+                position: faults.position(offset),
+                index: index as u32 as i32 as i64,
+            }
+        });
+        (result, error)
+    }
+}
+
+impl Drop for GuardedTape {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.region, self.size) };
+    }
+}
+
+/// Installs [`handle`] for `SIGSEGV` and `SIGBUS` unless it already is, keeping the previous actions.
+fn install() {
+    PREVIOUS.get_or_init(|| {
+        [libc::SIGSEGV, libc::SIGBUS].map(|signal| unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handle as *const () as usize;
+            // Run on the alternate stack if any, as Rust sets one up to report stack overflows.
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut previous = mem::zeroed();
+            // This only fails with an invalid signal or action.
+            let result = libc::sigaction(signal, &action, &mut previous);
+            assert_eq!(result, 0, "failed to install the signal handler");
+            previous
+        })
+    });
+}
+
+/// Handles `SIGSEGV` and `SIGBUS`, moving the machine code to its postlude if it faults on the guard pages.
+/// Other faults go to the previous action.
+extern "C" fn handle(signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    let context = context.cast::<ucontext_t>();
+    // The kernel passes valid pointers to the handler.
+    let address = unsafe { (*info).si_addr() } as usize;
+    let pc = unsafe { program_counter(context) };
+    if let Some(running) = RUNNING.get() {
+        let in_code = (running.code.0..running.code.1).contains(&pc);
+        if in_code && (running.region.0..running.region.1).contains(&address) {
+            FAULT.set(Some((pc - running.code.0, address)));
+            unsafe { throw(context, running.throwing) };
+            return;
+        }
+    }
+
+    let Some(previous) = PREVIOUS.get() else {
+        return;
+    };
+    let previous = &previous[usize::from(signal == libc::SIGBUS)];
+    match previous.sa_sigaction {
+        // Returning runs the faulting instruction again, which then takes the previous action.
+        libc::SIG_DFL | libc::SIG_IGN => unsafe {
+            libc::sigaction(signal, previous, ptr::null_mut());
+        },
+        action if previous.sa_flags & libc::SA_SIGINFO != 0 => unsafe {
+            let action: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) = mem::transmute(action);
+            action(signal, info, context.cast());
+        },
+        action => unsafe {
+            let action: extern "C" fn(c_int) = mem::transmute(action);
+            action(signal);
+        },
+    }
+}
+
+/// Returns where the machine code faulted.
+#[cfg(target_arch = "x86_64")]
+unsafe fn program_counter(context: *const ucontext_t) -> usize {
+    unsafe { (*context).uc_mcontext.gregs[libc::REG_RIP as usize] as usize }
+}
+
+/// Makes the machine code continue at `throwing` with 0 as the return value.
+#[cfg(target_arch = "x86_64")]
+unsafe fn throw(context: *mut ucontext_t, throwing: usize) {
+    let registers = unsafe { &mut (*context).uc_mcontext.gregs };
+    registers[libc::REG_RIP as usize] = throwing as i64;
+    registers[libc::REG_RAX as usize] = 0;
+}
+
+/// Returns where the machine code faulted.
+#[cfg(target_arch = "aarch64")]
+unsafe fn program_counter(context: *const ucontext_t) -> usize {
+    unsafe { (*context).uc_mcontext.pc as usize }
+}
+
+/// Makes the machine code continue at `throwing` with 0 as the return value.
+#[cfg(target_arch = "aarch64")]
+unsafe fn throw(context: *mut ucontext_t, throwing: usize) {
+    let registers = unsafe { &mut (*context).uc_mcontext };
+    registers.pc = throwing as u64;
+    registers.regs[0] = 0;
+}
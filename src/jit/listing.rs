@@ -0,0 +1,118 @@
+//! Annotated listings of the code the dynasm based JIT generates, for `--emit asm`.
+//!
+//! `dynasm!` assembles instructions when this project is compiled, so there's no text of them at runtime.
+//! Instead, the listing keeps the source of each `dynasm!` invocation as it is written,
+//! along with the part of the Brainf*ck programme it is for and the bytes it is assembled into.
+//! The operands are the Rust expressions in the source rather than their values, so it is not a disassembly;
+//! `--emit bin` gives the machine code for a disassembler.
+
+use super::Faults;
+use crate::ir::{Node, NodeKind};
+use dynasmrt::DynasmApi;
+use std::fmt::Write;
+
+/// An assembler, along with the listing of what it assembles if one is wanted
+/// and where it accesses cells between guard pages.
+pub struct Ops<'a, A> {
+    pub asm: A,
+    pub listing: Option<&'a mut Listing>,
+    pub faults: Faults,
+    /// Whether to call `trace` at the conditions of loops and at the end.
+    pub trace: bool,
+}
+
+impl<A: DynasmApi> Ops<'_, A> {
+    /// Starts a new block in the listing, which is for what `note` returns.
+    pub fn block(&mut self, note: impl FnOnce() -> String) {
+        if let Some(listing) = &mut self.listing {
+            listing.blocks.push(Block {
+                note: note(),
+                start: self.asm.offset().0,
+                lines: Vec::new(),
+            });
+        }
+    }
+
+    /// Starts a new block in the listing for `nodes`, which are lowered together.
+    /// A loop only has the start of it in the block as the body follows in the blocks of its own.
+    pub fn nodes(&mut self, nodes: &[Node]) {
+        if let [node @ Node {
+            kind: NodeKind::Loop { .. },
+            ..
+        }] = nodes
+        {
+            self.block(|| format!("{}..{}: [", node.span.start, node.span.start + 1));
+            return;
+        }
+        self.block(|| {
+            let start = nodes.iter().map(|node| node.span.start).min().unwrap_or(0);
+            let end = nodes.iter().map(|node| node.span.end).max().unwrap_or(0);
+            let kinds: Vec<_> = nodes
+                .iter()
+                .map(|node| format!("{:?}", node.kind))
+                .collect();
+            format!("{start}..{end}: {}", kinds.join(", "))
+        });
+    }
+
+    /// Starts a new block in the listing for the end of the loop `node`.
+    pub fn loop_end(&mut self, node: &Node) {
+        self.block(|| format!("{}..{}: ]", node.span.end - 1, node.span.end));
+    }
+}
+
+/// Instructions in a row generated for the same thing.
+struct Block {
+    /// What the instructions are for.
+    note: String,
+    /// The offset of the first instruction in the code.
+    start: usize,
+    /// The instructions as written in `dynasm!`, one per line.
+    lines: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct Listing {
+    blocks: Vec<Block>,
+}
+
+impl Listing {
+    /// Adds the instructions in `source`, which is what `stringify!` makes of the tokens given to `dynasm!`.
+    pub fn push(&mut self, source: &str) {
+        let Some(block) = self.blocks.last_mut() else {
+            return;
+        };
+        let lines = source.split(';').filter(|line| !line.trim().is_empty());
+        // `stringify!` puts spaces, or even line breaks, between tokens. Tidy them up.
+        block.lines.extend(lines.map(|line| {
+            let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+            line.replace("=> ", "=>")
+                .replace("-> ", "->")
+                .replace(" :", ":")
+                .replace(". ", ".")
+        }));
+    }
+
+    /// Writes out the listing, where `code` is what the instructions are assembled into.
+    pub fn render(&self, code: &[u8]) -> String {
+        let mut text = format!("; {} code from the asm engine\n", std::env::consts::ARCH);
+        for (i, block) in self.blocks.iter().enumerate() {
+            let end = self.blocks.get(i + 1).map_or(code.len(), |next| next.start);
+            // `fmt::Write` for `String` never fails.
+            let _ = writeln!(text, "\n; {}", block.note);
+            for line in &block.lines {
+                let _ = writeln!(text, "        {line}");
+            }
+            for (row, bytes) in code[block.start..end].chunks(16).enumerate() {
+                let bytes: Vec<_> = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+                let _ = writeln!(
+                    text,
+                    "{:04x}:   {}",
+                    block.start + row * 16,
+                    bytes.join(" ")
+                );
+            }
+        }
+        text
+    }
+}
@@ -1,3 +1,7 @@
+//! JIT compilers, which generate machine code for the processor this runs on and call into it.
+//!
+//! [`machine`] writes the machine code by hand, while [`asm`] uses `dynasm-rs`.
+
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
 
@@ -10,51 +14,332 @@ mod aarch64;
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::*;
 
-use crate::Error;
-use std::{io, mem};
+#[cfg(feature = "asm")]
+mod listing;
+
+#[cfg(target_os = "linux")]
+mod guard;
+
+#[cfg(target_arch = "aarch64")]
+use crate::Cell;
+use crate::{
+    trace::{self, Op, Record},
+    Bounds, Budget, CancelHandle, CellWidth, Diagnostics, EofMode, Error, Options,
+};
+use std::{
+    io::{self, Read, Write},
+    mem,
+    panic::AssertUnwindSafe,
+    ptr, slice,
+};
+
+/// What the machine code passes to [`putchar`], [`getchar`], [`bounds`], [`limit`], [`dump`] and [`trace`] as it is,
+/// so that each run has its own io and tape.
+pub(crate) struct Context<'a> {
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    /// Where [`trace`] writes the records, if the machine code is compiled with calls to it.
+    trace: Option<&'a mut trace::Writer<dyn Write + 'a>>,
+    eof: EofMode,
+    cell_width: CellWidth,
+    bounds: Bounds,
+    diagnostics: Diagnostics,
+    /// The tape, made of `u32` so that it is aligned for any cells.
+    /// It is only owned here so that [`bounds`] can grow it, and the machine code keeps its own pointer to it.
+    tape: Vec<u32>,
+    /// The number of cells on the tape, including the one between guard pages.
+    len: usize,
+    /// The steps left for [`limit`] to hand out.
+    budget: Budget,
+    /// The error which ended the programme.
+    error: Option<Error>,
+}
+
+impl Context<'_> {
+    /// Returns 1 if `result` is `Ok`, or keeps the error and returns 0 for the machine code.
+    fn check(&mut self, result: io::Result<()>) -> u8 {
+        match result {
+            Ok(()) => 1,
+            Err(e) => {
+                self.error = Some(e.into());
+                0
+            }
+        }
+    }
+
+    /// Resizes the tape to `len` cells, filling new ones with zeros.
+    fn resize(&mut self, len: usize) {
+        let bytes = len * self.cell_width.bytes();
+        self.tape.resize(bytes.div_ceil(4), 0);
+        self.len = len;
+    }
+}
+
+/// Where the tape is and how many cells it has, returned from [`bounds`] in two registers.
+#[repr(C)]
+pub(crate) struct Tape {
+    cells: *mut u8,
+    len: usize,
+}
+
+impl Tape {
+    /// What [`bounds`] returns on errors.
+    const NULL: Self = Self {
+        cells: ptr::null_mut(),
+        len: 0,
+    };
+}
+
+/// Where the machine code accesses cells between guard pages, to tell where in the source a fault comes from.
+#[derive(Default)]
+pub(crate) struct Faults {
+    /// The offset of the postlude, where the machine code goes on a fault.
+    pub(crate) throwing: usize,
+    /// The offsets of the instructions calculating the index of each cell accessed,
+    /// along with where in the source the cell is accessed, in the order of the offsets.
+    pub(crate) accesses: Vec<(usize, usize)>,
+}
+
+impl Faults {
+    /// Returns where in the source the cell accessed by the instruction at `offset` is accessed,
+    /// which is the last access calculated before it.
+    #[cfg(target_os = "linux")]
+    fn position(&self, offset: usize) -> usize {
+        let accesses = self.accesses.partition_point(|&(start, _)| start <= offset);
+        accesses
+            .checked_sub(1)
+            .map_or(0, |access| self.accesses[access].1)
+    }
+}
+
+/// Returns whether the tape is between guard pages instead of checking the index on each access.
+/// Only Linux has the signal handler to recover from faults on them.
+pub(crate) fn guarded(options: &Options) -> bool {
+    cfg!(target_os = "linux") && options.bounds == Bounds::Guard
+}
 
 /// A wrapper around [`crate::putchar`] to for the JIT to call.
-/// Writes the value pointed by `byte` into the output.
+/// Writes the value pointed by `byte` into the output of `context`.
+/// For cells wider than a byte, `byte` points to the lowest 8 bits of the cell as they are little endian.
 /// ## Error
-/// This returns 0 if the output failed and stores the details in `errorno`.
+/// This returns 0 if the output failed and stores the details in `context`.
 /// ## Safety
-/// The caller must ensure `byte` is safe to dereference.
-pub unsafe extern "C" fn putchar(byte: *const u8) -> u8 {
+/// The caller must ensure both `context` and `byte` are safe to dereference.
+pub(crate) unsafe extern "C" fn putchar(context: *mut Context, byte: *const u8) -> u8 {
     // Catch panicking as it is UB to unwind from Rust into a foreign language.
     // "sysv64-unwind" may be a better alternative.
     // https://github.com/rust-lang/rust/issues/74990
-    std::panic::catch_unwind(||
-        // It is the caller's responsibility to ensure `byte` is a valid pointer.
-        crate::putchar(unsafe { &*byte }).is_ok() as u8)
+    // The context is left as it is after panicking, as the programme ends right away.
+    std::panic::catch_unwind(AssertUnwindSafe(|| {
+        // It is the caller's responsibility to ensure both are valid pointers.
+        let context = unsafe { &mut *context };
+        let result = crate::putchar(context.output, unsafe { &*byte });
+        context.check(result)
+    }))
     .unwrap_or(0) // The caller cannot know why this panicked, but it's unlikely to happen anyway.
 }
 
 /// A wrapper around [`crate::getchar`] to for the JIT to call.
-/// Reads a value from the input into the memory pointed by `byte`.
+/// Flushes the output of `context` and reads a value from its input into the cell pointed by `cell`.
 /// ## Error
-/// This returns 0 if the output failed and stores the details in `errorno`.
+/// This returns 0 if the input failed and stores the details in `context`.
 /// ## Safety
-/// The caller must ensure `byte` is safe to dereference.
-pub unsafe extern "C" fn getchar(byte: *mut u8) -> u8 {
+/// The caller must ensure both `context` and `cell` are safe to dereference,
+/// and that `cell` is aligned to the cell width of `context`.
+pub(crate) unsafe extern "C" fn getchar(context: *mut Context, cell: *mut u8) -> u8 {
     // Catch panicking as it is UB to unwind from Rust into a foreign language.
     // "sysv64-unwind" may be a better alternative.
     // https://github.com/rust-lang/rust/issues/74990
-    std::panic::catch_unwind(||
-        // It is the caller's responsibility to ensure `byte` is a valid pointer.
-        crate::getchar(unsafe { &mut *byte }).is_ok() as u8)
+    // The context is left as it is after panicking, as the programme ends right away.
+    std::panic::catch_unwind(AssertUnwindSafe(|| {
+        // It is the caller's responsibility to ensure both are valid pointers.
+        let context = unsafe { &mut *context };
+        let (input, output, eof) = (&mut *context.input, &mut *context.output, context.eof);
+        let result = match context.cell_width {
+            CellWidth::U8 => crate::getchar(input, output, eof, unsafe { &mut *cell }),
+            CellWidth::U16 => {
+                crate::getchar(input, output, eof, unsafe { &mut *cell.cast::<u16>() })
+            }
+            CellWidth::U32 => {
+                crate::getchar(input, output, eof, unsafe { &mut *cell.cast::<u32>() })
+            }
+        };
+        context.check(result)
+    }))
     .unwrap_or(0) // The caller cannot know why this panicked, but it's unlikely to happen anyway.
 }
 
-fn run_opcode(opcode: &[u8]) -> Result<(), Error> {
-    // Safety: it must be safe to access the given pointer up to it plus 2^16.
-    let execute: unsafe extern "C" fn(*mut u8) -> u8 =
+/// Called by the JIT when the cell `index` is beyond either end of the tape, accessed at `position` in the source.
+/// Grows the tape of `context` with [`Bounds::Grow`], returning where the tape is and how long it is now.
+/// The index is signed so that it is negative on the left of the tape, as the machine code keeps it in 32 bits.
+/// ## Error
+/// This returns a null pointer if the cell is out of bounds after all and stores the details in `context`.
+/// ## Safety
+/// The caller must ensure `context` is safe to dereference, and must not use the old pointer to the tape any longer.
+pub(crate) unsafe extern "C" fn bounds(context: *mut Context, index: i32, position: u32) -> Tape {
+    // Catch panicking as it is UB to unwind from Rust into a foreign language.
+    // The context is left as it is after panicking, as the programme ends right away.
+    std::panic::catch_unwind(AssertUnwindSafe(|| {
+        // It is the caller's responsibility to ensure this is a valid pointer.
+        let context = unsafe { &mut *context };
+        let index = index as isize as usize;
+        match context.bounds.reach(context.len, index, position as usize) {
+            Ok(len) => {
+                context.resize(len);
+                Tape {
+                    cells: context.tape.as_mut_ptr().cast(),
+                    len,
+                }
+            }
+            Err(e) => {
+                context.error = Some(e);
+                Tape::NULL
+            }
+        }
+    }))
+    .unwrap_or(Tape::NULL)
+}
+
+/// A wrapper around [`crate::dump`] for the JIT to call for `#` at `position` in the source.
+/// Shows the cells around the cell `index` on the tape at `cells`, where the index is signed the same as in [`bounds`].
+/// ## Error
+/// This returns 0 if flushing the output or writing into [`Options::diagnostics`] failed and stores the details in `context`.
+/// ## Safety
+/// The caller must ensure `context` is safe to dereference, and that it is safe to access `cells`
+/// up to the number of cells in `context`, aligned to the cell width.
+pub(crate) unsafe extern "C" fn dump(
+    context: *mut Context,
+    cells: *const u8,
+    index: i32,
+    position: u32,
+) -> u8 {
+    // Catch panicking as it is UB to unwind from Rust into a foreign language.
+    // The context is left as it is after panicking, as the programme ends right away.
+    std::panic::catch_unwind(AssertUnwindSafe(|| {
+        // It is the caller's responsibility to ensure both are valid pointers.
+        let context = unsafe { &mut *context };
+        let (output, diagnostics, len) = (&mut *context.output, &context.diagnostics, context.len);
+        let (pointer, position) = (index as isize as usize, position as usize);
+        let result = match context.cell_width {
+            CellWidth::U8 => {
+                let cells = unsafe { slice::from_raw_parts(cells, len) };
+                crate::dump(output, diagnostics, cells, pointer, position)
+            }
+            CellWidth::U16 => {
+                let cells = unsafe { slice::from_raw_parts(cells.cast::<u16>(), len) };
+                crate::dump(output, diagnostics, cells, pointer, position)
+            }
+            CellWidth::U32 => {
+                let cells = unsafe { slice::from_raw_parts(cells.cast::<u32>(), len) };
+                crate::dump(output, diagnostics, cells, pointer, position)
+            }
+        };
+        context.check(result)
+    }))
+    .unwrap_or(0)
+}
+
+/// Called by the JIT compiled with tracing at the conditions of loops and at the end, which are at `position` in the source.
+/// Writes the record of `op` with the cell `index` on the tape at `cells` into the trace of `context`,
+/// where the index is signed the same as in [`bounds`].
+/// ## Error
+/// This returns 0 if writing the trace failed and stores the details in `context`.
+/// ## Safety
+/// The caller must ensure `context` is safe to dereference, and that it is safe to access `cells`
+/// up to the number of cells in `context`, aligned to the cell width.
+pub(crate) unsafe extern "C" fn trace(
+    context: *mut Context,
+    cells: *const u8,
+    index: i32,
+    position: u32,
+    op: u8,
+) -> u8 {
+    // Catch panicking as it is UB to unwind from Rust into a foreign language.
+    // The context is left as it is after panicking, as the programme ends right away.
+    std::panic::catch_unwind(AssertUnwindSafe(|| {
+        // It is the caller's responsibility to ensure both are valid pointers.
+        let context = unsafe { &mut *context };
+        let cell = usize::try_from(index)
+            .ok()
+            .filter(|&index| index < context.len)
+            .map(|index| unsafe {
+                match context.cell_width {
+                    CellWidth::U8 => u32::from(*cells.add(index)),
+                    CellWidth::U16 => u32::from(*cells.cast::<u16>().add(index)),
+                    CellWidth::U32 => *cells.cast::<u32>().add(index),
+                }
+            });
+        let record = Record {
+            position,
+            op: Op::from_u8(op).expect("the machine code passes a known op"),
+            pointer: index,
+            cell,
+        };
+        let result = match &mut context.trace {
+            Some(trace) => trace.write(&record),
+            None => Ok(()),
+        };
+        context.check(result)
+    }))
+    .unwrap_or(0)
+}
+
+/// Called by the JIT when it has run all the steps handed out, counting the iterations of loops
+/// for [`Options::max_steps`] and [`Options::timeout`]. Returns the number of steps to run before calling this again.
+/// ## Error
+/// This returns 0 if the programme is beyond either limit and stores the details in `context`.
+/// ## Safety
+/// The caller must ensure `context` is safe to dereference.
+pub(crate) unsafe extern "C" fn limit(context: *mut Context) -> u64 {
+    // Catch panicking as it is UB to unwind from Rust into a foreign language.
+    // The context is left as it is after panicking, as the programme ends right away.
+    std::panic::catch_unwind(AssertUnwindSafe(|| {
+        // It is the caller's responsibility to ensure this is a valid pointer.
+        let context = unsafe { &mut *context };
+        context.budget.next().unwrap_or_else(|e| {
+            context.error = Some(e);
+            0
+        })
+    }))
+    .unwrap_or(0)
+}
+
+/// A wrapper around [`crate::scan`] for the JIT to call, with cells of the type `C`.
+/// Returns the index of the zero cell found from `pointer` in `tape`, or `u32::MAX` if there isn't one.
+/// ## Safety
+/// The caller must ensure it is safe to access `tape` up to `len` cells, which must be a power of two.
+#[cfg(target_arch = "aarch64")]
+pub(crate) unsafe extern "C" fn scan<C: Cell>(
+    tape: *const C,
+    len: u32,
+    pointer: u32,
+    stride: i32,
+) -> u32 {
+    // It is the caller's responsibility to ensure `tape` is a valid pointer.
+    let tape = unsafe { std::slice::from_raw_parts(tape, len as usize) };
+    crate::scan(tape, pointer as usize, stride).map_or(u32::MAX, |found| found as u32)
+}
+
+/// Runs `opcode` with a new tape and `options`, reading from `input` and writing into `output`.
+/// `faults` is where `opcode` accesses cells, which is only used with guard pages.
+/// `trace` is where the records go if `opcode` is compiled with calls to [`trace`].
+fn run_opcode(
+    opcode: &[u8],
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))] faults: &Faults,
+    options: &Options,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    trace: Option<&mut trace::Writer<dyn Write + '_>>,
+) -> Result<(), Error> {
+    // Safety: it must be safe to access the tape up to `options.tape_size` cells, and the context must be valid.
+    let execute: unsafe extern "C" fn(*mut u8, *mut Context) -> u8 =
         // The safety of this block depends on the correctness of the compilers. How dangerous.
         unsafe { mem::transmute(opcode.as_ptr()) };
 
-    // Create an array of bytes with the size of `u16::MAX + 1`
-    // This way, the Brainf*ck programme can only access regions inside the array.
-    // That's provided that the value used to index the array is 16 bit.
-    // There's a better way that wastes no memory, but it is too hard to do correctly.
+    // With `Bounds::Wrap`, the Brainf*ck programme can only access regions inside the tape,
+    // as the index is masked with the size of the tape, which is a power of two.
+    // With `Bounds::Error` or `Bounds::Grow`, every access compares the index with the size
+    // and calls `bounds` if it's beyond.
     //
     // Address masking seems good but I have to control virtual address to give Brainf*ck code.
     // https://www.cse.psu.edu/~gxt29/papers/sfi-final.pdf
@@ -62,15 +347,50 @@ fn run_opcode(opcode: &[u8]) -> Result<(), Error> {
     //
     // According to the documentation, Wasmtime has both guard page and bound checking.
     // Wasmtime puts enough guard pages so that 32-bit wasm cannot access outside of it.
-    // This is super hard because I had to catch SIGSEGV or SIGBUS from guarded pages and recover from it.
     // https://github.com/bytecodealliance/wasmtime/issues/15
-    let mut array = vec![0u8; u16::MAX as usize + 1].into_boxed_slice();
-    debug_assert!(array.get(u16::MAX as usize).is_some());
-    let result = unsafe { execute(array.as_mut_ptr()) };
-
-    if result == 0 {
-        Err(io::Error::last_os_error().into())
-    } else {
-        Ok(())
+    // `Bounds::Guard` does the same on Linux, as the index is 32 bits here as well.
+    // The signal handler in `guard.rs` recovers from SIGSEGV or SIGBUS on the guard pages.
+    let mut context = Context {
+        input,
+        output,
+        // Shorten the lifetime of the writer to the one of the context.
+        trace: trace.map(|trace| trace as _),
+        eof: options.eof,
+        cell_width: options.cell_width,
+        bounds: options.bounds,
+        diagnostics: options.diagnostics.clone(),
+        tape: Vec::new(),
+        len: 0,
+        budget: Budget::new(options),
+        error: None,
+    };
+
+    #[cfg(target_os = "linux")]
+    if guarded(options) {
+        let tape = guard::GuardedTape::new(options.tape_size, options.cell_width)?;
+        context.len = options.tape_size;
+        let cells = tape.cells();
+        let (result, fault) = tape.run(opcode, faults, || unsafe { execute(cells, &mut context) });
+        return finish(result, context.error.or(fault), options);
+    }
+    context.resize(options.tape_size);
+    let tape = context.tape.as_mut_ptr().cast();
+    let result = unsafe { execute(tape, &mut context) };
+    finish(result, context.error, options)
+}
+
+/// Returns the result of a programme, where `result` is what the machine code returns and `error` is what ended it.
+fn finish(result: u8, error: Option<Error>, options: &Options) -> Result<(), Error> {
+    let cancelled = options
+        .cancel
+        .as_ref()
+        .is_some_and(CancelHandle::is_cancelled);
+    match error {
+        Some(e) if result == 0 => Err(e),
+        // The machine code returns 0 by itself when it finds the programme cancelled.
+        None if result == 0 && cancelled => Err(Error::Cancelled),
+        // Otherwise, only a panic in the functions called returns 0 without an error.
+        None if result == 0 => Err(io::Error::other("panicked during io").into()),
+        _ => Ok(()),
     }
 }
@@ -1,110 +1,623 @@
 use crate::{
-    jit::{getchar, putchar, run_opcode},
-    Consumer as _, Error,
+    ir::{Node, NodeKind},
+    jit::{
+        bounds, dump, getchar, guarded, limit,
+        listing::{Listing, Ops},
+        putchar, run_opcode, trace, Faults,
+    },
+    trace::Op,
+    Bounds, CellWidth, Error, Options,
 };
 use dynasm::dynasm;
-use dynasmrt::{DynasmApi, DynasmLabelApi, ExecutableBuffer};
+use dynasmrt::{x64::Assembler, DynasmApi, DynasmLabelApi, ExecutableBuffer};
+use std::io::{Read, Write};
 
 macro_rules! my_dynasm {
-    ($ops:ident $($t:tt)*) => {
-        dynasm!($ops
+    ($ops:ident $($t:tt)*) => {{
+        if let Some(listing) = &mut $ops.listing {
+            listing.push(stringify!($($t)*));
+        }
+        dynasm!($ops.asm
             ; .arch x64
             ; .alias ptr, rbx
             ; .alias idx, r12d
             ; .alias idxq, r12
             ; .alias idxw, r12w
+            ; .alias ctx, r13
+            ; .alias size, r14d
+            ; .alias sizeq, r14
+            ; .alias budget, r15
             $($t)*
         )
-    }
+    }}
 }
 
-fn compile(program: &[u8]) -> Result<ExecutableBuffer, Error> {
-    let mut ops = dynasmrt::x64::Assembler::new()?;
+/// Generates machine code for `program` running with `options`,
+/// writing down what is generated into `listing` if any. With `trace`, it calls [`trace`] at the conditions of loops
+/// and at the end. Returns the machine code along with where it accesses cells between guard pages.
+fn compile(
+    program: &[Node],
+    options: &Options,
+    listing: Option<&mut Listing>,
+    trace: bool,
+) -> Result<(ExecutableBuffer, Faults), Error> {
+    let mut ops = Ops {
+        asm: Assembler::new()?,
+        listing,
+        faults: Faults::default(),
+        trace,
+    };
 
+    ops.block(|| "prelude".to_string());
     my_dynasm!(ops
         ; push rbp
         ; mov rbp, rsp
 
         ; push ptr
         ; push idxq
+        ; push ctx
+        ; push sizeq // This also keeps the stack aligned to 16 bytes for calls
         ; mov ptr, rdi
+        ; mov ctx, rsi // Keep the context to pass it to `putchar`, `getchar` and `bounds`
+        ; mov size, options.tape_size as i32 // Keep the number of cells, which `bounds` can change
         ; xor idx, idx // Set the array index to 0
         ; mov eax, 1 // Set the initial return value to 1 in case no io happens.
     );
+    if options.limited() {
+        my_dynasm!(ops
+            ; push budget
+            ; sub rsp, 8 // Keep the stack aligned
+            ; xor budget, budget // Call `limit` for the first steps at the first iteration
+        );
+    }
 
-    let mut loops = Vec::new();
-
-    let mut iter = program.iter();
-    while let Some(&c) = iter.next() {
-        match c {
-            b'>' => my_dynasm!(ops
-                ; add idx, (iter.consume_while(b'>') + 1) as _
-                // Make sure the index stays within 16 bit values for memory protection.
-                // Use zero-extension instead of writing directly to 16 bit register
-                // See https://stackoverflow.com/questions/34058101/referencing-the-contents-of-a-memory-location-x86-addressing-modes
-                ; movzx idx, idxw
-            ),
-            b'<' => my_dynasm!(ops
-                ; sub idx, (iter.consume_while(b'<') + 1) as _
-                ; movzx idx, idxw
+    lower(&mut ops, program, options);
+    if ops.trace {
+        ops.block(|| "end".to_string());
+        trace_point(
+            &mut ops,
+            program.last().map_or(0, |node| node.span.end),
+            Op::End,
+        );
+    }
+
+    ops.block(|| "postlude".to_string());
+    ops.faults.throwing = ops.asm.offset().0;
+    my_dynasm!(ops
+        // Keep `rax` set by the functions called as it is for the return value.
+        ;->throwing:
+    );
+    if options.limited() {
+        my_dynasm!(ops
+            ; add rsp, 8
+            ; pop budget
+        );
+    }
+    my_dynasm!(ops
+        ; pop sizeq
+        ; pop ctx
+        ; pop idxq
+        ; pop ptr
+
+        ; mov rsp, rbp
+        ; pop rbp
+        ; ret
+    );
+
+    let code = ops
+        .asm
+        .finalize()
+        .expect("Finalising the exec buffer failed");
+    Ok((code, ops.faults))
+}
+
+fn lower(ops: &mut Ops<Assembler>, nodes: &[Node], options: &Options) {
+    let cell = options.cell_width;
+    // The tape has as many cells as a power of two to wrap around, so the mask is one less.
+    let mask = (options.tape_size - 1) as i32;
+    // Changes to cells in a row are lowered together.
+    for nodes in nodes.chunk_by(|a, b| a.cell_offset().is_some() && b.cell_offset().is_some()) {
+        ops.nodes(nodes);
+        let position = nodes[0].span.start;
+        match nodes[0].kind {
+            NodeKind::Move { amount } if options.bounds == Bounds::Wrap => my_dynasm!(ops
+                // A negative amount moves the pointer to the left.
+                ; add idx, amount
+                // Make sure the index stays within the tape for memory protection.
+                ; and idx, mask
             ),
-            b'+' => my_dynasm!(ops; add BYTE [ptr + idxq], (iter.consume_while(b'+') + 1) as _),
-            b'-' => my_dynasm!(ops; sub BYTE [ptr + idxq], (iter.consume_while(b'-') + 1) as _),
-            b'.' => my_dynasm!(ops
-                ; lea rdi, [ptr + idxq]
-                ; mov rax, QWORD putchar as _
-                ; call rax
-                ; cmp eax, 0
-                ; jz ->throwing
+            // The index is only checked when a cell is accessed.
+            NodeKind::Move { amount } => my_dynasm!(ops
+                ; add idx, amount
             ),
-            b',' => my_dynasm!(ops
-                ; lea rdi, [ptr + idxq]
-                ; mov rax, QWORD getchar as _
+            NodeKind::Add { .. } | NodeKind::Set { .. } => lower_cells(ops, nodes, options),
+            NodeKind::Scan { stride } if options.bounds != Bounds::Wrap => {
+                // Check every cell on the way, which is where it can go out of the tape.
+                let start_label = ops.asm.new_dynamic_label();
+                let end_label = ops.asm.new_dynamic_label();
+                my_dynasm!(ops
+                    ;=>start_label
+                );
+                back_edge(ops, options);
+                current_cell(ops, options, position);
+                compare_zero(ops, options);
+                my_dynasm!(ops
+                    ; jz =>end_label
+                    ; add idx, stride
+                    ; jmp =>start_label
+                    ;=>end_label
+                );
+            }
+            NodeKind::Scan { stride } => {
+                let start_label = ops.asm.new_dynamic_label();
+                let found_label = ops.asm.new_dynamic_label();
+                let end_label = ops.asm.new_dynamic_label();
+                compare_zero(ops, options);
+                my_dynasm!(ops
+                    ; jz =>end_label
+                    ;=>start_label
+                );
+                back_edge(ops, options);
+                match stride {
+                    // `repne scasb` searches `rcx` bytes from `rdi` for `al`, leaving `rdi` next to the byte found.
+                    // When it is not found, search again from the other end of the tape for the rest.
+                    // Not finding anything at all means the programme loops forever, so go back to the start.
+                    // It only compares bytes, so wider cells take the loop below.
+                    1 if cell == CellWidth::U8 => my_dynasm!(ops
+                        ; lea rdi, [ptr + idxq]
+                        ; mov ecx, options.tape_size as i32
+                        ; sub ecx, idx
+                        ; xor eax, eax
+                        ; repne scasb
+                        ; jz =>found_label
+                        ; mov rdi, ptr
+                        // `mov` doesn't touch the flags, which `repne scasb` keeps as they are when `ecx` is 0.
+                        ; mov ecx, idx
+                        ; repne scasb
+                        ; jnz =>start_label
+                        ;=>found_label
+                        ; sub rdi, ptr
+                        ; lea idx, [rdi - 1]
+                        ; mov eax, 1 // Restore the return value
+                    ),
+                    // The direction flag makes `repne scasb` go backwards.
+                    // Clear it as soon as possible as it must be cleared when calling or returning.
+                    -1 if cell == CellWidth::U8 => my_dynasm!(ops
+                        ; lea rdi, [ptr + idxq]
+                        ; lea ecx, [idxq + 1]
+                        // Calculate the number of cells after the current one for the second search,
+                        // as it must not touch the flags there.
+                        ; mov edx, mask
+                        ; sub edx, idx
+                        ; xor eax, eax
+                        ; std
+                        ; repne scasb
+                        ; cld
+                        ; jz =>found_label
+                        ; lea rdi, [ptr + mask]
+                        ; mov ecx, edx
+                        ; std
+                        ; repne scasb
+                        ; cld
+                        ; jnz =>start_label
+                        ;=>found_label
+                        ; sub rdi, ptr
+                        ; lea idx, [rdi + 1]
+                        ; mov eax, 1 // Restore the return value
+                    ),
+                    _ => {
+                        my_dynasm!(ops
+                            ; add idx, stride
+                            ; and idx, mask
+                        );
+                        compare_zero(ops, options);
+                        my_dynasm!(ops
+                            ; jnz =>start_label
+                        );
+                    }
+                }
+                my_dynasm!(ops
+                    ;=>end_label
+                );
+            }
+            NodeKind::MulAdd { offset, factor } if options.bounds != Bounds::Wrap => {
+                // Only access the other cell if the loop this comes from runs, as it can be out of the tape.
+                let end_label = ops.asm.new_dynamic_label();
+                current_cell(ops, options, position);
+                compare_zero(ops, options);
+                my_dynasm!(ops
+                    ; jz =>end_label
+                );
+                checked_index(ops, options, offset, position);
+                // The current cell is on the tape as it is checked above.
+                match cell {
+                    CellWidth::U8 => my_dynasm!(ops; movzx edx, BYTE [ptr + idxq]),
+                    CellWidth::U16 => my_dynasm!(ops; movzx edx, WORD [ptr + idxq * 2]),
+                    CellWidth::U32 => my_dynasm!(ops; mov edx, DWORD [ptr + idxq * 4]),
+                }
+                my_dynasm!(ops
+                    ; imul edx, edx, factor
+                );
+                match cell {
+                    CellWidth::U8 => my_dynasm!(ops; add BYTE [ptr + rcx], dl),
+                    CellWidth::U16 => my_dynasm!(ops; add WORD [ptr + rcx * 2], dx),
+                    CellWidth::U32 => my_dynasm!(ops; add DWORD [ptr + rcx * 4], edx),
+                }
+                my_dynasm!(ops
+                    ;=>end_label
+                );
+            }
+            NodeKind::MulAdd { offset, factor } => {
+                // Don't touch `eax` as it keeps the return value.
+                match cell {
+                    CellWidth::U8 => my_dynasm!(ops; movzx ecx, BYTE [ptr + idxq]),
+                    CellWidth::U16 => my_dynasm!(ops; movzx ecx, WORD [ptr + idxq * 2]),
+                    CellWidth::U32 => my_dynasm!(ops; mov ecx, DWORD [ptr + idxq * 4]),
+                }
+                my_dynasm!(ops
+                    ; imul ecx, ecx, factor
+                    ; lea edx, [idxq + offset]
+                    ; and edx, mask
+                );
+                match cell {
+                    CellWidth::U8 => my_dynasm!(ops; add BYTE [ptr + rdx], cl),
+                    CellWidth::U16 => my_dynasm!(ops; add WORD [ptr + rdx * 2], cx),
+                    CellWidth::U32 => my_dynasm!(ops; add DWORD [ptr + rdx * 4], ecx),
+                }
+            }
+            NodeKind::Output => {
+                current_cell(ops, options, position);
+                probe(ops, options);
+                my_dynasm!(ops
+                    ; mov rdi, ctx
+                );
+                // `putchar` takes the lowest byte, which comes first as x86_64 is little endian.
+                cell_address(ops, options);
+                my_dynasm!(ops
+                    ; mov rax, QWORD putchar as *const () as _
+                    ; call rax
+                    ; cmp eax, 0
+                    ; jz ->throwing
+                );
+            }
+            NodeKind::Input => {
+                current_cell(ops, options, position);
+                probe(ops, options);
+                my_dynasm!(ops
+                    ; mov rdi, ctx
+                );
+                cell_address(ops, options);
+                my_dynasm!(ops
+                    ; mov rax, QWORD getchar as *const () as _
+                    ; call rax
+                    ; cmp eax, 0
+                    ; jz ->throwing
+                );
+            }
+            // The index isn't checked, as `dump` shows where it is even if it's off the tape.
+            NodeKind::Dump => my_dynasm!(ops
+                ; mov rdi, ctx
+                ; mov rsi, ptr
+                ; mov edx, idx
+                ; mov ecx, position as i32
+                ; mov rax, QWORD dump as *const () as _
                 ; call rax
                 ; cmp eax, 0
                 ; jz ->throwing
             ),
-            b'[' => {
-                let bwd_label = ops.new_dynamic_label();
-                let fwd_label = ops.new_dynamic_label();
-                loops.push((bwd_label, fwd_label));
+            NodeKind::Loop { ref body } => {
+                let start_label = ops.asm.new_dynamic_label();
+                let bwd_label = ops.asm.new_dynamic_label();
+                let fwd_label = ops.asm.new_dynamic_label();
+                my_dynasm!(ops
+                    ;=>start_label
+                );
+                trace_point(ops, position, Op::LoopStart);
+                current_cell(ops, options, position);
+                compare_zero(ops, options);
                 my_dynasm!(ops
-                    ; cmp BYTE [ptr + idxq], 0
                     ; jz =>fwd_label
                     ;=>bwd_label
-                )
-            }
-            b']' => {
-                let (bwd_label, fwd_label) = loops.pop().ok_or(Error::UnmatchedRight)?;
+                );
+                lower(ops, body, options);
+                ops.loop_end(&nodes[0]);
+                trace_point(ops, nodes[0].span.end - 1, Op::LoopEnd);
+                back_edge(ops, options);
+                current_cell(ops, options, nodes[0].span.end - 1);
+                compare_zero(ops, options);
+                // Traced, it goes back to check the cell at `[` again as the interpreter does, so that their traces match.
+                let bwd_label = if ops.trace { start_label } else { bwd_label };
                 my_dynasm!(ops
-                    ; cmp BYTE [ptr + idxq], 0
                     ; jnz =>bwd_label
                     ;=>fwd_label
-                )
+                );
+            }
+        }
+    }
+}
+
+pub fn run(
+    program: &[Node],
+    options: &Options,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> Result<(), Error> {
+    run_traced(program, options, input, output, None)
+}
+
+/// Runs `program` the same as [`run`], compiled with calls to write the records of the conditions of loops
+/// and the end into `trace` if any.
+pub(crate) fn run_traced(
+    program: &[Node],
+    options: &Options,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    trace: Option<&mut crate::trace::Writer<dyn Write + '_>>,
+) -> Result<(), Error> {
+    let (opcode, faults) = compile(program, options, None, trace.is_some())?;
+    run_opcode(opcode.as_ref(), &faults, options, input, output, trace)
+}
+
+/// Returns the machine code generated for `program`, with the default [`Options`].
+pub fn code(program: &[Node]) -> Result<Vec<u8>, Error> {
+    Ok(compile(program, &Options::default(), None, false)?
+        .0
+        .to_vec())
+}
+
+/// Returns the annotated assembly listing of the machine code generated for `program`, with the default [`Options`].
+pub fn listing(program: &[Node]) -> Result<String, Error> {
+    let mut listing = Listing::default();
+    let (code, _) = compile(program, &Options::default(), Some(&mut listing), false)?;
+    Ok(listing.render(&code))
+}
+
+/// Lowers `nodes`, which are all either [`NodeKind::Add`] or [`NodeKind::Set`].
+/// The cells are addressed with offsets from the index, but they must wrap around
+/// when they are beyond either end of the tape. So, only if the index is far enough from the ends,
+/// the cells are accessed directly. Otherwise, the index for each cell is wrapped separately.
+/// Without wrapping around, the index for each cell is checked separately instead.
+fn lower_cells(ops: &mut Ops<Assembler>, nodes: &[Node], options: &Options) {
+    let cell = options.cell_width;
+    if options.bounds != Bounds::Wrap {
+        for node in nodes {
+            checked_index(
+                ops,
+                options,
+                node.cell_offset().unwrap_or(0),
+                node.span.start,
+            );
+            change_cell(ops, node, cell);
+        }
+        return;
+    }
+
+    let mask = (options.tape_size - 1) as i32;
+    let offsets = nodes.iter().filter_map(Node::cell_offset);
+    let min = offsets.clone().min().unwrap_or(0).min(0);
+    let max = offsets.max().unwrap_or(0).max(0);
+
+    let slow_label = ops.asm.new_dynamic_label();
+    let end_label = ops.asm.new_dynamic_label();
+    // The displacements are 32 bit, and a tape smaller than the offsets needs the slow path anyway.
+    let reach = |offset: i32| {
+        offset.unsigned_abs() <= mask as u32 && offset.checked_mul(cell.bytes() as i32).is_some()
+    };
+    if reach(min) && reach(max) {
+        if min < 0 {
+            my_dynasm!(ops
+                ; cmp idx, -min
+                ; jb =>slow_label
+            );
+        }
+        if max > 0 {
+            my_dynasm!(ops
+                ; cmp idx, mask - max
+                ; ja =>slow_label
+            );
+        }
+
+        // The immediate values are truncated to the width of the cells by `as _`.
+        for node in nodes {
+            match (&node.kind, cell) {
+                (&NodeKind::Add { offset, amount }, CellWidth::U8) => {
+                    my_dynasm!(ops; add BYTE [ptr + idxq + offset], amount as _)
+                }
+                (&NodeKind::Add { offset, amount }, CellWidth::U16) => {
+                    my_dynasm!(ops; add WORD [ptr + idxq * 2 + offset * 2], amount as _)
+                }
+                (&NodeKind::Add { offset, amount }, CellWidth::U32) => {
+                    my_dynasm!(ops; add DWORD [ptr + idxq * 4 + offset * 4], amount)
+                }
+                (&NodeKind::Set { offset, value }, CellWidth::U8) => {
+                    my_dynasm!(ops; mov BYTE [ptr + idxq + offset], value as _)
+                }
+                (&NodeKind::Set { offset, value }, CellWidth::U16) => {
+                    my_dynasm!(ops; mov WORD [ptr + idxq * 2 + offset * 2], value as _)
+                }
+                (&NodeKind::Set { offset, value }, CellWidth::U32) => {
+                    my_dynasm!(ops; mov DWORD [ptr + idxq * 4 + offset * 4], value)
+                }
+                _ => unreachable!("only changes to cells are lowered together"),
             }
-            _ => {}
         }
+
+        if min == 0 && max == 0 {
+            return;
+        }
+        my_dynasm!(ops; jmp =>end_label);
     }
 
-    if !loops.is_empty() {
-        return Err(Error::UnmatchedLeft);
+    my_dynasm!(ops
+        ;=>slow_label
+    );
+    for node in nodes {
+        // Don't touch `eax` as it keeps the return value.
+        let offset = node.cell_offset().unwrap_or(0);
+        my_dynasm!(ops
+            ; lea ecx, [idxq + offset]
+            ; and ecx, mask
+        );
+        change_cell(ops, node, cell);
+    }
+    my_dynasm!(ops
+        ;=>end_label
+    );
+}
+
+/// Lowers `node`, which is either [`NodeKind::Add`] or [`NodeKind::Set`], for the cell at the index in `rcx`.
+fn change_cell(ops: &mut Ops<Assembler>, node: &Node, cell: CellWidth) {
+    match (&node.kind, cell) {
+        (&NodeKind::Add { amount, .. }, CellWidth::U8) => {
+            my_dynasm!(ops; add BYTE [ptr + rcx], amount as _)
+        }
+        (&NodeKind::Add { amount, .. }, CellWidth::U16) => {
+            my_dynasm!(ops; add WORD [ptr + rcx * 2], amount as _)
+        }
+        (&NodeKind::Add { amount, .. }, CellWidth::U32) => {
+            my_dynasm!(ops; add DWORD [ptr + rcx * 4], amount)
+        }
+        (&NodeKind::Set { value, .. }, CellWidth::U8) => {
+            my_dynasm!(ops; mov BYTE [ptr + rcx], value as _)
+        }
+        (&NodeKind::Set { value, .. }, CellWidth::U16) => {
+            my_dynasm!(ops; mov WORD [ptr + rcx * 2], value as _)
+        }
+        (&NodeKind::Set { value, .. }, CellWidth::U32) => {
+            my_dynasm!(ops; mov DWORD [ptr + rcx * 4], value)
+        }
+        _ => unreachable!("only changes to cells are lowered together"),
     }
+}
 
+/// Calculates the index of the cell `offset` cells away into `ecx`, making sure it is on the tape.
+/// If it isn't, `bounds` either grows the tape or ends the programme with the error at `position`.
+/// Between guard pages, the access faults instead, so it is only written down for the signal handler.
+/// This is only for [`Bounds::Error`], [`Bounds::Grow`] and [`Bounds::Guard`], as the index is masked with [`Bounds::Wrap`].
+fn checked_index(ops: &mut Ops<Assembler>, options: &Options, offset: i32, position: usize) {
+    if guarded(options) {
+        let start = ops.asm.offset().0;
+        ops.faults.accesses.push((start, position));
+        my_dynasm!(ops
+            // The index is zero-extended into `rcx`, so the cell is within 2^32 cells from the tape.
+            ; lea ecx, [idxq + offset]
+        );
+        return;
+    }
+    let retry_label = ops.asm.new_dynamic_label();
+    let ok_label = ops.asm.new_dynamic_label();
     my_dynasm!(ops
-        // Keep `rax` set by `putchar` and `getchar` functions as it is for the return value.
-        ;->throwing:
-        ; pop idxq
-        ; pop ptr
+        ;=>retry_label
+        ; lea ecx, [idxq + offset]
+        // The index on the left of the tape is negative, which is larger than any size as unsigned.
+        ; cmp ecx, size
+        ; jb =>ok_label
+        ; mov rdi, ctx
+        ; mov esi, ecx
+        ; mov edx, position as i32
+        ; mov rax, QWORD bounds as *const () as _
+        ; call rax
+        ; test rax, rax
+        ; jz ->throwing
+        // Take the new tape, and calculate the index again as calling clobbers `ecx`.
+        ; mov ptr, rax
+        ; mov size, edx
+        ; mov eax, 1 // Restore the return value
+        ; jmp =>retry_label
+        ;=>ok_label
+    );
+}
 
-        ; mov rsp, rbp
-        ; pop rbp
-        ; ret
+/// Checks at the end of each iteration of loops whether the programme is cancelled, ending it if so,
+/// and takes a step from the budget with [`Options::max_steps`] or [`Options::timeout`].
+/// When the steps handed out run out, `limit` either hands out more or ends the programme.
+fn back_edge(ops: &mut Ops<Assembler>, options: &Options) {
+    if let Some(cancel) = &options.cancel {
+        let running_label = ops.asm.new_dynamic_label();
+        my_dynasm!(ops
+            // Don't touch `eax` as it keeps the return value.
+            ; mov rcx, QWORD cancel.flag() as _
+            ; cmp BYTE [rcx], 0
+            ; jz =>running_label
+            ; xor eax, eax
+            ; jmp ->throwing
+            ;=>running_label
+        );
+    }
+    if !options.limited() {
+        return;
+    }
+    let ok_label = ops.asm.new_dynamic_label();
+    my_dynasm!(ops
+        // It only borrows when there were no steps left.
+        ; sub budget, 1
+        ; jae =>ok_label
+        ; mov rdi, ctx
+        ; mov rax, QWORD limit as *const () as _
+        ; call rax
+        ; test rax, rax
+        ; jz ->throwing
+        // Take this step from the ones handed out.
+        ; lea budget, [rax - 1]
+        ; mov eax, 1 // Restore the return value
+        ;=>ok_label
     );
+}
 
-    Ok(ops.finalize().expect("Finalising the exec buffer failed"))
+/// Calls `trace` with the record of `op` at `position` in the source, if the programme is compiled with tracing.
+/// The index isn't checked, as the record tells where it is even if it's off the tape.
+fn trace_point(ops: &mut Ops<Assembler>, position: usize, op: Op) {
+    if ops.trace {
+        my_dynasm!(ops
+            ; mov rdi, ctx
+            ; mov rsi, ptr
+            ; mov edx, idx
+            ; mov ecx, position as i32
+            ; mov r8d, op as i32
+            ; mov rax, QWORD trace as *const () as _
+            ; call rax
+            ; cmp eax, 0
+            ; jz ->throwing
+        );
+    }
 }
 
-pub fn run(program: &[u8]) -> Result<(), Error> {
-    let opcode = compile(program)?;
-    run_opcode(opcode.as_ref())
+/// Makes sure the current cell is on the tape without wrapping around, as [`checked_index`] does.
+/// The index is in `rcx` then, which is where [`compare_zero`] and [`cell_address`] take it from.
+fn current_cell(ops: &mut Ops<Assembler>, options: &Options, position: usize) {
+    if options.bounds != Bounds::Wrap {
+        checked_index(ops, options, 0, position);
+    }
+}
+
+/// Reads the current cell between guard pages before passing it to `putchar` or `getchar`,
+/// so that it faults in the machine code rather than in them.
+fn probe(ops: &mut Ops<Assembler>, options: &Options) {
+    if guarded(options) {
+        compare_zero(ops, options);
+    }
+}
+
+/// Compares the current cell with 0.
+fn compare_zero(ops: &mut Ops<Assembler>, options: &Options) {
+    match (options.cell_width, options.bounds) {
+        (CellWidth::U8, Bounds::Wrap) => my_dynasm!(ops; cmp BYTE [ptr + idxq], 0),
+        (CellWidth::U16, Bounds::Wrap) => my_dynasm!(ops; cmp WORD [ptr + idxq * 2], 0),
+        (CellWidth::U32, Bounds::Wrap) => my_dynasm!(ops; cmp DWORD [ptr + idxq * 4], 0),
+        (CellWidth::U8, _) => my_dynasm!(ops; cmp BYTE [ptr + rcx], 0),
+        (CellWidth::U16, _) => my_dynasm!(ops; cmp WORD [ptr + rcx * 2], 0),
+        (CellWidth::U32, _) => my_dynasm!(ops; cmp DWORD [ptr + rcx * 4], 0),
+    }
+}
+
+/// Loads the address of the current cell into `rsi` to pass it to `putchar` or `getchar`.
+fn cell_address(ops: &mut Ops<Assembler>, options: &Options) {
+    match (options.cell_width, options.bounds) {
+        (CellWidth::U8, Bounds::Wrap) => my_dynasm!(ops; lea rsi, [ptr + idxq]),
+        (CellWidth::U16, Bounds::Wrap) => my_dynasm!(ops; lea rsi, [ptr + idxq * 2]),
+        (CellWidth::U32, Bounds::Wrap) => my_dynasm!(ops; lea rsi, [ptr + idxq * 4]),
+        (CellWidth::U8, _) => my_dynasm!(ops; lea rsi, [ptr + rcx]),
+        (CellWidth::U16, _) => my_dynasm!(ops; lea rsi, [ptr + rcx * 2]),
+        (CellWidth::U32, _) => my_dynasm!(ops; lea rsi, [ptr + rcx * 4]),
+    }
 }
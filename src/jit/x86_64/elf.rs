@@ -0,0 +1,87 @@
+//! Standalone executables for Linux, made from the machine code of [`super::machine`].
+//!
+//! The executable has no dependencies, not even the Rust runtime.
+//! It consists of an ELF header, two program headers and the machine code, in that order.
+//! The first program header loads the entire file, and the second allocates the tape, which is zeroed by the kernel.
+
+use super::machine::{assemble, Target};
+use crate::{ir::Node, jit::Faults, Options};
+
+/// The address the file is loaded at, which is the usual one for x86_64.
+const BASE: u64 = 0x40_0000;
+/// The address of the tape. This must be below `BASE` so that the code never overlaps the tape.
+const TAPE: u64 = 0x10_0000;
+const TAPE_SIZE: u64 = u16::MAX as u64 + 1;
+
+const EHDR_SIZE: u16 = 64;
+const PHDR_SIZE: u16 = 56;
+const PHDR_NUM: u16 = 2;
+
+/// Returns a Linux executable for x86_64 running `program`.
+/// The executable exits with 1 if either reading or writing fails, and 0 otherwise.
+pub fn executable(program: &[Node]) -> Vec<u8> {
+    let code = assemble(
+        program,
+        Target::Linux { tape: TAPE as u32 },
+        &Options::default(),
+        // There are no guard pages with the default options.
+        &mut Faults::default(),
+    );
+    let code_offset = (EHDR_SIZE + PHDR_SIZE * PHDR_NUM) as u64;
+    let file_size = code_offset + code.len() as u64;
+
+    let mut writer = Vec::with_capacity(file_size as usize);
+
+    // The ELF header.
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        0x7f, b'E', b'L', b'F',
+        2, // 64 bit
+        1, // little endian
+        1, // the version of ELF
+        0, // System V ABI
+        0, 0, 0, 0, 0, 0, 0, 0, // padding
+    ]);
+    writer.extend_from_slice(&2u16.to_le_bytes()); // an executable file
+    writer.extend_from_slice(&0x3eu16.to_le_bytes()); // x86_64
+    writer.extend_from_slice(&1u32.to_le_bytes()); // the version of ELF again
+    writer.extend_from_slice(&(BASE + code_offset).to_le_bytes()); // the entry point
+    writer.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes()); // the program headers follow this header
+    writer.extend_from_slice(&0u64.to_le_bytes()); // no section headers
+    writer.extend_from_slice(&0u32.to_le_bytes()); // no flags
+    writer.extend_from_slice(&EHDR_SIZE.to_le_bytes());
+    writer.extend_from_slice(&PHDR_SIZE.to_le_bytes());
+    writer.extend_from_slice(&PHDR_NUM.to_le_bytes());
+    writer.extend_from_slice(&0u16.to_le_bytes()); // the size of section headers
+    writer.extend_from_slice(&0u16.to_le_bytes()); // the number of section headers
+    writer.extend_from_slice(&0u16.to_le_bytes()); // the index of the section name table
+
+    // The code, readable and executable.
+    program_header(&mut writer, 0b101, 0, BASE, file_size, file_size);
+    // The tape, readable and writable. Nothing in the file is loaded.
+    program_header(&mut writer, 0b110, 0, TAPE, 0, TAPE_SIZE);
+
+    debug_assert_eq!(writer.len() as u64, code_offset);
+    writer.extend_from_slice(&code);
+    writer
+}
+
+/// Writes a program header to load `file_size` bytes from `offset` in the file to `address`
+/// with the permissions in `flags`. The rest up to `memory_size` is filled with zeros.
+fn program_header(
+    writer: &mut Vec<u8>,
+    flags: u32,
+    offset: u64,
+    address: u64,
+    file_size: u64,
+    memory_size: u64,
+) {
+    writer.extend_from_slice(&1u32.to_le_bytes()); // a loadable segment
+    writer.extend_from_slice(&flags.to_le_bytes());
+    writer.extend_from_slice(&offset.to_le_bytes());
+    writer.extend_from_slice(&address.to_le_bytes()); // the virtual address
+    writer.extend_from_slice(&address.to_le_bytes()); // the physical address, which is ignored
+    writer.extend_from_slice(&file_size.to_le_bytes());
+    writer.extend_from_slice(&memory_size.to_le_bytes());
+    writer.extend_from_slice(&0x1000u64.to_le_bytes()); // aligned to pages
+}
@@ -1,18 +1,89 @@
 use crate::{
-    jit::{getchar, putchar, run_opcode},
-    Consumer as _, Error,
+    ir::{Node, NodeKind},
+    jit::{bounds, dump, getchar, guarded, limit, putchar, run_opcode, trace, Faults},
+    trace::Op,
+    Bounds, CellWidth, Error, Options,
 };
 use memmap2::{Mmap, MmapMut};
+use std::{
+    io::{Read, Write},
+    ops::Range,
+};
+
+/// Where the machine code runs.
+#[derive(Clone, Copy)]
+pub(super) enum Target {
+    /// In this process as a function, calling [`putchar`] and [`getchar`] for io.
+    /// With `trace`, it calls [`trace`] at the conditions of loops and at the end.
+    Jit { trace: bool },
+    /// As the entire programme of a Linux process, making system calls for io.
+    /// The tape is at the address `tape`, which must be a 32 bit value.
+    /// The options must be the default ones.
+    Linux { tape: u32 },
+}
 
-fn compile(program: &[u8]) -> Result<Mmap, Error> {
-    // Although the length of `program` include comments, it is still a good indicator.
-    let mut writer = Vec::with_capacity(program.len());
-    let mut loops = Vec::new();
+fn compile(program: &[Node], options: &Options, trace: bool) -> Result<(Mmap, Faults), Error> {
+    let mut faults = Faults::default();
+    let writer = assemble(program, Target::Jit { trace }, options, &mut faults);
+
+    // The use of `mmap` is neccessary as POSIX defines `mprotect` only for `mmap`.
+    let mut opcode = MmapMut::map_anon(writer.len())?;
+    opcode.copy_from_slice(&writer);
+    Ok((opcode.make_exec()?, faults))
+}
+
+/// Writes machine code for `program` running on `target` with `options`.
+/// Where it accesses cells between guard pages is written down in `faults`.
+pub(super) fn assemble(
+    program: &[Node],
+    target: Target,
+    options: &Options,
+    faults: &mut Faults,
+) -> Vec<u8> {
+    // Although the number of nodes doesn't tell the size of machine code, it is still a good indicator.
+    let mut writer = Vec::with_capacity(program.len() * 8);
     let mut throwing_dsts = Vec::new();
 
-    // The signature of compiled routine is `fn(*mut u8)`.
-    // Since it uses sysv64 calling convention, `rdi` stores the argument.
-    // Use that register to store the pointer to the buffer throughtout.
+    if let Target::Linux { tape } = target {
+        // There's nothing to preserve at the entry point of a process.
+        // Writing to a 32 bit register clears the upper half of the 64 bit register.
+        writer.push(0xb8 + 3); // mov ebx,
+        writer.extend_from_slice(&tape.to_ne_bytes());
+        writer.extend_from_slice(&[0x4d, 0x31, 0b11_100_100]); // xor r12, r12
+
+        lower(
+            &mut writer,
+            program,
+            &mut throwing_dsts,
+            faults,
+            target,
+            options,
+        );
+
+        // 231 is `exit_group`, which exits with the status in edi.
+        #[rustfmt::skip]
+        writer.extend_from_slice(&[
+            0x31, 0b11_111_111, // xor edi, edi
+            // 0xeb is jmp with an 8 bit offset.
+            0xeb, 5, // jmp exit
+        ]);
+        for throwing_dst in throwing_dsts {
+            let fwd_label = writer.len() as i32 - throwing_dst.start as i32 - 4;
+            writer[throwing_dst].copy_from_slice(&fwd_label.to_ne_bytes());
+        }
+        #[rustfmt::skip]
+        writer.extend_from_slice(&[
+            0xb8 + 7, 1, 0, 0, 0, // mov edi, 1
+            // exit:
+            0xb8, 231, 0, 0, 0, // mov eax, 231
+            0x0f, 0x05, // syscall
+        ]);
+        return writer;
+    }
+
+    // The signature of compiled routine is `fn(*mut u8, *mut Context)`.
+    // Since it uses sysv64 calling convention, `rdi` and `rsi` store the arguments.
+    // Keep the pointer to the buffer in rbx, the context in r13 and the number of cells in r14 throughout.
 
     // Write sysv64's minimum prelude.
     // This preserves the 64-bit base pointer and stack pointer.
@@ -29,59 +100,311 @@ fn compile(program: &[u8]) -> Result<Mmap, Error> {
         0x50 + 3, // push rbx
         // "+ 4" is usually rsp, but the 0x41 prefix changes it to r12. It's called REX.B.
         0x41, 0x50 + 4, // push r12
+        0x41, 0x50 + 5, // push r13
+        // This also keeps the stack aligned to 16 bytes for calls, after the return address and 5 registers.
+        0x41, 0x50 + 6, // push r14
         // rdi is the 7th register (0b111).
         0x48, 0x89, 0b11_111_011, // mov QWORD rbx, rdi
+        // 0x49 is REX.W and REX.B. rsi is the 6th register (0b110).
+        0x49, 0x89, 0b11_110_101, // mov QWORD r13, rsi
         // 0x4d acts both as REX.W, REX.R and REX.B.
         // REX.B alternates the first part byte while REX.R changes the second part.
         // 0x31 is xor.
         0x4d, 0x31, 0b11_100_100, // xor r12, r12
         // 0xb8 means mov that takes a register and an immediate value.
-        0x48, 0xc7, 0b11_000_000, 1, 0, 0, 0 // mov QWORD rax, 1
+        0x48, 0xc7, 0b11_000_000, 1, 0, 0, 0, // mov QWORD rax, 1
+        0x41, 0xb8 + 6, // mov r14d,
     ]);
+    writer.extend_from_slice(&(options.tape_size as u32).to_ne_bytes());
+    if options.limited() {
+        // Keep the steps handed out by `limit` in r15, starting from none.
+        #[rustfmt::skip]
+        writer.extend_from_slice(&[
+            0x41, 0x50 + 7, // push r15
+            // 0x83 takes an 8 bit immediate value, and sub is 5 (0b101) in its extension.
+            0x48, 0x83, 0b11_101_100, 8, // sub rsp, 8
+            0x4d, 0x31, 0b11_111_111, // xor r15, r15
+        ]);
+    }
+
+    lower(
+        &mut writer,
+        program,
+        &mut throwing_dsts,
+        faults,
+        target,
+        options,
+    );
+    let end = program.last().map_or(0, |node| node.span.end);
+    trace_point(&mut writer, &mut throwing_dsts, target, end, Op::End);
 
-    let mut iter = program.iter();
-    while let Some(&c) = iter.next() {
-        match c {
-            b'>' => {
-                let amount = iter.consume_while(b'>') + 1;
+    for throwing_dst in throwing_dsts {
+        let fwd_label = writer.len() as i32 - throwing_dst.start as i32 - 4;
+        writer[throwing_dst].copy_from_slice(&fwd_label.to_ne_bytes());
+    }
+    faults.throwing = writer.len();
+
+    // Write sysv64's postlude.
+    // This undoes the prelude.
+    if options.limited() {
+        #[rustfmt::skip]
+        writer.extend_from_slice(&[
+            0x48, 0x83, 0b11_000_100, 8, // add rsp, 8
+            0x41, 0x58 + 7, // pop r15
+        ]);
+    }
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        // 0x58 + 6 is for pop with a register code added.
+        0x41, 0x58 + 6, // pop r14
+        0x41, 0x58 + 5, // pop r13
+        0x41, 0x58 + 4, // pop r12
+        0x58 + 3, // pop rbx
+
+        0x48, 0x89, 0b11_101_100, // mov QWORD rsp, rbp
+        0x58 + 5,     // pop rbp
+        0xc3,         // ret
+    ]);
+
+    writer
+}
+
+/// Writes machine code for `nodes` into `writer`.
+/// The locations of the offsets for jumping to the postlude on errors are pushed to `throwing_dsts`,
+/// and the accesses to cells between guard pages to `faults`.
+fn lower(
+    writer: &mut Vec<u8>,
+    nodes: &[Node],
+    throwing_dsts: &mut Vec<Range<usize>>,
+    faults: &mut Faults,
+    target: Target,
+    options: &Options,
+) {
+    let cell = options.cell_width;
+    // The tape has as many cells as a power of two to wrap around, so the mask is one less.
+    let mask = (options.tape_size - 1) as u32;
+    // The register code of the index of the current cell, which `current_cell` calculates.
+    let index = current_index(options);
+    // Changes to cells in a row are lowered together.
+    for nodes in nodes.chunk_by(|a, b| a.cell_offset().is_some() && b.cell_offset().is_some()) {
+        let position = nodes[0].span.start;
+        match nodes[0].kind {
+            NodeKind::Move { amount } => {
                 // 0x81 has an opcode exntension to switch 7 operations.
                 // The last byte is kind of a ModR/M byte where the second part is for add.
+                // A negative amount moves the pointer to the left.
                 writer.extend_from_slice(&[0x41, 0x81, 0b11_000_100]); // add r12d,
-                writer.extend_from_slice(&(amount as i32).to_ne_bytes());
-                // 0x45 is REX.B and REX.R. 0x0fb7 is for movzx.
-                writer.extend_from_slice(&[0x45, 0x0f, 0xb7, 0b11_100_100]); // mov r12d, r12w
+                writer.extend_from_slice(&amount.to_ne_bytes());
+                // The index is only checked when a cell is accessed, unless it wraps around.
+                if options.bounds == Bounds::Wrap {
+                    // and is 4 (0b100) in the extension of 0x81.
+                    writer.extend_from_slice(&[0x41, 0x81, 0b11_100_100]); // and r12d,
+                    writer.extend_from_slice(&mask.to_ne_bytes());
+                }
+            }
+            NodeKind::Add { .. } | NodeKind::Set { .. } => {
+                lower_cells(writer, nodes, throwing_dsts, faults, options)
+            }
+            NodeKind::Scan { stride } if options.bounds != Bounds::Wrap => {
+                // Check every cell on the way, which is where it can go out of the tape.
+                let start_label = writer.len();
+                back_edge(writer, throwing_dsts, options);
+                current_cell(writer, throwing_dsts, faults, options, position);
+                compare_zero(writer, cell, index);
+                #[rustfmt::skip]
+                writer.extend_from_slice(&[
+                    0x74, 12, // je to the end
+                    0x41, 0x81, 0b11_000_100, // add r12d,
+                ]);
+                writer.extend_from_slice(&stride.to_ne_bytes());
+                // 0xe9 is jmp with a 32 bit offset.
+                let start_label = start_label as i32 - (writer.len() as i32 + 5);
+                writer.push(0xe9); // jmp start
+                writer.extend_from_slice(&start_label.to_ne_bytes());
+            }
+            NodeKind::Scan { stride } => {
+                compare_zero(writer, cell, R12);
+                // 0x74 is je with an 8 bit offset, which is enough for this short jump.
+                writer.extend_from_slice(&[0x74, 0]); // je to the end
+                let end_label_dst = writer.len() - 1;
+                let start_label = writer.len();
+                back_edge(writer, throwing_dsts, options);
+                match stride {
+                    // See `asm.rs` for how this works.
+                    // scasb only compares bytes, so wider cells take the loop below.
+                    1 if cell == CellWidth::U8 => {
+                        #[rustfmt::skip]
+                        writer.extend_from_slice(&[
+                            0x4a, 0x8d, 0b00_111_100, 0b00_100_011, // lea rdi, [rbx + r12]
+                            0xb9, // mov ecx,
+                        ]);
+                        writer.extend_from_slice(&(options.tape_size as u32).to_ne_bytes());
+                        #[rustfmt::skip]
+                        writer.extend_from_slice(&[
+                            // 0x29 is sub that takes a register as the second operand.
+                            0x44, 0x29, 0b11_100_001, // sub ecx, r12d
+                            0x31, 0b11_000_000, // xor eax, eax
+                            // 0xf2 is the repne prefix. 0xae is scasb.
+                            0xf2, 0xae, // repne scasb
+                            0x74, 10, // je found
+                            0x48, 0x89, 0b11_011_111, // mov rdi, rbx
+                            0x44, 0x89, 0b11_100_001, // mov ecx, r12d
+                            0xf2, 0xae, // repne scasb
+                            // 0x75 is jne with an 8 bit offset.
+                            0x75, // jne start
+                        ]);
+                        writer.push((start_label as i32 - (writer.len() as i32 + 1)) as u8);
+                        #[rustfmt::skip]
+                        writer.extend_from_slice(&[
+                            // found:
+                            0x48, 0x29, 0b11_011_111, // sub rdi, rbx
+                            // The 0b01 modifier means an 8 bit displacement follows.
+                            0x44, 0x8d, 0b01_100_111, -1i8 as u8, // lea r12d, [rdi - 1]
+                            0xb8, 1, 0, 0, 0, // mov eax, 1
+                        ]);
+                    }
+                    -1 if cell == CellWidth::U8 => {
+                        let mask = mask.to_ne_bytes();
+                        #[rustfmt::skip]
+                        writer.extend_from_slice(&[
+                            0x4a, 0x8d, 0b00_111_100, 0b00_100_011, // lea rdi, [rbx + r12]
+                            0x41, 0x8d, 0b01_001_100, 0b00_100_100, 1, // lea ecx, [r12 + 1]
+                            // Calculate the number of cells after the current one for the second search,
+                            // as it must not touch the flags there.
+                            0xb8 + 2, mask[0], mask[1], mask[2], mask[3], // mov edx, mask
+                            0x44, 0x29, 0b11_100_010, // sub edx, r12d
+                            0x31, 0b11_000_000, // xor eax, eax
+                            0xfd, // std
+                            0xf2, 0xae, // repne scasb
+                            0xfc, // cld
+                            0x74, 15, // je found
+                            0x48, 0x8d, 0b10_111_011, mask[0], mask[1], mask[2], mask[3], // lea rdi, [rbx + mask]
+                            0x89, 0b11_010_001, // mov ecx, edx
+                            0xfd, // std
+                            0xf2, 0xae, // repne scasb
+                            0xfc, // cld
+                            0x75, // jne start
+                        ]);
+                        writer.push((start_label as i32 - (writer.len() as i32 + 1)) as u8);
+                        #[rustfmt::skip]
+                        writer.extend_from_slice(&[
+                            // found:
+                            0x48, 0x29, 0b11_011_111, // sub rdi, rbx
+                            0x44, 0x8d, 0b01_100_111, 1, // lea r12d, [rdi + 1]
+                            0xb8, 1, 0, 0, 0, // mov eax, 1
+                        ]);
+                    }
+                    _ => {
+                        writer.extend_from_slice(&[0x41, 0x81, 0b11_000_100]); // add r12d,
+                        writer.extend_from_slice(&stride.to_ne_bytes());
+                        writer.extend_from_slice(&[0x41, 0x81, 0b11_100_100]); // and r12d,
+                        writer.extend_from_slice(&mask.to_ne_bytes());
+                        compare_zero(writer, cell, R12);
+                        let start_label = start_label as i32 - (writer.len() as i32 + 2);
+                        writer.extend_from_slice(&[0x75, start_label as u8]); // jne start
+                    }
+                }
+                writer[end_label_dst] = (writer.len() - end_label_dst - 1) as u8;
+            }
+            NodeKind::MulAdd { offset, factor } if options.bounds != Bounds::Wrap => {
+                // Only access the other cell if the loop this comes from runs, as it can be out of the tape.
+                current_cell(writer, throwing_dsts, faults, options, position);
+                compare_zero(writer, cell, RCX);
+                writer.extend_from_slice(&[0x0f, 0x84, 0, 0, 0, 0]); // je
+                let end_label_dst = writer.len() - 4..writer.len();
+                checked_index(writer, throwing_dsts, faults, options, offset, position);
+                // The current cell is on the tape as it is checked above.
+                // Use edx as a scratch register, where 2 (0b010) is for edx.
+                cell_operand(writer, cell, 0, load(cell), 2, R12, 0); // movzx/mov edx, [rbx + r12]
+                writer.extend_from_slice(&[0x69, 0b11_010_010]); // imul edx, edx,
+                writer.extend_from_slice(&factor.to_ne_bytes());
+                operand_size(writer, cell);
+                let add = if cell == CellWidth::U8 { 0x00 } else { 0x01 };
+                cell_operand(writer, cell, 0, &[add], 2, RCX, 0); // add [rbx + rcx], dl/dx/edx
+                let end_label = writer.len() as i32 - end_label_dst.end as i32;
+                writer[end_label_dst].copy_from_slice(&end_label.to_ne_bytes());
             }
-            b'<' => {
-                let amount = iter.consume_while(b'<') + 1;
-                // sub is 0b101 (5).
-                writer.extend_from_slice(&[0x41, 0x81, 0b11_101_100]); // sub r12d,
-                writer.extend_from_slice(&(amount as i32).to_ne_bytes());
-                writer.extend_from_slice(&[0x45, 0x0f, 0xb7, 0b11_100_100]); // mov r12d, r12w
+            NodeKind::MulAdd { offset, factor } => {
+                // Use ecx and edx as scratch registers. eax keeps the return value.
+                // 1 (0b001) is for ecx.
+                cell_operand(writer, cell, 0, load(cell), 1, R12, 0); // movzx/mov ecx, [rbx + r12]
+                #[rustfmt::skip]
+                writer.extend_from_slice(&[
+                    // 0x69 is imul that takes a 32 bit immediate value.
+                    0x69, 0b11_001_001, // imul ecx, ecx,
+                ]);
+                writer.extend_from_slice(&factor.to_ne_bytes());
+                #[rustfmt::skip]
+                writer.extend_from_slice(&[
+                    // The 0b10 modifier means a 32 bit displacement follows the SIB byte.
+                    // r12 can only be a base with a SIB byte, where 0b100 as the displacement means none.
+                    0x41, 0x8d, 0b10_010_100, 0b00_100_100, // lea edx, [r12 +
+                ]);
+                writer.extend_from_slice(&offset.to_ne_bytes()); // ]
+                writer.extend_from_slice(&[0x81, 0b11_100_010]); // and edx,
+                writer.extend_from_slice(&mask.to_ne_bytes());
+                // 0x01 is add taking a register as the second operand, and 0x00 is its 8 bit version.
+                operand_size(writer, cell);
+                let add = if cell == CellWidth::U8 { 0x00 } else { 0x01 };
+                cell_operand(writer, cell, 0, &[add], 1, 2, 0); // add [rbx + rdx], cl/cx/ecx
             }
-            b'+' => {
-                let amount = iter.consume_while(b'+') + 1;
-                // 0x42 is REX.X, which alternates the displacement register of the SIB.
-                // 0x80 is the 8 bit version of 0x81.
-                // The 0b00 modifier means one operand is a pointer to the value wanted.
-                // 0x100 in the last three byte of ModR/M doesn't specify registers. It says I'm using the SIB byte.
-                // The SIB byte follows ModR/M and specifies the base and displacement registers.
-                // The first two bit of SIB changes the scale of displacement.
-                writer.extend_from_slice(&[0x42, 0x80, 0b00_000_100, 0b00_100_011]); // add BYTE [rbx + r12],
-                writer.extend_from_slice(&(amount as i8).to_ne_bytes());
+            NodeKind::Output if matches!(target, Target::Linux { .. }) => {
+                #[rustfmt::skip]
+                writer.extend_from_slice(&[
+                    // 1 is `write`, writing edx bytes from rsi to the file descriptor edi.
+                    0xb8, 1, 0, 0, 0, // mov eax, 1
+                    0xb8 + 7, 1, 0, 0, 0, // mov edi, 1
+                    // rsi is the 6th register (0b110).
+                    0x4a, 0x8d, 0b00_110_100, 0b00_100_011, // lea rsi, [rbx + r12]
+                    0xb8 + 2, 1, 0, 0, 0, // mov edx, 1
+                    0x0f, 0x05, // syscall
+                    // It returns the number of bytes written, or a negative number on errors.
+                    // 0x83 is the version of 0x81 that takes an 8 bit immediate value.
+                    0x48, 0x83, 0b11_111_000, 1, // cmp rax, 1
+                    0x0f, 0x85, // jne
+                    0, 0, 0, 0 // stub for the relocation offset.
+                ]);
+                throwing_dsts.push(writer.len() - 4..writer.len());
             }
-            b'-' => {
-                let amount = iter.consume_while(b'-') + 1;
-                writer.extend_from_slice(&[0x42, 0x80, 0b00_101_100, 0b00_100_011]); // sub BYTE [rbx + r12],
-                writer.extend_from_slice(&(amount as i8).to_ne_bytes());
+            NodeKind::Input if matches!(target, Target::Linux { .. }) => {
+                #[rustfmt::skip]
+                writer.extend_from_slice(&[
+                    // 0 is `read`, reading edx bytes from the file descriptor edi to rsi.
+                    0x31, 0b11_000_000, // xor eax, eax
+                    0x31, 0b11_111_111, // xor edi, edi
+                    0x4a, 0x8d, 0b00_110_100, 0b00_100_011, // lea rsi, [rbx + r12]
+                    0xb8 + 2, 1, 0, 0, 0, // mov edx, 1
+                    0x0f, 0x05, // syscall
+                    // It returns the number of bytes read, 0 at EOF, or a negative number on errors.
+                    // 0x85 is test.
+                    0x48, 0x85, 0b11_000_000, // test rax, rax
+                    // 0x0f, 0x88 is js.
+                    0x0f, 0x88, // js
+                    0, 0, 0, 0 // stub for the relocation offset.
+                ]);
+                throwing_dsts.push(writer.len() - 4..writer.len());
+                #[rustfmt::skip]
+                writer.extend_from_slice(&[
+                    0x75, 5, // jnz to the end
+                    // Set the cell to 0 on EOF, the same as `crate::getchar`.
+                    0x42, 0xc6, 0b00_000_100, 0b00_100_011, 0, // mov BYTE [rbx + r12], 0
+                ]);
             }
-            b'.' => {
+            NodeKind::Output => {
+                current_cell(writer, throwing_dsts, faults, options, position);
+                probe(writer, options);
                 #[rustfmt::skip]
                 writer.extend_from_slice(&[
-                    0x4a, 0x8d, 0b00_111_100, 0b00_100_011, // lea QWORD rdi, [rbx + r12]
-                    // 0xb8 is for mov with a register code.
-                    0x48, 0xb8 + 0, // mov rax, QWORD
+                    // 0x4c is REX.W and REX.R, where r13 is the 5th register (0b101) of the extended ones.
+                    0x4c, 0x89, 0b11_101_111, // mov QWORD rdi, r13
                 ]);
-                writer.extend_from_slice(&(putchar as u64).to_ne_bytes());
+                cell_operand(writer, cell, 0x48, &[0x8d], 6, index, 0); // lea QWORD rsi, [rbx + r12]
+                #[rustfmt::skip]
+                writer.extend_from_slice(&[
+                    // 0xb8 is for mov with a register code. rax is 0.
+                    0x48, 0xb8, // mov rax, QWORD
+                ]);
+                writer.extend_from_slice(&(putchar as *const () as u64).to_ne_bytes());
                 #[rustfmt::skip]
                 writer.extend_from_slice(&[
                     // 0xff is for call when the ModR/M byte says 2 (0b010).
@@ -94,13 +417,16 @@ fn compile(program: &[u8]) -> Result<Mmap, Error> {
                 ]);
                 throwing_dsts.push(writer.len() - 4..writer.len());
             }
-            b',' => {
+            NodeKind::Input => {
+                current_cell(writer, throwing_dsts, faults, options, position);
+                probe(writer, options);
                 #[rustfmt::skip]
                 writer.extend_from_slice(&[
-                    0x4a, 0x8d, 0b00_111_100, 0b00_100_011, // lea QWORD rdi, [rbx + r12]
-                    0x48, 0xb8 + 0, // mov rax, QWORD
+                    0x4c, 0x89, 0b11_101_111, // mov QWORD rdi, r13
                 ]);
-                writer.extend_from_slice(&(getchar as u64).to_ne_bytes());
+                cell_operand(writer, cell, 0x48, &[0x8d], 6, index, 0); // lea QWORD rsi, [rbx + r12]
+                writer.extend_from_slice(&[0x48, 0xb8]); // mov rax, QWORD
+                writer.extend_from_slice(&(getchar as *const () as u64).to_ne_bytes());
                 #[rustfmt::skip]
                 writer.extend_from_slice(&[
                     0xff, 0b11_010_000, // call rax
@@ -110,67 +436,435 @@ fn compile(program: &[u8]) -> Result<Mmap, Error> {
                 ]);
                 throwing_dsts.push(writer.len() - 4..writer.len());
             }
-            b'[' => {
+            // An executable has nothing to show the tape with.
+            NodeKind::Dump if matches!(target, Target::Linux { .. }) => {}
+            // The index isn't checked, as `dump` shows where it is even if it's off the tape.
+            NodeKind::Dump => {
                 #[rustfmt::skip]
                 writer.extend_from_slice(&[
-                    0x42, 0x80, 0b00_111_100, 0b00_100_011,
-                    0, // cmp BYTE [rbx + r12], 0
-                    // 0x0f, 0x84 is je.
-                    0x0f, 0x84, // je
+                    0x4c, 0x89, 0b11_101_111, // mov QWORD rdi, r13
+                    0x48, 0x89, 0b11_011_110, // mov QWORD rsi, rbx
+                    // 0x44 is REX.R, where r12 is the 4th register (0b100) of the extended ones.
+                    0x44, 0x89, 0b11_100_010, // mov edx, r12d
+                    0xb8 + 1, // mov ecx,
                 ]);
-                let fwd_label_dst = writer.len()..writer.len() + 4;
-                writer.extend_from_slice(&[0; 4]);
-                loops.push(fwd_label_dst);
-            }
-            b']' => {
-                let fwd_label_dst = loops.pop().ok_or(Error::UnmatchedRight)?;
+                writer.extend_from_slice(&(position as u32).to_ne_bytes());
+                writer.extend_from_slice(&[0x48, 0xb8]); // mov rax, QWORD
+                writer.extend_from_slice(&(dump as *const () as u64).to_ne_bytes());
                 #[rustfmt::skip]
                 writer.extend_from_slice(&[
-                    0x42, 0x80, 0b00_111_100, 0b00_100_011,
-                    0, // cmp BYTE [rbx + r12], 0
-                    0x0f, 0x85, // jne
+                    0xff, 0b11_010_000, // call rax
+                    0x3c, 0, // cmp al, 0
+                    0x0f, 0x84, // jz
+                    0, 0, 0, 0 // stub for the relocation offset.
                 ]);
+                throwing_dsts.push(writer.len() - 4..writer.len());
+            }
+            NodeKind::Loop { ref body } => {
+                let start_label = writer.len();
+                trace_point(writer, throwing_dsts, target, position, Op::LoopStart);
+                current_cell(writer, throwing_dsts, faults, options, position);
+                compare_zero(writer, cell, index);
+                // 0x0f, 0x84 is je.
+                writer.extend_from_slice(&[0x0f, 0x84]); // je
+                let fwd_label_dst = writer.len()..writer.len() + 4;
+                writer.extend_from_slice(&[0; 4]);
+
+                lower(writer, body, throwing_dsts, faults, target, options);
+
+                let end = nodes[0].span.end - 1;
+                trace_point(writer, throwing_dsts, target, end, Op::LoopEnd);
+                back_edge(writer, throwing_dsts, options);
+                current_cell(
+                    writer,
+                    throwing_dsts,
+                    faults,
+                    options,
+                    nodes[0].span.end - 1,
+                );
+                compare_zero(writer, cell, index);
                 // je and jne use relative locations to jump
                 // Subtract the start of the backward label location from
                 // the start of the forward label location to get the difference.
-                let bwd_label = fwd_label_dst.start as i32 - writer.len() as i32;
-                writer.extend_from_slice(&bwd_label.to_ne_bytes());
+                writer.extend_from_slice(&[0x0f, 0x85]); // jne
+                let mut bwd_label = fwd_label_dst.start as i32 - writer.len() as i32;
                 let fwd_label = -bwd_label;
+                // Traced, it goes back to check the cell at `[` again as the interpreter does, so that their traces match.
+                if matches!(target, Target::Jit { trace: true }) {
+                    bwd_label = start_label as i32 - (writer.len() as i32 + 4);
+                }
+                writer.extend_from_slice(&bwd_label.to_ne_bytes());
                 writer[fwd_label_dst].copy_from_slice(&fwd_label.to_ne_bytes());
             }
-            _ => {}
         }
     }
+}
 
-    if !loops.is_empty() {
-        return Err(Error::UnmatchedLeft);
+pub fn run(
+    program: &[Node],
+    options: &Options,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> Result<(), Error> {
+    run_traced(program, options, input, output, None)
+}
+
+/// Runs `program` the same as [`run`], compiled with calls to write the records of the conditions of loops
+/// and the end into `trace` if any.
+pub(crate) fn run_traced(
+    program: &[Node],
+    options: &Options,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    trace: Option<&mut crate::trace::Writer<dyn Write + '_>>,
+) -> Result<(), Error> {
+    let (opcode, faults) = compile(program, options, trace.is_some())?;
+    run_opcode(opcode.as_ref(), &faults, options, input, output, trace)
+}
+
+/// Returns the machine code generated for `program`, with the default [`Options`].
+pub fn code(program: &[Node]) -> Result<Vec<u8>, Error> {
+    Ok(compile(program, &Options::default(), false)?.0.to_vec())
+}
+
+/// Calls [`trace`] with the record of `op` at `position` in the source, if `target` is compiled with tracing.
+/// The index isn't checked, as the record tells where it is even if it's off the tape.
+fn trace_point(
+    writer: &mut Vec<u8>,
+    throwing_dsts: &mut Vec<Range<usize>>,
+    target: Target,
+    position: usize,
+    op: Op,
+) {
+    if !matches!(target, Target::Jit { trace: true }) {
+        return;
     }
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        0x4c, 0x89, 0b11_101_111, // mov QWORD rdi, r13
+        0x48, 0x89, 0b11_011_110, // mov QWORD rsi, rbx
+        0x44, 0x89, 0b11_100_010, // mov edx, r12d
+        0xb8 + 1, // mov ecx,
+    ]);
+    writer.extend_from_slice(&(position as u32).to_ne_bytes());
+    // 0x41 is REX.B, where r8 is the 0th register of the extended ones.
+    writer.extend_from_slice(&[0x41, 0xb8]); // mov r8d,
+    writer.extend_from_slice(&(op as u32).to_ne_bytes());
+    writer.extend_from_slice(&[0x48, 0xb8]); // mov rax, QWORD
+    writer.extend_from_slice(&(trace as *const () as u64).to_ne_bytes());
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        0xff, 0b11_010_000, // call rax
+        0x3c, 0, // cmp al, 0
+        0x0f, 0x84, // jz
+        0, 0, 0, 0 // stub for the relocation offset.
+    ]);
+    throwing_dsts.push(writer.len() - 4..writer.len());
+}
 
-    for throwing_dst in throwing_dsts {
-        let fwd_label = writer.len() as i32 - throwing_dst.start as i32 - 4;
-        writer[throwing_dst].copy_from_slice(&fwd_label.to_ne_bytes());
+/// The register code of r12 as an index, which needs REX.X.
+const R12: u8 = 0b100;
+/// The register code of rcx, where [`checked_index`] calculates the index.
+const RCX: u8 = 0b001;
+
+/// Returns the opcode loading a cell into a 32 bit register, zero-extending it.
+/// 0x0fb6 and 0x0fb7 are movzx from 8 and 16 bits, and 0x8b is mov.
+fn load(cell: CellWidth) -> &'static [u8] {
+    match cell {
+        CellWidth::U8 => &[0x0f, 0xb6],
+        CellWidth::U16 => &[0x0f, 0xb7],
+        CellWidth::U32 => &[0x8b],
     }
+}
 
-    // Write sysv64's postlude.
-    // This undoes the prelude.
+/// Writes the operand-size prefix if `cell` is 16 bits.
+/// 0x66 switches instructions working on 32 bits to 16 bits.
+fn operand_size(writer: &mut Vec<u8>, cell: CellWidth) {
+    if cell == CellWidth::U16 {
+        writer.push(0x66);
+    }
+}
+
+/// Writes `opcode` with `reg` and the cell `offset` cells away from `[rbx + index]` as its operands,
+/// where `index` is a register code and the cells are as wide as `cell`.
+/// `rex` is the REX prefix the instruction needs other than REX.X, or 0 if none.
+fn cell_operand(
+    writer: &mut Vec<u8>,
+    cell: CellWidth,
+    rex: u8,
+    opcode: &[u8],
+    reg: u8,
+    index: u8,
+    offset: i32,
+) {
+    // 0x42 is REX.X, which alternates the index register of the SIB.
+    let rex = if index == R12 { rex | 0x42 } else { rex };
+    if rex != 0 {
+        writer.push(rex);
+    }
+    writer.extend_from_slice(opcode);
+    // The 0b00 modifier means one operand is a pointer to the value wanted,
+    // and the 0b10 modifier means a 32 bit displacement follows the SIB byte.
+    // 0x100 in the last three byte of ModR/M doesn't specify registers. It says I'm using the SIB byte.
+    // The SIB byte follows ModR/M and specifies the base and index registers.
+    // The first two bit of SIB changes the scale of the index, which is the width of the cells.
+    let modifier = if offset == 0 { 0b00 } else { 0b10 };
+    writer.push(modifier << 6 | reg << 3 | 0b100);
+    writer.push((cell.shift() as u8) << 6 | index << 3 | 0b011);
+    if offset != 0 {
+        writer.extend_from_slice(&(offset * cell.bytes() as i32).to_ne_bytes());
+    }
+}
+
+/// Writes the lowest bytes of `value` that fit in `cell`, as an immediate value.
+fn immediate(writer: &mut Vec<u8>, cell: CellWidth, value: i32) {
+    writer.extend_from_slice(&value.to_ne_bytes()[..cell.bytes()]);
+}
+
+/// Writes `cmp [rbx + index], 0`, where `index` is a register code.
+fn compare_zero(writer: &mut Vec<u8>, cell: CellWidth, index: u8) {
+    // cmp is 7 (0b111) in the extension of 0x80, which takes an 8 bit immediate value,
+    // and 0x83, which takes one as well but sign-extends it to the operand size.
+    operand_size(writer, cell);
+    let opcode = if cell == CellWidth::U8 { 0x80 } else { 0x83 };
+    cell_operand(writer, cell, 0, &[opcode], 0b111, index, 0);
+    writer.push(0);
+}
+
+/// Writes machine code calculating the index of the cell `offset` cells away into ecx, making sure it is on the tape.
+/// If it isn't, `bounds` either grows the tape or ends the programme with the error at `position`.
+/// Between guard pages, the access is written down in `faults` instead.
+/// This is only for [`Bounds::Error`], [`Bounds::Grow`] and [`Bounds::Guard`], as the index is masked with [`Bounds::Wrap`].
+/// See `asm.rs` for how this works.
+fn checked_index(
+    writer: &mut Vec<u8>,
+    throwing_dsts: &mut Vec<Range<usize>>,
+    faults: &mut Faults,
+    options: &Options,
+    offset: i32,
+    position: usize,
+) {
+    let retry_label = writer.len();
+    if guarded(options) {
+        faults.accesses.push((writer.len(), position));
+    }
+    writer.extend_from_slice(&[0x41, 0x8d, 0b10_001_100, 0b00_100_100]); // lea ecx, [r12 +
+    writer.extend_from_slice(&offset.to_ne_bytes()); // ]
+    if guarded(options) {
+        return;
+    }
     #[rustfmt::skip]
     writer.extend_from_slice(&[
-        // 0x58 + 3 is for pop with a register code added.
-        0x41, 0x58 + 4, // pop r12
-        0x58 + 3, // pop rbx
+        // 0x39 is cmp taking a register as the second operand.
+        0x44, 0x39, 0b11_110_001, // cmp ecx, r14d
+        // 0x72 is jb with an 8 bit offset.
+        0x72, 44, // jb ok
+        0x4c, 0x89, 0b11_101_111, // mov rdi, r13
+        0x89, 0b11_001_110, // mov esi, ecx
+        0xb8 + 2, // mov edx,
+    ]);
+    writer.extend_from_slice(&(position as u32).to_ne_bytes());
+    writer.extend_from_slice(&[0x48, 0xb8]); // mov rax, QWORD
+    writer.extend_from_slice(&(bounds as *const () as u64).to_ne_bytes());
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        0xff, 0b11_010_000, // call rax
+        0x48, 0x85, 0b11_000_000, // test rax, rax
+        0x0f, 0x84, // jz
+        0, 0, 0, 0 // stub for the relocation offset.
+    ]);
+    throwing_dsts.push(writer.len() - 4..writer.len());
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        // The tape is returned in rax and rdx.
+        0x48, 0x89, 0b11_000_011, // mov rbx, rax
+        0x41, 0x89, 0b11_010_110, // mov r14d, edx
+        0xb8, 1, 0, 0, 0, // mov eax, 1
+        // 0xeb is jmp with an 8 bit offset.
+        0xeb, // jmp retry
+    ]);
+    writer.push((retry_label as i32 - (writer.len() as i32 + 1)) as u8);
+    // ok:
+}
 
-        0x48, 0x89, 0b11_101_100, // mov QWORD rsp, rbp
-        0x58 + 5,     // pop rbp
-        0xc3,         // ret
+/// Writes machine code checking at the end of each iteration of loops whether the programme is cancelled,
+/// and taking a step from the budget with [`Options::max_steps`] or [`Options::timeout`]. See `asm.rs` for how this works.
+fn back_edge(writer: &mut Vec<u8>, throwing_dsts: &mut Vec<Range<usize>>, options: &Options) {
+    if let Some(cancel) = &options.cancel {
+        writer.extend_from_slice(&[0x48, 0xb8 + 1]); // mov rcx, QWORD
+        writer.extend_from_slice(&(cancel.flag() as u64).to_ne_bytes());
+        #[rustfmt::skip]
+        writer.extend_from_slice(&[
+            // 0x80 is the 8 bit version of 0x81, where cmp is 7 (0b111) in the extension.
+            0x80, 0b00_111_001, 0, // cmp BYTE [rcx], 0
+            0x74, 7, // je running
+            0x31, 0b11_000_000, // xor eax, eax
+            0xe9, // jmp
+            0, 0, 0, 0 // stub for the relocation offset.
+        ]);
+        throwing_dsts.push(writer.len() - 4..writer.len());
+        // running:
+    }
+    if !options.limited() {
+        return;
+    }
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        0x49, 0x83, 0b11_101_111, 1, // sub r15, 1
+        // 0x73 is jae with an 8 bit offset.
+        0x73, 33, // jae ok
+        0x4c, 0x89, 0b11_101_111, // mov rdi, r13
+        0x48, 0xb8, // mov rax, QWORD
     ]);
+    writer.extend_from_slice(&(limit as *const () as u64).to_ne_bytes());
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        0xff, 0b11_010_000, // call rax
+        0x48, 0x85, 0b11_000_000, // test rax, rax
+        0x0f, 0x84, // jz
+        0, 0, 0, 0 // stub for the relocation offset.
+    ]);
+    throwing_dsts.push(writer.len() - 4..writer.len());
+    #[rustfmt::skip]
+    writer.extend_from_slice(&[
+        0x4c, 0x8d, 0b01_111_000, -1i8 as u8, // lea r15, [rax - 1]
+        0xb8, 1, 0, 0, 0, // mov eax, 1
+    ]);
+    // ok:
+}
 
-    // The use of `mmap` is neccessary as POSIX defines `mprotect` only for `mmap`.
-    let mut opcode = MmapMut::map_anon(writer.len())?;
-    opcode.copy_from_slice(&writer);
-    Ok(opcode.make_exec()?)
+/// Returns the register code of the index of the current cell after [`current_cell`].
+fn current_index(options: &Options) -> u8 {
+    if options.bounds == Bounds::Wrap {
+        R12
+    } else {
+        RCX
+    }
+}
+
+/// Makes sure the current cell is on the tape without wrapping around, as [`checked_index`] does.
+fn current_cell(
+    writer: &mut Vec<u8>,
+    throwing_dsts: &mut Vec<Range<usize>>,
+    faults: &mut Faults,
+    options: &Options,
+    position: usize,
+) {
+    if options.bounds != Bounds::Wrap {
+        checked_index(writer, throwing_dsts, faults, options, 0, position);
+    }
+}
+
+/// Reads the current cell between guard pages before passing it to `putchar` or `getchar`,
+/// so that it faults in the machine code rather than in them.
+fn probe(writer: &mut Vec<u8>, options: &Options) {
+    if guarded(options) {
+        compare_zero(writer, options.cell_width, RCX);
+    }
 }
 
-pub fn run(program: &[u8]) -> Result<(), Error> {
-    let opcode = compile(program)?;
-    run_opcode(opcode.as_ref())
+/// Writes machine code for `nodes`, which are all either [`NodeKind::Add`] or [`NodeKind::Set`].
+/// See `asm.rs` for how this works.
+fn lower_cells(
+    writer: &mut Vec<u8>,
+    nodes: &[Node],
+    throwing_dsts: &mut Vec<Range<usize>>,
+    faults: &mut Faults,
+    options: &Options,
+) {
+    let cell = options.cell_width;
+    if options.bounds != Bounds::Wrap {
+        for node in nodes {
+            let (opcode, offset, operand) = cell_change(node, cell);
+            checked_index(
+                writer,
+                throwing_dsts,
+                faults,
+                options,
+                offset,
+                node.span.start,
+            );
+            operand_size(writer, cell);
+            cell_operand(writer, cell, 0, &[opcode], 0, RCX, 0); // add/mov [rbx + rcx],
+            immediate(writer, cell, operand);
+        }
+        return;
+    }
+
+    let mask = (options.tape_size - 1) as u32;
+    let offsets = nodes.iter().filter_map(Node::cell_offset);
+    let min = offsets.clone().min().unwrap_or(0).min(0);
+    let max = offsets.max().unwrap_or(0).max(0);
+
+    let mut slow_label_dsts = Vec::new();
+    let mut end_label_dst = None;
+    let reach = |offset: i32| {
+        offset.unsigned_abs() <= mask && offset.checked_mul(cell.bytes() as i32).is_some()
+    };
+    if reach(min) && reach(max) {
+        if min < 0 {
+            // cmp is 7 (0b111) in the extension of 0x81.
+            writer.extend_from_slice(&[0x41, 0x81, 0b11_111_100]); // cmp r12d,
+            writer.extend_from_slice(&(-min).to_ne_bytes());
+            writer.extend_from_slice(&[0x0f, 0x82, 0, 0, 0, 0]); // jb
+            slow_label_dsts.push(writer.len() - 4..writer.len());
+        }
+        if max > 0 {
+            writer.extend_from_slice(&[0x41, 0x81, 0b11_111_100]); // cmp r12d,
+            writer.extend_from_slice(&(mask - max as u32).to_ne_bytes());
+            writer.extend_from_slice(&[0x0f, 0x87, 0, 0, 0, 0]); // ja
+            slow_label_dsts.push(writer.len() - 4..writer.len());
+        }
+
+        for node in nodes {
+            let (opcode, offset, operand) = cell_change(node, cell);
+            operand_size(writer, cell);
+            cell_operand(writer, cell, 0, &[opcode], 0, R12, offset); // add/mov [rbx + r12 + offset],
+            immediate(writer, cell, operand);
+        }
+
+        if slow_label_dsts.is_empty() {
+            return;
+        }
+
+        writer.extend_from_slice(&[0xe9, 0, 0, 0, 0]); // jmp
+        end_label_dst = Some(writer.len() - 4..writer.len());
+    }
+
+    for slow_label_dst in slow_label_dsts {
+        let slow_label = writer.len() as i32 - slow_label_dst.end as i32;
+        writer[slow_label_dst].copy_from_slice(&slow_label.to_ne_bytes());
+    }
+
+    for node in nodes {
+        let (opcode, offset, operand) = cell_change(node, cell);
+        // Use ecx as a scratch register. eax keeps the return value.
+        writer.extend_from_slice(&[0x41, 0x8d, 0b10_001_100, 0b00_100_100]); // lea ecx, [r12 +
+        writer.extend_from_slice(&offset.to_ne_bytes()); // ]
+        writer.extend_from_slice(&[0x81, 0b11_100_001]); // and ecx,
+        writer.extend_from_slice(&mask.to_ne_bytes());
+        // rcx doesn't need REX.X unlike r12.
+        operand_size(writer, cell);
+        cell_operand(writer, cell, 0, &[opcode], 0, RCX, 0); // add/mov [rbx + rcx],
+        immediate(writer, cell, operand);
+    }
+
+    if let Some(end_label_dst) = end_label_dst {
+        let end_label = writer.len() as i32 - end_label_dst.end as i32;
+        writer[end_label_dst].copy_from_slice(&end_label.to_ne_bytes());
+    }
+}
+
+/// Returns the opcode, the offset and the immediate value for `node`,
+/// which is either [`NodeKind::Add`] or [`NodeKind::Set`].
+fn cell_change(node: &Node, cell: CellWidth) -> (u8, i32, i32) {
+    let wide = cell != CellWidth::U8;
+    match node.kind {
+        // 0x81 takes an immediate value of the operand size, and 0x80 is its 8 bit version.
+        NodeKind::Add { offset, amount } => (if wide { 0x81 } else { 0x80 }, offset, amount),
+        // 0xc7 is mov that takes an immediate value, and 0xc6 is its 8 bit version.
+        // They have no other operations to extend.
+        NodeKind::Set { offset, value } => (if wide { 0xc7 } else { 0xc6 }, offset, value),
+        _ => unreachable!("only changes to cells are lowered together"),
+    }
 }
@@ -1,4 +1,6 @@
 #[cfg(feature = "asm")]
 pub mod asm;
 #[cfg(feature = "machine")]
+pub mod elf;
+#[cfg(feature = "machine")]
 pub mod machine;
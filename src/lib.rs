@@ -0,0 +1,947 @@
+//! A Brainf*ck compiler and interpreter, with engines ranging from a plain interpreter to JIT compilers.
+//!
+//! A [`Program`] is parsed and optimised once, and then runs on any of the [`Engine`]s
+//! with the input and the output given by the caller.
+//!
+//! ```
+//! use brainf_ck::{ir::OptLevel, Engine, Program};
+//!
+//! let program = Program::parse(b",[.,]", OptLevel::O3)?;
+//! let mut output = Vec::new();
+//! program.run(Engine::Interpreter, &b"echo"[..], &mut output)?;
+//! assert_eq!(output, b"echo");
+//! # Ok::<(), brainf_ck::Error>(())
+//! ```
+
+#![warn(unsafe_op_in_unsafe_fn)]
+
+#[cfg(feature = "c")]
+pub mod c;
+#[cfg(feature = "interpreter")]
+mod interpreter;
+pub mod ir;
+#[cfg(any(feature = "asm", feature = "machine"))]
+pub mod jit;
+pub mod trace;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+use std::{
+    array,
+    borrow::Cow,
+    io::Read,
+    sync::{MutexGuard, PoisonError},
+    time::Instant,
+};
+use std::{
+    fmt,
+    io::{self, Write},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    /// A `[` without the `]` closing it, which is the innermost one if there are many.
+    #[error("unmatched [ at {0}")]
+    UnmatchedLeft(Location),
+    /// A `]` without the `[` opening it.
+    #[error("unmatched ] at {0}")]
+    UnmatchedRight(Location),
+    #[error("io-error during execution")]
+    Io(#[from] io::Error),
+    #[error("the cell {index} is out of the tape, accessed at {position}")]
+    OutOfBounds {
+        /// The byte offset in the source of the command accessing the cell.
+        position: usize,
+        /// The index of the cell, which is negative on the left of the tape.
+        index: i64,
+    },
+    #[error("invalid tape size {0}: it must be from 1 to 2^31, and a power of two to wrap around")]
+    TapeSize(usize),
+    /// The programme ran beyond [`Options::max_steps`] or [`Options::timeout`].
+    #[error("the programme exceeded the limit of {0}")]
+    LimitExceeded(Limit),
+    /// The programme was cancelled through [`Options::cancel`].
+    #[error("the programme was cancelled")]
+    Cancelled,
+}
+
+/// A limit on how far a programme runs, which it can exceed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    /// The number of steps from [`Options::max_steps`].
+    Steps(u64),
+    /// The time from [`Options::timeout`].
+    Time(Duration),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Steps(steps) => write!(f, "{steps} steps"),
+            Self::Time(timeout) => write!(f, "{timeout:?}"),
+        }
+    }
+}
+
+/// Where a byte is in the source, for errors pointing at it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Location {
+    /// The byte offset from the start of the source.
+    pub position: usize,
+    /// The line, counted from 1.
+    pub line: usize,
+    /// The character in the line, counted from 1.
+    /// Bytes which aren't valid UTF-8 count as the characters [`String::from_utf8_lossy`] replaces them with,
+    /// where a run of them which could start a valid character is a single one.
+    pub column: usize,
+}
+
+impl Location {
+    /// Returns where the byte at `position` in `source` is.
+    pub fn new(source: &[u8], position: usize) -> Self {
+        let before = &source[..position];
+        let start = before
+            .iter()
+            .rposition(|&byte| byte == b'\n')
+            .map_or(0, |newline| newline + 1);
+        Self {
+            position,
+            line: before.iter().filter(|&&byte| byte == b'\n').count() + 1,
+            column: String::from_utf8_lossy(&before[start..]).chars().count() + 1,
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// A parsed and optimised Brainf*ck programme, ready to run on any engine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Program {
+    /// The tree optimised for the default tape, which the emitters take.
+    nodes: Vec<ir::Node>,
+    /// The tree before the optimisations along with the level, to optimise it again for other tapes.
+    parsed: Vec<ir::Node>,
+    level: ir::OptLevel,
+}
+
+impl Program {
+    /// Parses `source` and optimises it at `level`.
+    pub fn parse(source: &[u8], level: ir::OptLevel) -> Result<Self, Error> {
+        Self::parse_with(source, level, false)
+    }
+
+    /// Parses `source` the same as [`Program::parse`], but with `debug_char`, `#` shows the pointer
+    /// and the cells around it on [`Options::diagnostics`]. The interpreter and the JIT compilers support it,
+    /// while the programmes written out skip it.
+    pub fn parse_with(source: &[u8], level: ir::OptLevel, debug_char: bool) -> Result<Self, Error> {
+        let parsed = ir::parse_with(source, debug_char)?;
+        let options = Options::default();
+        Ok(Self {
+            nodes: ir::optimize(parsed.clone(), level, options.tape_size, options.bounds),
+            parsed,
+            level,
+        })
+    }
+
+    /// Returns the tree of the programme optimised for the default tape, which the emitters take.
+    pub fn nodes(&self) -> &[ir::Node] {
+        &self.nodes
+    }
+
+    /// Returns the tree of the programme optimised for the tape of `options`,
+    /// as the optimisations differ on small tapes and on tapes that don't wrap around.
+    #[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+    fn nodes_for(&self, options: &Options) -> Cow<'_, [ir::Node]> {
+        let default = Options::default();
+        if (options.tape_size, options.bounds) == (default.tape_size, default.bounds) {
+            return Cow::Borrowed(&self.nodes);
+        }
+        let parsed = self.parsed.clone();
+        Cow::Owned(ir::optimize(
+            parsed,
+            self.level,
+            options.tape_size,
+            options.bounds,
+        ))
+    }
+
+    /// Runs the programme with `engine` and the default [`Options`], reading from `input` and writing into `output`.
+    /// EOF reads as 0, and any other io errors end the programme.
+    #[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+    pub fn run(&self, engine: Engine, input: impl Read, output: impl Write) -> Result<(), Error> {
+        self.run_with(engine, &Options::default(), input, output)
+    }
+
+    /// Runs the programme with `engine` and `options`, reading from `input` and writing into `output`.
+    #[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+    pub fn run_with(
+        &self,
+        engine: Engine,
+        options: &Options,
+        input: impl Read,
+        output: impl Write,
+    ) -> Result<(), Error> {
+        self.execute(engine, options, input, output, None)
+    }
+
+    /// Runs the programme the same as [`Program::run_with`], writing a trace of it into `trace`
+    /// in the binary format [`trace::print`] writes out as text.
+    /// The interpreter writes the records at `granularity`, while the JIT compilers always write them
+    /// at [`trace::Granularity::Blocks`], as they are compiled with calls at the conditions of loops.
+    #[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+    pub fn trace(
+        &self,
+        engine: Engine,
+        options: &Options,
+        granularity: trace::Granularity,
+        input: impl Read,
+        output: impl Write,
+        trace: impl Write,
+    ) -> Result<(), Error> {
+        let finest = match engine {
+            #[cfg(feature = "interpreter")]
+            Engine::Interpreter => trace::Granularity::Instructions,
+            #[cfg(feature = "machine")]
+            Engine::Machine => trace::Granularity::Blocks,
+            #[cfg(feature = "asm")]
+            Engine::Asm => trace::Granularity::Blocks,
+        };
+        let granularity = granularity.max(finest);
+        let mut trace = trace::Writer::new(io::BufWriter::new(trace), granularity)?;
+        let result = self.execute(engine, options, input, output, Some(&mut trace));
+        let flushed = trace.flush();
+        result?;
+        Ok(flushed?)
+    }
+
+    /// Runs the programme with `engine`, writing the trace into `trace` if any.
+    #[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+    fn execute(
+        &self,
+        engine: Engine,
+        options: &Options,
+        mut input: impl Read,
+        output: impl Write,
+        trace: Option<&mut trace::Writer<dyn Write + '_>>,
+    ) -> Result<(), Error> {
+        options.check()?;
+        let mut output = Output {
+            writer: output,
+            buffer: Vec::new(),
+            buffering: options.buffering,
+        };
+        let nodes = self.nodes_for(options);
+        let result = {
+            let (input, output): (&mut dyn Read, &mut dyn Write) = (&mut input, &mut output);
+            match engine {
+                #[cfg(feature = "interpreter")]
+                Engine::Interpreter => interpreter::run(&nodes, options, input, output, trace),
+                #[cfg(feature = "machine")]
+                Engine::Machine => jit::machine::run_traced(&nodes, options, input, output, trace),
+                #[cfg(feature = "asm")]
+                Engine::Asm => jit::asm::run_traced(&nodes, options, input, output, trace),
+            }
+        };
+        // Write out what is left even on errors, but the first error is the one to report.
+        let flushed = output.flush();
+        result?;
+        Ok(flushed?)
+    }
+
+    /// Runs the programme on the interpreter under a debugger, which reads commands from `input` a line at a time
+    /// and writes the prompt and what it shows into `prompt`, while the programme writes into `output`.
+    /// The programme reads from `input` too, taking the bytes after the command running it.
+    /// It stops at breakpoints at byte offsets in the source, and can step over instructions or whole loops.
+    /// `help` lists the commands. [`Options::max_steps`] and [`Options::timeout`] don't apply,
+    /// and the end of the commands ends the programme.
+    #[cfg(feature = "interpreter")]
+    pub fn debug(
+        &self,
+        options: &Options,
+        mut input: impl io::BufRead,
+        output: impl Write,
+        mut prompt: impl Write,
+    ) -> Result<(), Error> {
+        options.check()?;
+        let mut output = Output {
+            writer: output,
+            buffer: Vec::new(),
+            buffering: options.buffering,
+        };
+        let nodes = self.nodes_for(options);
+        let result = interpreter::debug(&nodes, options, &mut input, &mut output, &mut prompt);
+        let flushed = output.flush();
+        result?;
+        Ok(flushed?)
+    }
+}
+
+/// How a [`Program`] runs, whichever the engine is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Options {
+    /// When the output is written through.
+    pub buffering: Buffering,
+    /// What reading at the end of the input does.
+    pub eof: EofMode,
+    /// How wide each cell of the tape is.
+    pub cell_width: CellWidth,
+    /// The number of cells on the tape at the start.
+    /// It must be from 1 to 2^31, and a power of two with [`Bounds::Wrap`].
+    pub tape_size: usize,
+    /// What accessing a cell beyond either end of the tape does.
+    pub bounds: Bounds,
+    /// The number of steps the programme can run before ending with [`Error::LimitExceeded`].
+    /// Each iteration of a loop counts as a step, including those of loops the optimisations turn into scans.
+    pub max_steps: Option<u64>,
+    /// How long the programme can run before ending with [`Error::LimitExceeded`].
+    /// The time is only checked as steps are counted, so waiting for the input isn't interrupted.
+    pub timeout: Option<Duration>,
+    /// The handle to cancel the programme with from another thread, ending it with [`Error::Cancelled`].
+    pub cancel: Option<CancelHandle>,
+    /// Where `#` shows the pointer and the cells around it.
+    pub diagnostics: Diagnostics,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            buffering: Buffering::default(),
+            eof: EofMode::default(),
+            cell_width: CellWidth::default(),
+            tape_size: 1 << 16,
+            bounds: Bounds::default(),
+            max_steps: None,
+            timeout: None,
+            cancel: None,
+            diagnostics: Diagnostics::default(),
+        }
+    }
+}
+
+impl Options {
+    /// Returns an error if the tape can't be as large as [`Options::tape_size`].
+    #[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+    fn check(&self) -> Result<(), Error> {
+        let size = self.tape_size;
+        if size == 0
+            || size > MAX_TAPE_SIZE
+            || self.bounds == Bounds::Wrap && !size.is_power_of_two()
+        {
+            return Err(Error::TapeSize(size));
+        }
+        Ok(())
+    }
+
+    /// Returns whether the programme runs with [`Options::max_steps`] or [`Options::timeout`],
+    /// without which the JIT compilers leave out counting steps.
+    #[cfg(any(feature = "machine", feature = "asm"))]
+    pub(crate) fn limited(&self) -> bool {
+        self.max_steps.is_some() || self.timeout.is_some()
+    }
+}
+
+/// A handle to cancel a programme running on another thread with it in [`Options::cancel`].
+/// The engines check it at the end of each iteration of loops, so the programme ends soon after cancelling
+/// unless it is waiting for the input. Clones cancel the same programmes.
+///
+/// ```
+/// use brainf_ck::{ir::OptLevel, CancelHandle, Engine, Error, Options, Program};
+/// use std::{io, thread};
+///
+/// let program = Program::parse(b"+[]", OptLevel::O3)?;
+/// let cancel = CancelHandle::new();
+/// let options = Options {
+///     cancel: Some(cancel.clone()),
+///     ..Options::default()
+/// };
+/// let run = thread::spawn(move || program.run_with(Engine::Interpreter, &options, io::empty(), io::sink()));
+/// cancel.cancel();
+/// assert!(matches!(run.join().unwrap(), Err(Error::Cancelled)));
+/// # Ok::<(), brainf_ck::Error>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    /// Returns a handle which isn't cancelled yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the programmes running with this handle, and ones running with it later.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether this handle is cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Returns the flag for the machine code to read, which lives as long as the handle.
+    #[cfg(any(feature = "machine", feature = "asm"))]
+    pub(crate) fn flag(&self) -> *const bool {
+        self.0.as_ptr()
+    }
+}
+
+impl PartialEq for CancelHandle {
+    /// Handles are equal when they are clones of each other.
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for CancelHandle {}
+
+/// The writer in [`Options::diagnostics`], which is the stderr by default.
+/// It is shared so that the caller can keep it to read what is written after the programme ends.
+///
+/// ```
+/// use brainf_ck::{ir::OptLevel, Diagnostics, Engine, Options, Program};
+/// use std::{io, sync::{Arc, Mutex}};
+///
+/// let program = Program::parse_with(b"+#", OptLevel::O3, true)?;
+/// let diagnostics = Arc::new(Mutex::new(Vec::new()));
+/// let options = Options {
+///     diagnostics: Diagnostics::new(diagnostics.clone()),
+///     ..Options::default()
+/// };
+/// program.run_with(Engine::Interpreter, &options, io::empty(), io::sink())?;
+/// assert!(diagnostics.lock().unwrap().starts_with(b"# at 1, pointer 0: [0:1] 1:0"));
+/// # Ok::<(), brainf_ck::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct Diagnostics(Arc<Mutex<dyn Write + Send>>);
+
+impl Diagnostics {
+    /// Returns the writer writing into `writer`.
+    pub fn new(writer: Arc<Mutex<impl Write + Send + 'static>>) -> Self {
+        Self(writer)
+    }
+
+    /// Locks the writer, which stays usable even if writing into it panicked.
+    #[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+    pub(crate) fn lock(&self) -> MutexGuard<'_, dyn Write + Send + 'static> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Self::new(Arc::new(Mutex::new(io::stderr())))
+    }
+}
+
+impl fmt::Debug for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Diagnostics").finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for Diagnostics {
+    /// Writers are equal when they are clones of each other.
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Diagnostics {}
+
+/// The steps a programme can still run under [`Options::max_steps`] and [`Options::timeout`].
+/// They are handed out to the engines in chunks, so that the time is checked once in a while.
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+pub(crate) struct Budget {
+    /// The steps left which aren't handed out yet, along with the limit.
+    steps: Option<(u64, u64)>,
+    /// When the programme must end by, along with the timeout.
+    deadline: Option<(Instant, Duration)>,
+}
+
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+impl Budget {
+    /// The most steps handed out at once, between which the time is checked.
+    pub(crate) const CHUNK: u64 = 1 << 16;
+
+    /// Starts the budget for a run with `options`, from which the timeout counts.
+    pub(crate) fn new(options: &Options) -> Self {
+        let now = Instant::now();
+        Self {
+            steps: options.max_steps.map(|steps| (steps, steps)),
+            // A timeout too far away to represent never comes.
+            deadline: options
+                .timeout
+                .and_then(|timeout| Some((now.checked_add(timeout)?, timeout))),
+        }
+    }
+
+    /// A budget which never runs out, for the debugger where the programme waits for the commands.
+    #[cfg(feature = "interpreter")]
+    pub(crate) fn unlimited() -> Self {
+        Self {
+            steps: None,
+            deadline: None,
+        }
+    }
+
+    /// Hands out the next steps to run, which are at most [`Budget::CHUNK`],
+    /// or returns the error if there are none left or the time is up.
+    pub(crate) fn next(&mut self) -> Result<u64, Error> {
+        if let Some((deadline, timeout)) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(Error::LimitExceeded(Limit::Time(timeout)));
+            }
+        }
+        match &mut self.steps {
+            None => Ok(Self::CHUNK),
+            Some((0, limit)) => Err(Error::LimitExceeded(Limit::Steps(*limit))),
+            Some((left, _)) => {
+                let chunk = (*left).min(Self::CHUNK);
+                *left -= chunk;
+                Ok(chunk)
+            }
+        }
+    }
+}
+
+/// The largest number of cells on the tape, so that the JIT compilers can index them with 32 bit registers.
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+pub(crate) const MAX_TAPE_SIZE: usize = 1 << 31;
+
+/// What accessing a cell beyond either end of the tape does.
+/// Only accessing a cell counts, so the pointer can be off the tape as long as it comes back before accessing one.
+///
+/// The optimisations only remove accesses that nothing observes, such as changes to cells at the end of the programme,
+/// with [`Bounds::Wrap`]. A loop replaced by an optimisation reports errors at the start of the loop.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Bounds {
+    /// Wraps the pointer around the tape, so that the cell on the left of the first one is the last one.
+    #[default]
+    Wrap,
+    /// Ends the programme with [`Error::OutOfBounds`].
+    Error,
+    /// Extends the tape on the right with zero cells, at least doubling it.
+    /// The cells on the left of the first one are still out of bounds,
+    /// as are ones beyond 2^31 cells, which end the programme the same way as [`Bounds::Error`].
+    Grow,
+    /// Ends the programme with [`Error::OutOfBounds`] the same way as [`Bounds::Error`],
+    /// but the JIT compilers on Linux leave the check to the OS by putting pages that can't be accessed around the tape.
+    /// It saves the comparison on each access, but reserves 2^32 cells of address space.
+    /// The interpreter and the JIT compilers on other OSes check each access instead.
+    Guard,
+}
+
+impl Bounds {
+    /// Returns the number of cells a tape of `len` cells needs to access the cell `index`,
+    /// or the error for accessing it at `position`. Indices on the left of the tape have wrapped around to be huge.
+    #[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+    pub(crate) fn reach(self, len: usize, index: usize, position: usize) -> Result<usize, Error> {
+        match self {
+            Self::Grow if index < MAX_TAPE_SIZE => {
+                Ok(len.saturating_mul(2).clamp(index + 1, MAX_TAPE_SIZE))
+            }
+            _ => Err(Error::OutOfBounds {
+                position,
+                index: index as isize as i64,
+            }),
+        }
+    }
+}
+
+impl FromStr for Bounds {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, &'static str> {
+        match s {
+            "wrap" => Ok(Self::Wrap),
+            "error" => Ok(Self::Error),
+            "guard" => Ok(Self::Guard),
+            "grow" => Ok(Self::Grow),
+            _ => Err("Invalid bounds policy"),
+        }
+    }
+}
+
+/// How wide each cell of the tape is. Cells wrap around on overflow at any width.
+///
+/// The output takes the lowest 8 bits of a cell, and the input sets a cell to the byte read,
+/// zero-extended. [`EofMode::MinusOne`] sets all the bits of a cell.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CellWidth {
+    #[default]
+    U8,
+    U16,
+    U32,
+}
+
+impl CellWidth {
+    /// Returns the number of bytes in a cell.
+    #[cfg(any(feature = "machine", feature = "asm"))]
+    pub(crate) fn bytes(self) -> usize {
+        1 << self.shift()
+    }
+
+    /// Returns the shift from the index of a cell to its offset in bytes.
+    #[cfg(any(feature = "machine", feature = "asm"))]
+    pub(crate) fn shift(self) -> u32 {
+        match self {
+            Self::U8 => 0,
+            Self::U16 => 1,
+            Self::U32 => 2,
+        }
+    }
+
+    /// Truncates `value` to the width, as amounts in [`ir::NodeKind`] wrap around the same way at any width.
+    #[cfg(all(target_arch = "aarch64", any(feature = "machine", feature = "asm")))]
+    pub(crate) fn truncate(self, value: i32) -> u32 {
+        match self {
+            Self::U8 => value as u8 as u32,
+            Self::U16 => value as u16 as u32,
+            Self::U32 => value as u32,
+        }
+    }
+}
+
+impl FromStr for CellWidth {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, &'static str> {
+        match s {
+            "8" => Ok(Self::U8),
+            "16" => Ok(Self::U16),
+            "32" => Ok(Self::U32),
+            _ => Err("Invalid cell width"),
+        }
+    }
+}
+
+/// The type of cells of a [`CellWidth`], so that engines can be generic over the width.
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+pub(crate) trait Cell: Copy + Eq + From<u8> + Into<u32> + fmt::Display {
+    const MAX: Self;
+
+    /// Truncates `value` to the cell, as amounts in [`ir::NodeKind`] wrap around the same way at any width.
+    #[cfg(feature = "interpreter")]
+    fn truncate(value: i32) -> Self;
+
+    /// Returns the lowest 8 bits, which is what the output takes.
+    #[cfg(feature = "interpreter")]
+    fn low_byte(self) -> u8;
+
+    #[cfg(feature = "interpreter")]
+    fn wrapping_add(self, other: Self) -> Self;
+
+    #[cfg(feature = "interpreter")]
+    fn wrapping_mul(self, other: Self) -> Self;
+
+    /// Returns the index of the first zero cell in `cells`.
+    #[cfg(any(feature = "interpreter", target_arch = "aarch64"))]
+    fn find_zero(cells: &[Self]) -> Option<usize> {
+        cells.iter().position(|&cell| cell == Self::from(0))
+    }
+
+    /// Returns the index of the last zero cell in `cells`.
+    #[cfg(any(feature = "interpreter", target_arch = "aarch64"))]
+    fn rfind_zero(cells: &[Self]) -> Option<usize> {
+        cells.iter().rposition(|&cell| cell == Self::from(0))
+    }
+}
+
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+macro_rules! cell {
+    ($($ty:ty { $($item:item)* })*) => {$(
+        impl Cell for $ty {
+            const MAX: Self = <$ty>::MAX;
+
+            #[cfg(feature = "interpreter")]
+            fn truncate(value: i32) -> Self {
+                value as Self
+            }
+
+            #[cfg(feature = "interpreter")]
+            fn low_byte(self) -> u8 {
+                self as u8
+            }
+
+            #[cfg(feature = "interpreter")]
+            fn wrapping_add(self, other: Self) -> Self {
+                <$ty>::wrapping_add(self, other)
+            }
+
+            #[cfg(feature = "interpreter")]
+            fn wrapping_mul(self, other: Self) -> Self {
+                <$ty>::wrapping_mul(self, other)
+            }
+
+            $($item)*
+        }
+    )*};
+}
+
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+cell! {
+    u8 {
+        // `memchr` uses SIMD, which only works on bytes.
+        #[cfg(any(feature = "interpreter", target_arch = "aarch64"))]
+        fn find_zero(cells: &[Self]) -> Option<usize> {
+            memchr::memchr(0, cells)
+        }
+
+        #[cfg(any(feature = "interpreter", target_arch = "aarch64"))]
+        fn rfind_zero(cells: &[Self]) -> Option<usize> {
+            memchr::memrchr(0, cells)
+        }
+    }
+    u16 {}
+    u32 {}
+}
+
+/// What reading at the end of the input does, as programmes expect one of a few conventions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EofMode {
+    /// Sets the cell to 0.
+    #[default]
+    Zero,
+    /// Leaves the cell as it is.
+    Unchanged,
+    /// Sets the cell to -1, which is 255 as a byte and all the bits set at any width.
+    MinusOne,
+    /// Ends the programme with an io error of [`io::ErrorKind::UnexpectedEof`].
+    Error,
+}
+
+impl FromStr for EofMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, &'static str> {
+        match s {
+            "zero" => Ok(Self::Zero),
+            "unchanged" => Ok(Self::Unchanged),
+            "minus-one" => Ok(Self::MinusOne),
+            "error" => Ok(Self::Error),
+            _ => Err("Invalid EOF mode"),
+        }
+    }
+}
+
+/// When the output of a programme is written through to the writer.
+/// It is also written through before reading the input, so that prompts appear,
+/// and when the programme ends, whether or not it succeeds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Buffering {
+    /// After every byte.
+    None,
+    /// After every newline, which suits terminals.
+    Line,
+    /// Only when the buffer fills up.
+    #[default]
+    Full,
+}
+
+/// Buffers the output of a programme as its [`Buffering`] says.
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+struct Output<W> {
+    writer: W,
+    buffer: Vec<u8>,
+    buffering: Buffering,
+}
+
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+impl<W> Output<W> {
+    /// The size of the buffer to write through at with [`Buffering::Full`].
+    const CAPACITY: usize = 8 * 1024;
+}
+
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+impl<W: Write> Write for Output<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        let through = match self.buffering {
+            Buffering::None => true,
+            Buffering::Line => buf.contains(&b'\n'),
+            Buffering::Full => self.buffer.len() >= Self::CAPACITY,
+        };
+        if through {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Drop the buffer even if writing fails, so that nothing is written twice.
+        let result = self.writer.write_all(&self.buffer);
+        self.buffer.clear();
+        result?;
+        self.writer.flush()
+    }
+}
+
+/// The ways a [`Program`] can run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Engine {
+    /// Interprets the programme, which works everywhere.
+    #[cfg(feature = "interpreter")]
+    Interpreter,
+    /// Compiles the programme into machine code written by hand.
+    #[cfg(feature = "machine")]
+    Machine,
+    /// Compiles the programme into machine code with `dynasm-rs`.
+    #[cfg(feature = "asm")]
+    Asm,
+}
+
+impl FromStr for Engine {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, &'static str> {
+        match s {
+            #[cfg(feature = "interpreter")]
+            "interpreter" => Ok(Self::Interpreter),
+            #[cfg(feature = "machine")]
+            "machine" => Ok(Self::Machine),
+            #[cfg(feature = "asm")]
+            "asm" => Ok(Self::Asm),
+            _ => Err("Invalid engine type"),
+        }
+    }
+}
+
+/// Writes `byte` into `writer`.
+/// A few advantages of this over directly using `libstd`:
+///
+/// - a more convinient API to write only one byte.
+/// - converting "\n" to "\r\n" in Windows.
+///
+/// Flushing is left to `writer`, which [`Program::run_with`] buffers as the [`Options`] say.
+#[inline(always)]
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+pub(crate) fn putchar(writer: &mut (impl Write + ?Sized), byte: &u8) -> io::Result<()> {
+    if cfg!(windows) && *byte == b'\n' {
+        writer.write_all(b"\r\n")
+    } else {
+        writer.write_all(array::from_ref(byte))
+    }
+}
+
+/// Reads one byte from `reader` and writes it to `cell`, or does what `eof` says at EOF.
+/// A few advantages of this over directly using `libstd`:
+///
+/// - a more convinient API to read only one byte.
+/// - skipping "\r" in Windows to make "\n" a single newline sequence.
+/// - flushing `writer` first, so that a prompt appears before waiting for the answer.
+#[inline(always)]
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+pub(crate) fn getchar<C: Cell>(
+    reader: &mut (impl Read + ?Sized),
+    writer: &mut (impl Write + ?Sized),
+    eof: EofMode,
+    cell: &mut C,
+) -> io::Result<()> {
+    writer.flush()?;
+    // Read into another byte as `cell` must stay as it is on EOF with `EofMode::Unchanged`.
+    let mut read = 0;
+    let res = reader.read_exact(array::from_mut(&mut read));
+
+    match res {
+        Ok(_) => {
+            if cfg!(windows) && read == b'\r' {
+                // We're assuming there's '\n' after '\r'. Even if there isn't, this skips '\r'.
+                // Also, we call `UnexpectedEof` an error too. Basically, anything other than "\r\n" is unexpected.
+                reader.read_exact(array::from_mut(&mut read))?;
+            }
+            *cell = C::from(read);
+        }
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => match eof {
+            EofMode::Zero => *cell = C::from(0),
+            EofMode::Unchanged => {}
+            EofMode::MinusOne => *cell = C::MAX,
+            EofMode::Error => return Err(e),
+        },
+        r @ Err(_) => return r,
+    };
+
+    Ok(())
+}
+
+/// Finds the nearest zero cell from `pointer` in `tape`, moving by `stride` cells and wrapping around.
+/// Returns `None` if the pointer never stops at a zero cell.
+///
+/// The length of `tape` must be a power of two, and `pointer` must be within it.
+#[inline(always)]
+#[cfg(any(feature = "interpreter", target_arch = "aarch64"))]
+pub(crate) fn scan<C: Cell>(tape: &[C], pointer: usize, stride: i32) -> Option<usize> {
+    debug_assert!(tape.len().is_power_of_two());
+    let mask = tape.len() - 1;
+
+    match stride {
+        1 => C::find_zero(&tape[pointer..])
+            .map(|i| pointer + i)
+            .or_else(|| C::find_zero(&tape[..pointer])),
+        -1 => C::rfind_zero(&tape[..=pointer])
+            .or_else(|| C::rfind_zero(&tape[pointer + 1..]).map(|i| pointer + 1 + i)),
+        _ => {
+            // The pointer visits every cell it can reach within as many moves as the cells.
+            let mut pointer = pointer;
+            (0..tape.len()).find_map(|_| {
+                let found = (tape[pointer] == C::from(0)).then_some(pointer);
+                pointer = pointer.wrapping_add_signed(stride as isize) & mask;
+                found
+            })
+        }
+    }
+}
+
+/// The number of cells on either side of the pointer shown by `#` and the debugger.
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+pub(crate) const RADIUS: usize = 8;
+
+/// Writes the pointer and the cells around it into `diagnostics` for `#` at `position` in the source,
+/// after flushing `writer` so that they come in order with the output on a terminal.
+/// The pointer is on the left of the tape when it has wrapped around to be huge.
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+pub(crate) fn dump<C: Cell>(
+    writer: &mut (impl Write + ?Sized),
+    diagnostics: &Diagnostics,
+    cells: &[C],
+    pointer: usize,
+    position: usize,
+) -> io::Result<()> {
+    writer.flush()?;
+    let window = window(cells, pointer, RADIUS);
+    writeln!(
+        diagnostics.lock(),
+        "# at {position}, pointer {}: {}",
+        pointer as isize,
+        window.as_deref().unwrap_or("off the tape")
+    )
+}
+
+/// Returns the cells within `radius` of `pointer` in `cells` along with their indices, with the one at it in brackets,
+/// or `None` if there are none, as the pointer is far off the tape.
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+pub(crate) fn window<C: Cell>(cells: &[C], pointer: usize, radius: usize) -> Option<String> {
+    let pointer = pointer as isize;
+    let start = pointer.saturating_sub_unsigned(radius).max(0);
+    let end = pointer
+        .saturating_add_unsigned(radius)
+        .min(cells.len() as isize - 1);
+    let window: Vec<_> = (start..=end)
+        .map(|index| match cells[index as usize] {
+            cell if index == pointer => format!("[{index}:{cell}]"),
+            cell => format!("{index}:{cell}"),
+        })
+        .collect();
+    (!window.is_empty()).then(|| window.join(" "))
+}
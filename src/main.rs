@@ -1,163 +1,579 @@
-#![warn(unsafe_op_in_unsafe_fn)]
-
-#[cfg(feature = "interpreter")]
-mod interpreter;
-#[cfg(any(feature = "asm", feature = "machine"))]
-mod jit;
+#[cfg(feature = "c")]
+use brainf_ck::c;
+#[cfg(any(feature = "machine", feature = "asm"))]
+use brainf_ck::jit;
+#[cfg(feature = "wasm")]
+use brainf_ck::wasm;
+use brainf_ck::{
+    ir,
+    trace::{self, Granularity},
+    Bounds, CellWidth, Engine, EofMode, Error, Limit, Location, Options, Program,
+};
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+use brainf_ck::{Buffering, Diagnostics};
 
 use argh::FromArgs;
+use std::{io, process::ExitCode, str::FromStr, time::Duration};
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
 use std::{
-    array,
-    io::{self, Read, Write},
-    str::FromStr,
+    io::IsTerminal,
+    sync::{Arc, Mutex},
 };
-use thiserror::Error;
 
-pub(crate) trait Consumer {
-    fn consume_while(&mut self, target: u8) -> usize;
+enum EmitType {
+    #[cfg(all(feature = "machine", target_arch = "x86_64"))]
+    Exe,
+    #[cfg(feature = "asm")]
+    Asm,
+    #[cfg(any(feature = "machine", feature = "asm"))]
+    Bin,
+    #[cfg(feature = "c")]
+    C,
+    #[cfg(feature = "wasm")]
+    Wasm,
+    #[cfg(feature = "wasm")]
+    Wat,
 }
 
-impl Consumer for std::slice::Iter<'_, u8> {
-    #[inline(always)]
-    fn consume_while(&mut self, target: u8) -> usize {
-        // Calculate the span of continuous copies of the target.
-        let span = self.clone().take_while(|c| **c == target).count();
+impl FromStr for EmitType {
+    type Err = &'static str;
 
-        // FIXME: unstable `Iterator::advance_by` is better.
-        // Need https://github.com/rust-lang/rust/issues/77404
-        if span != 0 {
-            self.nth(span - 1);
+    fn from_str(s: &str) -> Result<Self, &'static str> {
+        match s {
+            #[cfg(all(feature = "machine", target_arch = "x86_64"))]
+            "exe" => Ok(Self::Exe),
+            #[cfg(feature = "asm")]
+            "asm" => Ok(Self::Asm),
+            #[cfg(any(feature = "machine", feature = "asm"))]
+            "bin" => Ok(Self::Bin),
+            #[cfg(feature = "c")]
+            "c" => Ok(Self::C),
+            #[cfg(feature = "wasm")]
+            "wasm" => Ok(Self::Wasm),
+            #[cfg(feature = "wasm")]
+            "wat" => Ok(Self::Wat),
+            _ => Err("Invalid emit type"),
         }
-        // self.advance_by(span);
-        return span;
     }
 }
 
-#[derive(Error, Debug)]
-pub enum Error {
-    #[error("unmatched [")]
-    UnmatchedLeft,
-    #[error("unmatched ]")]
-    UnmatchedRight,
-    #[error("io-error during execution")]
-    Io(#[from] io::Error),
+/// How errors are written to the stderr.
+#[derive(Clone, Copy)]
+enum ErrorFormat {
+    /// A message for people, with an excerpt of the source for brackets that don't match.
+    Text,
+    /// A JSON object on a line.
+    Json,
 }
 
-/// Writes `byte` into the stdout.
-/// A few advantages of this over directly using `libstd`:
-///
-/// - a more convinient API to write only one byte.
-/// - converting "\n" to "\r\n" in Windows.
-#[inline(always)]
-pub(crate) fn putchar(byte: &u8) -> io::Result<()> {
-    #[cfg(test)]
-    return test::OUT.with(|writer| inner(&mut *writer.borrow_mut(), byte));
-    #[cfg(not(test))]
-    {
-        let mut writer = io::stdout();
-        inner(&mut writer, byte)?;
-        return writer.flush();
-    }
+impl FromStr for ErrorFormat {
+    type Err = &'static str;
 
-    fn inner(writer: &mut impl Write, byte: &u8) -> io::Result<()> {
-        if cfg!(windows) && *byte == b'\n' {
-            writer.write_all(&[b'\r', b'\n'])
-        } else {
-            writer.write_all(array::from_ref(byte))
+    fn from_str(s: &str) -> Result<Self, &'static str> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err("Invalid error format"),
         }
     }
 }
 
-/// Reads one byte from the stdin and writes it to `byte`.
-/// A few advantages of this over directly using `libstd`:
-///
-/// - a more convinient API to read only one byte.
-/// - skipping "\r" in Windows to make "\n" a single newline sequence.
-#[inline(always)]
-pub(crate) fn getchar(byte: &mut u8) -> io::Result<()> {
-    #[cfg(test)]
-    return test::IN.with(|reader| inner(&mut *reader.borrow_mut(), byte));
-    #[cfg(not(test))]
-    return inner(&mut io::stdin(), byte);
-
-    fn inner(reader: &mut impl Read, byte: &mut u8) -> io::Result<()> {
-        let res = reader.read_exact(array::from_mut(byte));
-
-        match res {
-            Ok(_) => {
-                if cfg!(windows) && *byte == b'\r' {
-                    // We're assuming there's '\n' after '\r'. Even if there isn't, this skips '\r'.
-                    // Also, we call `UnexpectedEof` an error too. Basically, anything other than "\r\n" is unexpected.
-                    reader.read_exact(array::from_mut(byte))?;
+#[derive(FromArgs)]
+/// A brainf*ck language compiler and interpreter
+#[argh(
+    error_code(1, "The options are invalid."),
+    error_code(2, "Reading the source file failed."),
+    error_code(3, "The brackets in the source don't match."),
+    error_code(4, "Reading the input or writing the output failed."),
+    error_code(5, "The programme went beyond the tape."),
+    error_code(6, "The programme ran beyond --max-steps or --timeout.")
+)]
+struct BrainFck {
+    /// a brainf*ck source file to run
+    #[argh(positional)]
+    filename: String,
+
+    /// an engine type: either "interpreter", "machine" or "asm"
+    #[argh(option)]
+    engine: Option<Engine>,
+
+    /// write the compiled programme out instead of running it:
+    /// "exe" for an executable for Linux on x86_64, "asm" for a listing of the templates
+    /// the asm engine assembles from, with Rust expressions as operands, followed by the bytes,
+    /// or "bin" for the raw machine code of either the machine or asm engine given by --engine
+    /// (defaults to asm) to disassemble with a tool such as objdump,
+    /// "c" for the source of a C programme, or "wasm" and "wat" for a WebAssembly module
+    /// in the binary and the text format. The programmes written out have the default options for running them
+    #[argh(option)]
+    emit: Option<EmitType>,
+
+    /// a file to write into with --emit (defaults to "a.out" for executables and the stdout otherwise)
+    #[argh(option, short = 'o')]
+    output: Option<String>,
+
+    /// an optimisation level from 0 to 3 (defaults to 3)
+    #[argh(option, short = 'O', default = "ir::OptLevel::O3")]
+    opt_level: ir::OptLevel,
+
+    /// write the output through after every byte, instead of at newlines on terminals
+    /// or when the buffer fills up otherwise
+    #[argh(switch)]
+    unbuffered: bool,
+
+    /// what reading at the end of the input does: "zero" to set the cell to 0 (the default),
+    /// "unchanged" to leave it, "minus-one" to set all its bits, or "error" to end the programme
+    #[argh(option, default = "EofMode::Zero")]
+    eof: EofMode,
+
+    /// how wide each cell is: 8 (the default), 16 or 32 bits
+    #[argh(option, default = "CellWidth::U8")]
+    cell_bits: CellWidth,
+
+    /// the number of cells on the tape (defaults to 65536), which must be a power of two with --bounds wrap
+    #[argh(option, default = "1 << 16")]
+    tape_size: usize,
+
+    /// what accessing a cell beyond either end of the tape does: "wrap" to wrap around (the default),
+    /// "error" to end the programme with where it happens, "grow" to extend the tape to the right,
+    /// or "guard" to end it the same as "error" but with guard pages instead of checks in the JITs on Linux
+    #[argh(option, default = "Bounds::Wrap")]
+    bounds: Bounds,
+
+    /// the number of steps the programme can run before it is ended, where each iteration of a loop
+    /// counts as a step
+    #[argh(option)]
+    max_steps: Option<u64>,
+
+    /// the number of seconds the programme can run before it is ended, which can be fractional
+    #[argh(option, from_str_fn(seconds))]
+    timeout: Option<Duration>,
+
+    /// how errors are written to the stderr: "text" (the default), or "json" for a JSON object on a line
+    #[argh(option, default = "ErrorFormat::Text")]
+    error_format: ErrorFormat,
+
+    /// run the programme on the interpreter under a debugger, which reads commands from the stdin
+    /// a line at a time and writes its prompt to the stderr; "help" lists the commands.
+    /// The programme reads the stdin too, after the command running it
+    #[argh(switch)]
+    debug: bool,
+    /// make "#" show the pointer and the cells around it on the stderr, instead of being a comment
+    #[argh(switch)]
+    debug_char: bool,
+
+    /// write a trace of the programme into the file in a compact binary format, with the byte offset in the source,
+    /// the instruction, the pointer and the cell for each instruction the interpreter runs,
+    /// or only for the conditions of loops and the end with the JITs
+    #[argh(option)]
+    trace: Option<String>,
+
+    /// with --trace, make the interpreter only write the records the JITs write, to compare it with them.
+    /// With --show-trace, only show those records
+    #[argh(switch)]
+    trace_blocks: bool,
+
+    /// show the trace in the file given, instead of a source file, as text with a line for each record
+    #[argh(switch)]
+    show_trace: bool,
+}
+
+/// What ends the command unsuccessfully.
+enum Failure {
+    /// The options are invalid.
+    Usage(&'static str),
+    /// Reading the source file failed.
+    Source(io::Error),
+    /// Parsing the programme, running it or writing it out failed.
+    Program(Error),
+}
+
+impl Failure {
+    /// Returns the exit status, which tells the kinds of failures apart.
+    fn status(&self) -> u8 {
+        match self {
+            Self::Usage(_) | Self::Program(Error::TapeSize(_)) => 1,
+            Self::Source(_) => 2,
+            Self::Program(Error::UnmatchedLeft(_) | Error::UnmatchedRight(_)) => 3,
+            Self::Program(Error::Io(_)) => 4,
+            Self::Program(Error::OutOfBounds { .. }) => 5,
+            // The command doesn't cancel programmes, but they end early the same way as with the limits.
+            Self::Program(Error::LimitExceeded(_) | Error::Cancelled) => 6,
+        }
+    }
+
+    /// Writes the failure to the stderr in `format`, where `source` is the content of the file `filename`,
+    /// and returns the exit status.
+    fn report(&self, format: ErrorFormat, filename: &str, source: &[u8]) -> ExitCode {
+        match format {
+            ErrorFormat::Text => match self {
+                Self::Usage(message) => eprintln!("{message}"),
+                Self::Source(_) => eprintln!("io-error while reading the file"),
+                Self::Program(e) => {
+                    eprintln!("{e}");
+                    if let Error::UnmatchedLeft(location) | Error::UnmatchedRight(location) = e {
+                        eprintln!("{}", excerpt(filename, source, *location));
+                    }
                 }
+            },
+            ErrorFormat::Json => eprintln!("{}", self.json(filename)),
+        }
+        ExitCode::from(self.status())
+    }
+
+    /// Returns the failure as a JSON object, with `kind` telling what it is and `message` for people to read,
+    /// along with the fields for the kind.
+    fn json(&self, filename: &str) -> String {
+        let (kind, message, mut fields) = match self {
+            Self::Usage(message) => ("usage", message.to_string(), Vec::new()),
+            Self::Source(e) => (
+                "source",
+                "io-error while reading the file".to_string(),
+                vec![
+                    ("file", json_string(filename)),
+                    ("error", json_string(&e.to_string())),
+                ],
+            ),
+            Self::Program(e) => {
+                let (kind, fields) = match e {
+                    Error::UnmatchedLeft(location) | Error::UnmatchedRight(location) => {
+                        let kind = if let Error::UnmatchedLeft(_) = e {
+                            "unmatched-left"
+                        } else {
+                            "unmatched-right"
+                        };
+                        let fields = vec![
+                            ("file", json_string(filename)),
+                            ("position", location.position.to_string()),
+                            ("line", location.line.to_string()),
+                            ("column", location.column.to_string()),
+                        ];
+                        (kind, fields)
+                    }
+                    Error::Io(e) => ("io", vec![("error", json_string(&e.to_string()))]),
+                    Error::OutOfBounds { position, index } => (
+                        "out-of-bounds",
+                        vec![
+                            ("position", position.to_string()),
+                            ("index", index.to_string()),
+                        ],
+                    ),
+                    Error::TapeSize(size) => ("tape-size", vec![("size", size.to_string())]),
+                    Error::LimitExceeded(Limit::Steps(steps)) => (
+                        "limit-exceeded",
+                        vec![
+                            ("limit", json_string("steps")),
+                            ("steps", steps.to_string()),
+                        ],
+                    ),
+                    Error::LimitExceeded(Limit::Time(timeout)) => (
+                        "limit-exceeded",
+                        vec![
+                            ("limit", json_string("time")),
+                            ("seconds", timeout.as_secs_f64().to_string()),
+                        ],
+                    ),
+                    Error::Cancelled => ("cancelled", Vec::new()),
+                };
+                (kind, e.to_string(), fields)
             }
-            // The value of `buf` is "unspecified" when `UnexpectedEof` happens,
-            // Make sure it is 0 to be consistent.
-            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => *byte = 0,
-            r @ Err(_) => return r,
         };
-
-        Ok(())
+        fields.splice(
+            0..0,
+            [
+                ("kind", json_string(kind)),
+                ("message", json_string(&message)),
+                ("status", self.status().to_string()),
+            ],
+        );
+        let fields: Vec<_> = fields
+            .iter()
+            .map(|(name, value)| format!("{}:{value}", json_string(name)))
+            .collect();
+        format!("{{{}}}", fields.join(","))
     }
 }
 
-enum EngineType {
-    #[cfg(feature = "interpreter")]
-    Interpreter,
-    #[cfg(feature = "machine")]
-    Machine,
-    #[cfg(feature = "asm")]
-    Asm,
+/// Parses `value` as a number of seconds for --timeout.
+fn seconds(value: &str) -> Result<Duration, String> {
+    value
+        .parse()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| "Invalid timeout".to_string())
 }
 
-impl FromStr for EngineType {
-    type Err = &'static str;
+/// Returns `text` as a JSON string, quoted and escaped.
+fn json_string(text: &str) -> String {
+    let mut json = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c < ' ' => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
 
-    fn from_str(s: &str) -> Result<Self, &'static str> {
-        match s {
+fn main() -> ExitCode {
+    let BrainFck {
+        filename,
+        engine,
+        emit,
+        output,
+        opt_level,
+        unbuffered,
+        eof,
+        cell_bits,
+        tape_size,
+        bounds,
+        max_steps,
+        timeout,
+        error_format,
+        debug,
+        debug_char,
+        trace,
+        trace_blocks,
+        show_trace,
+    } = argh::from_env();
+    let granularity = if trace_blocks {
+        Granularity::Blocks
+    } else {
+        Granularity::Instructions
+    };
+    if trace_blocks && trace.is_none() && !show_trace {
+        let failure = Failure::Usage("--trace-blocks only works with --trace or --show-trace");
+        return failure.report(error_format, &filename, &[]);
+    }
+    if show_trace {
+        if engine.is_some() || emit.is_some() || debug || trace.is_some() {
+            let failure = Failure::Usage("--show-trace only shows a trace without running it");
+            return failure.report(error_format, &filename, &[]);
+        }
+        let bytes = match std::fs::read(&filename) {
+            Ok(bytes) => bytes,
+            Err(e) => return Failure::Source(e).report(error_format, &filename, &[]),
+        };
+        return match trace::print(&bytes[..], io::stdout().lock(), granularity) {
+            Ok(()) => ExitCode::SUCCESS,
+            // Reading the bytes only fails when the file isn't a trace, which is about the file as failing to read it.
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                Failure::Source(e).report(error_format, &filename, &[])
+            }
+            Err(e) => Failure::Program(e.into()).report(error_format, &filename, &[]),
+        };
+    }
+    if engine.is_none() && emit.is_none() && !debug {
+        let failure = Failure::Usage("either --engine or --emit is required");
+        return failure.report(error_format, &filename, &[]);
+    }
+    #[cfg(all(feature = "interpreter", any(feature = "machine", feature = "asm")))]
+    if let (Some(EmitType::Bin), Some(Engine::Interpreter)) = (&emit, &engine) {
+        let failure = Failure::Usage("the interpreter doesn't generate machine code to emit");
+        return failure.report(error_format, &filename, &[]);
+    }
+    #[cfg(feature = "interpreter")]
+    let interpreter = engine.is_none_or(|engine| engine == Engine::Interpreter);
+    #[cfg(not(feature = "interpreter"))]
+    let interpreter = false;
+    if debug && (emit.is_some() || !interpreter) {
+        let failure = Failure::Usage("--debug only runs the programme on the interpreter");
+        return failure.report(error_format, &filename, &[]);
+    }
+    if trace.is_some() && (emit.is_some() || debug) {
+        let failure = Failure::Usage("--trace only works with --engine");
+        return failure.report(error_format, &filename, &[]);
+    }
+    let defaults = Options::default();
+    if emit.is_some()
+        && (unbuffered
+            || eof != defaults.eof
+            || cell_bits != defaults.cell_width
+            || tape_size != defaults.tape_size
+            || bounds != defaults.bounds
+            || max_steps.is_some()
+            || timeout.is_some()
+            || debug_char)
+    {
+        let failure =
+            Failure::Usage("--emit writes programmes with the default options for running them");
+        return failure.report(error_format, &filename, &[]);
+    }
+    let source = match std::fs::read(&filename) {
+        Ok(source) => source,
+        Err(e) => return Failure::Source(e).report(error_format, &filename, &[]),
+    };
+
+    let res = Program::parse_with(&source, opt_level, debug_char).and_then(|program| {
+        // Only running the programme takes the options.
+        #[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+        let (stdout, options) = {
+            let stdout = io::stdout();
+            let buffering = if unbuffered {
+                Buffering::None
+            } else if stdout.is_terminal() {
+                Buffering::Line
+            } else {
+                Buffering::Full
+            };
+            let options = Options {
+                buffering,
+                eof,
+                cell_width: cell_bits,
+                tape_size,
+                bounds,
+                max_steps,
+                timeout,
+                cancel: None,
+                diagnostics: Diagnostics::new(Arc::new(Mutex::new(io::stderr()))),
+            };
+            (stdout, options)
+        };
+        match (emit, engine) {
+            (Some(emit), engine) => write(emit, engine, &program, output.as_deref()),
             #[cfg(feature = "interpreter")]
-            "interpreter" => Ok(Self::Interpreter),
-            #[cfg(feature = "machine")]
-            "machine" => Ok(Self::Machine),
-            #[cfg(feature = "asm")]
-            "asm" => Ok(Self::Asm),
-            _ => Err("Invalid engine type"),
+            (None, _) if debug => program.debug(
+                &options,
+                io::stdin().lock(),
+                stdout.lock(),
+                io::stderr().lock(),
+            ),
+            #[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+            (None, Some(engine)) => match &trace {
+                Some(trace) => program.trace(
+                    engine,
+                    &options,
+                    granularity,
+                    io::stdin().lock(),
+                    stdout.lock(),
+                    std::fs::File::create(trace)?,
+                ),
+                None => program.run_with(engine, &options, io::stdin().lock(), stdout.lock()),
+            },
+            (None, None) => unreachable!(),
         }
+    });
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => Failure::Program(e).report(error_format, &filename, &source),
     }
 }
 
-#[derive(FromArgs)]
-/// A brainf*ck language compiler and interpreter
-struct BrainFck {
-    /// a brainf*ck source file to run
-    #[argh(positional)]
-    filename: String,
+/// Returns the line of `source` at `location` with a caret under the character, which is in the file `filename`.
+/// Long lines are cut down to the characters around it.
+fn excerpt(filename: &str, source: &[u8], location: Location) -> String {
+    /// The number of characters shown on either side of the one at `location`.
+    const CONTEXT: usize = 40;
 
-    /// an engine type: either "interpreter", "machine" or "asm"
-    #[argh(option)]
-    engine: EngineType,
-}
+    let start = source[..location.position]
+        .iter()
+        .rposition(|&byte| byte == b'\n')
+        .map_or(0, |newline| newline + 1);
+    let end = source[location.position..]
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .map_or(source.len(), |newline| location.position + newline);
+    let line: Vec<char> = String::from_utf8_lossy(&source[start..end])
+        .trim_end_matches('\r')
+        .chars()
+        .collect();
 
-fn main() {
-    let BrainFck { filename, engine } = argh::from_env();
-    let Ok(program) = std::fs::read(filename) else {
-        eprintln!("io-error while reading the file");
-        return;
-    };
+    let column = location.column - 1;
+    let (from, to) = (
+        column.saturating_sub(CONTEXT),
+        line.len().min(column + CONTEXT + 1),
+    );
+    let mut text = String::new();
+    let mut padding = String::new();
+    if from > 0 {
+        text.push_str("...");
+        padding.push_str("   ");
+    }
+    text.extend(&line[from..to]);
+    // Keep tabs so that the caret lines up with the character above it.
+    padding.extend(
+        line[from..column]
+            .iter()
+            .map(|&c| if c == '\t' { c } else { ' ' }),
+    );
+    if to < line.len() {
+        text.push_str("...");
+    }
 
-    let res = match engine {
-        #[cfg(feature = "interpreter")]
-        EngineType::Interpreter => interpreter::run(&program),
-        #[cfg(feature = "machine")]
-        EngineType::Machine => jit::machine::run(&program),
+    let number = location.line.to_string();
+    let gutter = " ".repeat(number.len());
+    format!(
+        "{gutter}--> {filename}:{location}\n{gutter} |\n{number} | {text}\n{gutter} | {padding}^"
+    )
+}
+
+/// Writes `program` compiled as `emit` says into `output`, or the stdout if it is not given.
+#[cfg_attr(
+    not(any(feature = "machine", feature = "asm")),
+    allow(unused_variables)
+)]
+fn write(
+    emit: EmitType,
+    engine: Option<Engine>,
+    program: &Program,
+    output: Option<&str>,
+) -> Result<(), Error> {
+    let program = program.nodes();
+    match emit {
+        #[cfg(all(feature = "machine", target_arch = "x86_64"))]
+        EmitType::Exe => {
+            let output = output.unwrap_or("a.out");
+            std::fs::write(output, jit::elf::executable(program))?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt as _;
+                std::fs::set_permissions(output, std::fs::Permissions::from_mode(0o755))?;
+            }
+            Ok(())
+        }
         #[cfg(feature = "asm")]
-        EngineType::Asm => jit::asm::run(&program),
-    };
-    if let Err(e) = res {
-        eprintln!("{}", e);
+        EmitType::Asm => save(output, jit::asm::listing(program)?.as_bytes()),
+        #[cfg(any(feature = "machine", feature = "asm"))]
+        EmitType::Bin => {
+            let code = match engine {
+                #[cfg(feature = "machine")]
+                Some(Engine::Machine) => jit::machine::code(program)?,
+                #[cfg(feature = "asm")]
+                _ => jit::asm::code(program)?,
+                #[cfg(not(feature = "asm"))]
+                _ => jit::machine::code(program)?,
+            };
+            save(output, &code)
+        }
+        #[cfg(feature = "c")]
+        EmitType::C => save(output, c::transpile(program).as_bytes()),
+        #[cfg(feature = "wasm")]
+        EmitType::Wasm => save(output, &wasm::binary(program)),
+        #[cfg(feature = "wasm")]
+        EmitType::Wat => save(output, wasm::text(program).as_bytes()),
     }
 }
 
+/// Writes `bytes` into the file `output`, or the stdout if it is not given.
+#[cfg(any(feature = "machine", feature = "asm", feature = "c", feature = "wasm"))]
+fn save(output: Option<&str>, bytes: &[u8]) -> Result<(), Error> {
+    match output {
+        Some(output) => std::fs::write(output, bytes)?,
+        None => io::Write::write_all(&mut io::stdout(), bytes)?,
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     // All of the test cases under this module are adapted from http://brainfuck.org/tests.b
@@ -167,23 +583,141 @@ mod test {
     // [1]: http://www.hevanet.com/cristofd/brainfuck/
     // [2]: https://creativecommons.org/licenses/by-sa/4.0/
     use super::*;
-    use std::{cell::RefCell, collections::VecDeque};
+    use brainf_ck::CancelHandle;
+    use std::{cell::RefCell, collections::VecDeque, io::Write, mem, rc::Rc, thread};
 
     thread_local! {
-        pub static OUT: RefCell<Vec<u8>> = RefCell::new(Vec::new());
-        pub static IN: RefCell<VecDeque<u8>> = RefCell::new(VecDeque::new());
+        pub static OUT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        pub static IN: RefCell<VecDeque<u8>> = const { RefCell::new(VecDeque::new()) };
+        pub static OPTIONS: RefCell<Options> = RefCell::new(Options::default());
     }
 
-    fn run_tests(test: fn(fn(&[u8]) -> Result<(), Error>)) {
-        test(interpreter::run);
-        clear();
-        test(jit::machine::run);
-        clear();
-        test(jit::asm::run);
+    /// Parses a programme and runs it with one of the engines.
+    type Run<'a> = &'a dyn Fn(&[u8]) -> Result<(), Error>;
+    /// Runs a parsed programme with the options, the input and the output.
+    type Runner = fn(&Program, &Options, &mut VecDeque<u8>, &mut Vec<u8>) -> Result<(), Error>;
+
+    /// Runs `test` with every engine at every optimisation level,
+    /// so that each test also checks the optimised programmes behave identically.
+    /// The programme runs with what the test puts in `OPTIONS`, reads from `IN`, and writes into `OUT`.
+    fn run_tests(test: fn(Run)) {
+        let engines: [Runner; 4] = [
+            |program, options, input, output| {
+                program.run_with(Engine::Interpreter, options, input, output)
+            },
+            |program, options, input, output| {
+                program.run_with(Engine::Machine, options, input, output)
+            },
+            |program, options, input, output| program.run_with(Engine::Asm, options, input, output),
+            run_wasm,
+        ];
+        for level in ir::OptLevel::ALL {
+            for engine in engines {
+                clear();
+                test(&|program| {
+                    let program = Program::parse(program, level)?;
+                    let mut output = Vec::new();
+                    let options = OPTIONS.with_borrow(Clone::clone);
+                    let result = engine(&program, &options, &mut IN.take(), &mut output);
+                    OUT.with_borrow_mut(|out| out.extend(output));
+                    result
+                });
+            }
+        }
 
         fn clear() {
             OUT.with(|o| o.borrow_mut().clear());
             IN.with(|i| i.borrow_mut().clear());
+            OPTIONS.take();
+        }
+    }
+
+    /// What a programme did on an engine at an optimisation level.
+    struct Ran {
+        engine: Engine,
+        level: ir::OptLevel,
+        result: Result<(), Error>,
+        output: Vec<u8>,
+    }
+
+    /// Runs `source` with `options` and `input` on every engine at every optimisation level,
+    /// for the tests with options which the WebAssembly module doesn't take, unlike [`run_tests`].
+    fn run_engines(source: &[u8], options: &Options, input: &[u8]) -> Vec<Ran> {
+        let mut runs = Vec::new();
+        for level in ir::OptLevel::ALL {
+            let program = Program::parse(source, level).unwrap();
+            for engine in [Engine::Interpreter, Engine::Machine, Engine::Asm] {
+                let mut output = Vec::new();
+                let result = program.run_with(engine, options, input, &mut output);
+                runs.push(Ran {
+                    engine,
+                    level,
+                    result,
+                    output,
+                });
+            }
+        }
+        runs
+    }
+
+    /// Runs a programme as a WebAssembly module, with host functions doing the io the same as the other engines.
+    fn run_wasm(
+        program: &Program,
+        options: &Options,
+        input: &mut VecDeque<u8>,
+        output: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        use wasmi::{Caller, Engine, Extern, Linker, Module, Store};
+
+        /// The input, the output and what EOF does, owned by the store as the host functions must be `'static`.
+        type Io = (VecDeque<u8>, Vec<u8>, EofMode);
+
+        /// Calls `io` with the cell at `address`, returning 1, or 0 on errors, as the module expects.
+        fn host(mut caller: Caller<'_, Io>, address: i32, io: fn(&mut u8, &mut Io) -> bool) -> i32 {
+            let memory = caller
+                .get_export("memory")
+                .and_then(Extern::into_memory)
+                .unwrap();
+            let (memory, data) = memory.data_and_store_mut(&mut caller);
+            io(&mut memory[address as usize], data).into()
+        }
+
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wasm::binary(program.nodes())[..]).unwrap();
+        let mut store = Store::new(&engine, (mem::take(input), Vec::new(), options.eof));
+        let mut linker = Linker::new(&engine);
+        linker
+            .func_wrap("env", "putchar", |caller: Caller<'_, Io>, address: i32| {
+                host(caller, address, |cell, (_, output, _)| {
+                    output.push(*cell);
+                    true
+                })
+            })
+            .unwrap();
+        linker
+            .func_wrap("env", "getchar", |caller: Caller<'_, Io>, address: i32| {
+                host(caller, address, |cell, (input, _, eof)| {
+                    match (input.pop_front(), eof) {
+                        (Some(byte), _) => *cell = byte,
+                        (None, EofMode::Zero) => *cell = 0,
+                        (None, EofMode::Unchanged) => {}
+                        (None, EofMode::MinusOne) => *cell = u8::MAX,
+                        (None, EofMode::Error) => return false,
+                    }
+                    true
+                })
+            })
+            .unwrap();
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .and_then(|instance| instance.start(&mut store))
+            .unwrap();
+        let run = instance.get_typed_func::<(), i32>(&store, "run").unwrap();
+        let result = run.call(&mut store, ()).unwrap();
+        output.extend(mem::take(&mut store.data_mut().1));
+        match result {
+            0 => Err(io::Error::other("io-error in the host").into()),
+            _ => Ok(()),
         }
     }
 
@@ -213,6 +747,26 @@ mod test {
             run(PROGRAM).unwrap();
             OUT.with(|output| assert_eq!(output.borrow().as_slice(), b"LB\nLB\n"));
         });
+
+        // The other conventions, which the comment above lists.
+        run_tests(|run| {
+            OPTIONS.with_borrow_mut(|options| options.eof = EofMode::Unchanged);
+            IN.with(|input| input.borrow_mut().extend(b"\n"));
+            run(PROGRAM).unwrap();
+            OUT.with(|output| assert_eq!(output.borrow().as_slice(), b"LK\nLK\n"));
+        });
+        run_tests(|run| {
+            OPTIONS.with_borrow_mut(|options| options.eof = EofMode::MinusOne);
+            IN.with(|input| input.borrow_mut().extend(b"\n"));
+            run(PROGRAM).unwrap();
+            OUT.with(|output| assert_eq!(output.borrow().as_slice(), b"LA\nLA\n"));
+        });
+        run_tests(|run| {
+            OPTIONS.with_borrow_mut(|options| options.eof = EofMode::Error);
+            IN.with(|input| input.borrow_mut().extend(b"\n"));
+            assert!(matches!(run(PROGRAM), Err(Error::Io(_))));
+            OUT.with(|output| assert_eq!(output.borrow().as_slice(), b""));
+        });
     }
 
     #[test]
@@ -282,7 +836,12 @@ mod test {
         http://www.hevanet.com/cristofd/brainfuck/ */
 
         run_tests(|run| {
-            assert!(matches!(run(PROGRAM), Err(Error::UnmatchedLeft)));
+            let location = Location {
+                position: 25,
+                line: 1,
+                column: 26,
+            };
+            assert!(matches!(run(PROGRAM), Err(Error::UnmatchedLeft(l)) if l == location));
             OUT.with(|output| assert_eq!(output.borrow().as_slice(), b""));
         });
     }
@@ -296,11 +855,124 @@ mod test {
         http://www.hevanet.com/cristofd/brainfuck/ */
 
         run_tests(|run| {
-            assert!(matches!(run(PROGRAM), Err(Error::UnmatchedRight)));
+            let location = Location {
+                position: 25,
+                line: 1,
+                column: 26,
+            };
+            assert!(matches!(run(PROGRAM), Err(Error::UnmatchedRight(l)) if l == location));
             OUT.with(|output| assert_eq!(output.borrow().as_slice(), b""));
         });
     }
 
+    #[test]
+    fn bracket_locations() {
+        // The innermost `[` left open is the one reported, on the line and the column it is at.
+        let source = "+[\n\t>[-\n".as_bytes();
+        let Err(Error::UnmatchedLeft(location)) = ir::parse(source) else {
+            panic!("the brackets match");
+        };
+        let expected = Location {
+            position: 5,
+            line: 2,
+            column: 3,
+        };
+        assert_eq!(location, expected);
+        assert_eq!(
+            excerpt("a.b", source, location),
+            " --> a.b:2:3\n  |\n2 | \t>[-\n  | \t ^"
+        );
+
+        // Columns count characters rather than bytes.
+        let source = "é]".as_bytes();
+        let Err(Error::UnmatchedRight(location)) = ir::parse(source) else {
+            panic!("the brackets match");
+        };
+        assert_eq!((location.position, location.column), (2, 2));
+        // Invalid UTF-8 counts as many characters as replace it in the excerpt.
+        for (source, column) in [(&b"\xe2\x82]"[..], 2), (b"\xff\xff]", 3)] {
+            let Err(Error::UnmatchedRight(location)) = ir::parse(source) else {
+                panic!("the brackets match");
+            };
+            assert_eq!(location.column, column);
+            assert!(
+                excerpt("a.b", source, location).ends_with(&format!("{}^", " ".repeat(column - 1)))
+            );
+        }
+
+        // Long lines are cut down around the bracket.
+        let source = format!("{}]{}", "+".repeat(100), "-".repeat(100));
+        let Err(Error::UnmatchedRight(location)) = ir::parse(source.as_bytes()) else {
+            panic!("the brackets match");
+        };
+        let excerpt = excerpt("a.b", source.as_bytes(), location);
+        let lines: Vec<_> = excerpt.lines().collect();
+        assert_eq!(
+            lines[2],
+            format!("1 | ...{}]{}...", "+".repeat(40), "-".repeat(40))
+        );
+        assert_eq!(lines[3], format!("  | {}^", " ".repeat(43)));
+    }
+
+    #[test]
+    fn failures() {
+        let source = b"+[<\n]].";
+        let Err(e) = Program::parse(source, ir::OptLevel::O3) else {
+            panic!("the brackets match");
+        };
+        let failure = Failure::Program(e);
+        assert_eq!(failure.status(), 3);
+        assert_eq!(
+            failure.json("a \"b\".b"),
+            r#"{"kind":"unmatched-right","message":"unmatched ] at 2:2","status":3,"file":"a \"b\".b","position":5,"line":2,"column":2}"#
+        );
+
+        let program = Program::parse(source.split_at(5).0, ir::OptLevel::O0).unwrap();
+        let options = Options {
+            bounds: Bounds::Error,
+            ..Options::default()
+        };
+        let e = program
+            .run_with(Engine::Interpreter, &options, io::empty(), io::sink())
+            .unwrap_err();
+        let failure = Failure::Program(e);
+        assert_eq!(failure.status(), 5);
+        assert_eq!(
+            failure.json("a.b"),
+            r#"{"kind":"out-of-bounds","message":"the cell -1 is out of the tape, accessed at 4","status":5,"position":4,"index":-1}"#
+        );
+
+        let failure = Failure::Source(io::Error::other("gone\tmissing"));
+        assert_eq!(failure.status(), 2);
+        assert_eq!(
+            failure.json("a.b"),
+            r#"{"kind":"source","message":"io-error while reading the file","status":2,"file":"a.b","error":"gone\tmissing"}"#
+        );
+
+        let failure = Failure::Program(io::Error::other("closed").into());
+        assert_eq!(failure.status(), 4);
+        assert_eq!(
+            failure.json("a.b"),
+            r#"{"kind":"io","message":"io-error during execution","status":4,"error":"closed"}"#
+        );
+        assert_eq!(Failure::Program(Error::TapeSize(3)).status(), 1);
+
+        let limit = Limit::Time(Duration::from_millis(1500));
+        let failure = Failure::Program(Error::LimitExceeded(limit));
+        assert_eq!(failure.status(), 6);
+        assert_eq!(
+            failure.json("a.b"),
+            r#"{"kind":"limit-exceeded","message":"the programme exceeded the limit of 1.5s","status":6,"limit":"time","seconds":1.5}"#
+        );
+
+        let failure = Failure::Program(Error::Cancelled);
+        assert_eq!(failure.status(), 6);
+        assert_eq!(
+            failure.json("a.b"),
+            r#"{"kind":"cancelled","message":"the programme was cancelled","status":6}"#
+        );
+    }
+
     #[test]
     fn rot13() {
         static PROGRAM: &[u8] = include_bytes!("./rot13.b");
@@ -335,4 +1007,1255 @@ mod test {
             });
         });
     }
+
+    #[test]
+    fn clear_loop() {
+        static PROGRAM: &[u8] = b"++++[-]+++>+[+]<[>+<-]>[---]+++++++++[>++++++++<-]>.";
+        // Clears cells with `[-]`, `[+]` and `[---]`, surrounded by additions that get folded into them.
+        // It should output an H.
+
+        assert_eq!(
+            ir::optimize(
+                ir::parse(b"+[-]+++>[+]-[--]").unwrap(),
+                ir::OptLevel::O2,
+                1 << 16,
+                Bounds::Wrap
+            ),
+            [
+                ir::Node::new(
+                    ir::NodeKind::Set {
+                        offset: 0,
+                        value: 3
+                    },
+                    0..7
+                ),
+                ir::Node::new(
+                    ir::NodeKind::Set {
+                        offset: 1,
+                        value: -1
+                    },
+                    8..12
+                ),
+                ir::Node::new(ir::NodeKind::Move { amount: 1 }, 7..8),
+                ir::Node::new(
+                    ir::NodeKind::Loop {
+                        body: vec![ir::Node::new(
+                            ir::NodeKind::Add {
+                                offset: 0,
+                                amount: -2
+                            },
+                            13..15
+                        )]
+                    },
+                    12..16
+                ),
+            ]
+        );
+
+        run_tests(|run| {
+            run(PROGRAM).unwrap();
+            OUT.with(|output| assert_eq!(output.borrow().as_slice(), b"H"));
+        });
+    }
+
+    #[test]
+    fn multiply_loop() {
+        static PROGRAM: &[u8] =
+            b"++++++++[>+++++++++>+++++++++++++<<-]>.>+.<<++[>>>+++<<<-]>>>[<<+>>-]+++++[<<<++>>>-]<<.<.[-<+++++++>]<++.";
+        // Multiplies and copies cells to the both sides, including across the start of the tape.
+        // It should output "HiN", a newline and another H.
+
+        assert_eq!(
+            ir::optimize(
+                ir::parse(b"[->+>++<<]+[>-<-]>[->+]").unwrap(),
+                ir::OptLevel::O2,
+                1 << 16,
+                Bounds::Wrap
+            ),
+            [
+                ir::Node::new(
+                    ir::NodeKind::MulAdd {
+                        offset: 1,
+                        factor: 1
+                    },
+                    0..10
+                ),
+                ir::Node::new(
+                    ir::NodeKind::MulAdd {
+                        offset: 2,
+                        factor: 2
+                    },
+                    0..10
+                ),
+                ir::Node::new(
+                    ir::NodeKind::Set {
+                        offset: 0,
+                        value: 1
+                    },
+                    0..11
+                ),
+                ir::Node::new(
+                    ir::NodeKind::MulAdd {
+                        offset: 1,
+                        factor: -1
+                    },
+                    11..17
+                ),
+                ir::Node::new(
+                    ir::NodeKind::Set {
+                        offset: 0,
+                        value: 0
+                    },
+                    11..17
+                ),
+                ir::Node::new(ir::NodeKind::Move { amount: 1 }, 17..18),
+                ir::Node::new(
+                    ir::NodeKind::Loop {
+                        body: vec![
+                            ir::Node::new(
+                                ir::NodeKind::Add {
+                                    offset: 0,
+                                    amount: -1
+                                },
+                                19..20
+                            ),
+                            ir::Node::new(
+                                ir::NodeKind::Add {
+                                    offset: 1,
+                                    amount: 1
+                                },
+                                21..22
+                            ),
+                            ir::Node::new(ir::NodeKind::Move { amount: 1 }, 20..21),
+                        ]
+                    },
+                    18..23
+                ),
+            ]
+        );
+
+        run_tests(|run| {
+            run(PROGRAM).unwrap();
+            OUT.with(|output| assert_eq!(output.borrow().as_slice(), b"HiN\nH"));
+        });
+    }
+
+    #[test]
+    fn scan_loop() {
+        static PROGRAM: &[u8] =
+            b"++++++++[>++++++++>++++++++>++++++++>++++++++<<<<-]>+>++>+++>++++[<]>.[>]<.<<<<+[<]>>.<<+<+[>]<.>+>>+<<[>>]++++++++[<<++++++++>>-]<<+.";
+        // Scans to the both sides, wrapping around the tape, and with a stride of two.
+        // It should output "ADADB".
+
+        assert_eq!(
+            ir::optimize(
+                ir::parse(b"[>][<<][>>>>]").unwrap(),
+                ir::OptLevel::O1,
+                1 << 16,
+                Bounds::Wrap
+            ),
+            [
+                ir::Node::new(ir::NodeKind::Scan { stride: 1 }, 0..3),
+                ir::Node::new(ir::NodeKind::Scan { stride: -2 }, 3..7),
+                ir::Node::new(ir::NodeKind::Scan { stride: 4 }, 7..13),
+            ]
+        );
+
+        run_tests(|run| {
+            run(PROGRAM).unwrap();
+            OUT.with(|output| assert_eq!(output.borrow().as_slice(), b"ADADB"));
+        });
+    }
+
+    #[test]
+    fn offset_cells() {
+        // Changes the cells on the both sides of the pointer without moving it in between,
+        // at the start of the tape, somewhere in the middle, and at the end of the tape.
+        // It should output "HiOk!" and a newline.
+        #[rustfmt::skip]
+        static PROGRAM: &[&[u8]] = &[
+            b"<", &[b'+'; 72], b">>", &[b'+'; 105], b"<<.>>.",
+            b">>>>>>>>>>",
+            b"<", &[b'+'; 79], b">>", &[b'+'; 107], b"<<.>>.",
+            b"<<<<<<<<<<<<<",
+            b">", &[b'+'; 33], b"<<", &[b'+'; 10], b">>.<<.",
+        ];
+
+        assert_eq!(
+            ir::optimize(
+                ir::parse(b">+>++<<-.").unwrap(),
+                ir::OptLevel::O2,
+                1 << 16,
+                Bounds::Wrap
+            ),
+            [
+                ir::Node::new(
+                    ir::NodeKind::Add {
+                        offset: 1,
+                        amount: 1
+                    },
+                    1..2
+                ),
+                ir::Node::new(
+                    ir::NodeKind::Add {
+                        offset: 2,
+                        amount: 2
+                    },
+                    3..5
+                ),
+                ir::Node::new(
+                    ir::NodeKind::Add {
+                        offset: 0,
+                        amount: -1
+                    },
+                    7..8
+                ),
+                ir::Node::new(ir::NodeKind::Output, 8..9),
+            ]
+        );
+
+        run_tests(|run| {
+            run(&PROGRAM.concat()).unwrap();
+            OUT.with(|output| assert_eq!(output.borrow().as_slice(), b"HiOk!\n"));
+        });
+    }
+
+    #[test]
+    fn dead_code() {
+        static PROGRAM: &[u8] = b"[.]++[>+<-][>.<][-]>.+>>";
+        // The first loop never runs as every cell is zero at the start,
+        // nor the second and the third as they come after the multiplication loop.
+        // It should output a byte of 2.
+
+        assert_eq!(
+            ir::optimize(
+                ir::parse(PROGRAM).unwrap(),
+                ir::OptLevel::O3,
+                1 << 16,
+                Bounds::Wrap
+            ),
+            [
+                ir::Node::new(
+                    ir::NodeKind::Add {
+                        offset: 0,
+                        amount: 2
+                    },
+                    3..5
+                ),
+                ir::Node::new(
+                    ir::NodeKind::MulAdd {
+                        offset: 1,
+                        factor: 1
+                    },
+                    5..11
+                ),
+                ir::Node::new(
+                    ir::NodeKind::Set {
+                        offset: 0,
+                        value: 0
+                    },
+                    5..11
+                ),
+                ir::Node::new(ir::NodeKind::Move { amount: 1 }, 19..20),
+                ir::Node::new(ir::NodeKind::Output, 20..21),
+            ]
+        );
+
+        run_tests(|run| {
+            run(PROGRAM).unwrap();
+            OUT.with(|output| assert_eq!(output.borrow().as_slice(), b"\x02"));
+        });
+    }
+
+    #[test]
+    fn concurrent_io() {
+        // Runs rot13 on a few threads at once, each with its own input and output,
+        // so that the JIT engines must not share any streams between the runs.
+        let program = Program::parse(include_bytes!("./rot13.b"), ir::OptLevel::O3).unwrap();
+
+        for engine in [Engine::Interpreter, Engine::Machine, Engine::Asm] {
+            std::thread::scope(|scope| {
+                for (input, expected) in [
+                    (&b"~mlk zyx"[..], &b"~zyx mlk"[..]),
+                    (b"Hello, World!", b"Uryyb, Jbeyq!"),
+                    (b"abc", b"nop"),
+                    (b"", b""),
+                ] {
+                    let program = &program;
+                    scope.spawn(move || {
+                        for _ in 0..16 {
+                            let mut output = Vec::new();
+                            program.run(engine, input, &mut output).unwrap();
+                            assert_eq!(output, expected);
+                        }
+                    });
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn io_error() {
+        // An output which fails on the second byte, whose error every engine should return as it is.
+        struct Failing(usize);
+
+        impl Write for Failing {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0 += buf.len();
+                match self.0 {
+                    ..2 => Ok(buf.len()),
+                    _ => Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed")),
+                }
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let program = Program::parse(b"+.+.+.", ir::OptLevel::O3).unwrap();
+        for engine in [Engine::Interpreter, Engine::Machine, Engine::Asm] {
+            let result = program.run(engine, io::empty(), Failing(0));
+            assert!(
+                matches!(result, Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::BrokenPipe),
+                "{engine:?}: {result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn buffering() {
+        // Outputs "ab\ncd\n", asks for a byte with "?" and echoes it.
+        static PROGRAM: &[u8] =
+            b"++++++++++[>++++++++++<-]>---.+.>++++++++++.<+.+.>.<<+++++++[>>+++++++<<-]>>++++.,.";
+
+        /// Keeps every write on its own, to tell where the output was written through.
+        #[derive(Clone, Default)]
+        struct Writes(Rc<RefCell<Vec<Vec<u8>>>>);
+
+        impl Write for Writes {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().push(buf.to_vec());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        /// Checks the prompt has been written before giving the input.
+        struct Answer(Writes);
+
+        impl io::Read for Answer {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                assert_eq!(self.0 .0.borrow().concat(), b"ab\ncd\n?");
+                buf[0] = b'!';
+                Ok(1)
+            }
+        }
+
+        let program = Program::parse(PROGRAM, ir::OptLevel::O3).unwrap();
+        for engine in [Engine::Interpreter, Engine::Machine, Engine::Asm] {
+            for (buffering, expected) in [
+                (
+                    Buffering::None,
+                    &[&b"a"[..], b"b", b"\n", b"c", b"d", b"\n", b"?", b"!"][..],
+                ),
+                (Buffering::Line, &[&b"ab\n"[..], b"cd\n", b"?", b"!"]),
+                (Buffering::Full, &[&b"ab\ncd\n?"[..], b"!"]),
+            ] {
+                let output = Writes::default();
+                let options = Options {
+                    buffering,
+                    ..Options::default()
+                };
+                program
+                    .run_with(engine, &options, Answer(output.clone()), output.clone())
+                    .unwrap();
+                assert_eq!(*output.0.borrow(), expected, "{engine:?}, {buffering:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn cell_width() {
+        // Each programme gets a cell to a multiple of 256 and outputs "1" if it isn't 0,
+        // so the output tells at which width the cell wraps around.
+        let flag = format!("[>{}.>]", "+".repeat(49));
+        let sixteen = "+".repeat(16);
+        /// A programme, the input and the output with each width.
+        type Case = (String, &'static [u8], [&'static [u8]; 3]);
+
+        let cases: [Case; 7] = [
+            // 256 by multiplying.
+            (
+                format!("{sixteen}[>{sixteen}<-]>{flag}"),
+                b"",
+                [b"", b"1", b"1"],
+            ),
+            // 65536 by multiplying twice.
+            (
+                format!("{sixteen}[>{sixteen}<-]>[>{sixteen}[>{sixteen}<-]<-]>>{flag}"),
+                b"",
+                [b"", b"", b"1"],
+            ),
+            // -256 with a negative factor.
+            (
+                format!("++++[>{}<-]>{flag}", "-".repeat(64)),
+                b"",
+                [b"", b"1", b"1"],
+            ),
+            // Large amounts, where the output takes the lowest 8 bits of 5000.
+            (
+                format!("{}.{}{flag}", "+".repeat(5000), "-".repeat(4744)),
+                b"",
+                [b"\x88", b"\x881", b"\x881"],
+            ),
+            // A large value set to a cell before the start of the tape.
+            (
+                format!("<+[-]{}><{flag}", "+".repeat(4864)),
+                b"",
+                [b"", b"1", b"1"],
+            ),
+            // The input is zero-extended, while EOF sets all the bits so that adding 1 gets to 0.
+            (
+                format!(",{}{flag}", "+".repeat(56)),
+                b"\xc8",
+                [b"", b"1", b"1"],
+            ),
+            (format!(",.+{flag}"), b"", [b"\xff", b"\xff", b"\xff"]),
+        ];
+
+        for (i, (source, input, expected)) in cases.iter().enumerate() {
+            for (cell_width, expected) in [CellWidth::U8, CellWidth::U16, CellWidth::U32]
+                .into_iter()
+                .zip(expected)
+            {
+                let options = Options {
+                    eof: EofMode::MinusOne,
+                    cell_width,
+                    ..Options::default()
+                };
+                for Ran {
+                    engine,
+                    level,
+                    result,
+                    output,
+                } in run_engines(source.as_bytes(), &options, input)
+                {
+                    result.unwrap();
+                    assert_eq!(
+                        output, *expected,
+                        "{engine:?}, {level:?}, {cell_width:?}: {i}"
+                    );
+                }
+            }
+        }
+
+        // Scanning goes through the wider cells the same way, wrapping around the tape.
+        for source in [
+            &b"+>+>+>+<<<[>]<<<."[..],
+            b"+>+>+>+[<]>>.",
+            b"+>>+>>+[<<]>>>>.",
+        ] {
+            for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+                let options = Options {
+                    cell_width,
+                    ..Options::default()
+                };
+                for Ran {
+                    engine,
+                    level,
+                    result,
+                    output,
+                } in run_engines(source, &options, b"")
+                {
+                    result.unwrap();
+                    assert_eq!(output, [1], "{engine:?}, {level:?}, {cell_width:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tape_bounds() {
+        let right = ">".repeat(20);
+        let left = "<".repeat(20);
+        /// A programme, the cell it goes out of a tape of 16 cells at and where, the level from which
+        /// an optimisation replaces the loop doing so and where the loop starts, and the output growing the tape.
+        type Case = (
+            String,
+            i64,
+            usize,
+            Option<(ir::OptLevel, usize)>,
+            Option<&'static [u8]>,
+        );
+
+        let cases: [Case; 5] = [
+            // Growing only extends the tape to the right.
+            ("+<+.".to_string(), -1, 2, None, None),
+            (format!("{}+.", ">".repeat(16)), 16, 16, None, Some(b"\x01")),
+            // A multiplication reaching far, and a scan going past the left end.
+            (
+                format!("++[{right}+{left}-]{right}."),
+                20,
+                23,
+                Some((ir::OptLevel::O2, 2)),
+                Some(b"\x02"),
+            ),
+            (
+                "+>+[<]".to_string(),
+                -1,
+                5,
+                Some((ir::OptLevel::O1, 3)),
+                None,
+            ),
+            (
+                format!("{}.", ">".repeat(100)),
+                100,
+                100,
+                None,
+                Some(b"\x00"),
+            ),
+        ];
+
+        for (source, index, position, optimised, grown) in &cases {
+            for cell_width in [CellWidth::U8, CellWidth::U32] {
+                let options = Options {
+                    cell_width,
+                    tape_size: 16,
+                    bounds: Bounds::Error,
+                    ..Options::default()
+                };
+                for Ran {
+                    engine,
+                    level,
+                    result,
+                    ..
+                } in run_engines(source.as_bytes(), &options, b"")
+                {
+                    let position = match optimised {
+                        Some((from, start)) if level >= *from => *start,
+                        _ => *position,
+                    };
+                    assert!(
+                        matches!(result, Err(Error::OutOfBounds { index: i, position: p }) if i == *index && p == position),
+                        "{engine:?}, {level:?}, {cell_width:?}: {source} {result:?}",
+                    );
+                }
+
+                let options = Options {
+                    bounds: Bounds::Grow,
+                    ..options
+                };
+                for Ran {
+                    engine,
+                    level,
+                    result,
+                    output,
+                } in run_engines(source.as_bytes(), &options, b"")
+                {
+                    match grown {
+                        Some(expected) => {
+                            result.unwrap();
+                            assert_eq!(
+                                output, *expected,
+                                "{engine:?}, {level:?}, {cell_width:?}: {source}"
+                            );
+                        }
+                        None => assert!(
+                            matches!(result, Err(Error::OutOfBounds { index: i, .. }) if i == *index),
+                            "{engine:?}, {level:?}, {cell_width:?}: {source} {result:?}",
+                        ),
+                    }
+                }
+            }
+        }
+
+        // Smaller tapes wrap around sooner, even a single cell.
+        for (tape_size, expected) in [(8, b"\x01\x00"), (2, b"\x01\x01"), (1, b"\x01\x02")] {
+            let options = Options {
+                tape_size,
+                ..Options::default()
+            };
+            for Ran {
+                engine,
+                level,
+                result,
+                output,
+            } in run_engines(b"+>>>>>>>>.<+>>>>>>>.", &options, b"")
+            {
+                result.unwrap();
+                assert_eq!(output, expected, "{engine:?}, {level:?}, {tape_size}");
+            }
+        }
+
+        // Offsets reach the same cells on small tapes, so these loops add to their own counters and never end,
+        // and a cell changed at an offset can be the current one, the same at any level.
+        /// A programme, the size of the tape, and the output, or `None` if it never ends.
+        type Small = (&'static [u8], usize, Option<&'static [u8]>);
+        let cases: [Small; 5] = [
+            (b"+[->+<]", 1, None),
+            (b"+[->>+<<]", 2, None),
+            (b"+[->>>>+<<<<]", 4, None),
+            (b">>>>+<<<<[.-]", 4, Some(b"\x01")),
+            (b"+[->>>>+<<<<]>>>>.", 8, Some(b"\x01")),
+        ];
+        for (source, tape_size, expected) in cases {
+            let options = Options {
+                tape_size,
+                max_steps: Some(1000),
+                ..Options::default()
+            };
+            for Ran {
+                engine,
+                level,
+                result,
+                output,
+            } in run_engines(source, &options, b"")
+            {
+                match expected {
+                    Some(expected) => {
+                        result.unwrap();
+                        assert_eq!(output, expected, "{engine:?}, {level:?}: {source:?}");
+                    }
+                    None => assert!(
+                        matches!(result, Err(Error::LimitExceeded(_))),
+                        "{engine:?}, {level:?}: {source:?} {result:?}"
+                    ),
+                }
+            }
+        }
+
+        // Changes at the end of the programme are kept when going out of the tape ends it.
+        for source in [&b"+.<+"[..], b"+.<[->>>>+<<<<]", b"+.>>>>>>>>>>>>>>>>>-"] {
+            let options = Options {
+                tape_size: 16,
+                bounds: Bounds::Error,
+                ..Options::default()
+            };
+            for Ran {
+                engine,
+                level,
+                result,
+                output,
+            } in run_engines(source, &options, b"")
+            {
+                assert!(
+                    matches!(result, Err(Error::OutOfBounds { .. })),
+                    "{engine:?}, {level:?}: {source:?} {result:?}"
+                );
+                assert_eq!(output, b"\x01", "{engine:?}, {level:?}: {source:?}");
+            }
+        }
+
+        // Only a wrapping tape must be as large as a power of two.
+        for (tape_size, bounds, valid) in [
+            (3, Bounds::Wrap, false),
+            (0, Bounds::Grow, false),
+            (1 << 32, Bounds::Error, false),
+            (3, Bounds::Error, true),
+            (1 << 20, Bounds::Wrap, true),
+        ] {
+            let options = Options {
+                tape_size,
+                bounds,
+                ..Options::default()
+            };
+            for Ran {
+                engine,
+                level,
+                result,
+                ..
+            } in run_engines(b"+.", &options, b"")
+            {
+                if valid {
+                    result.unwrap();
+                } else {
+                    assert!(
+                        matches!(result, Err(Error::TapeSize(size)) if size == tape_size),
+                        "{engine:?}, {level:?}, {tape_size}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn guard_pages() {
+        let right = ">".repeat(20);
+        let left = "<".repeat(20);
+        let sources = [
+            "+<+.".to_string(),
+            format!("{}+.", ">".repeat(16)),
+            format!("++[{right}+{left}-]{right}."),
+            "+>+[<]".to_string(),
+            "+[>+]".to_string(),
+            format!("{},", "<".repeat(100)),
+            format!("{}.", ">".repeat(5000)),
+        ];
+
+        // Guard pages end the programme the same way as checking each access.
+        for source in &sources {
+            for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+                // A tape not as large as a page ends in the middle of one.
+                for tape_size in [16, 4097] {
+                    let options = Options {
+                        cell_width,
+                        tape_size,
+                        bounds: Bounds::Error,
+                        ..Options::default()
+                    };
+                    let checked = run_engines(source.as_bytes(), &options, b"");
+                    let options = Options {
+                        bounds: Bounds::Guard,
+                        ..options
+                    };
+                    let guarded = run_engines(source.as_bytes(), &options, b"");
+                    for (checked, guarded) in checked.iter().zip(&guarded) {
+                        let Ran { engine, level, .. } = guarded;
+                        assert_eq!(
+                            format!("{:?}", guarded.result),
+                            format!("{:?}", checked.result),
+                            "{engine:?}, {level:?}, {cell_width:?}, {tape_size}: {source}",
+                        );
+                    }
+                }
+            }
+        }
+
+        // The tape is as it is within the bounds.
+        let source = format!("+>+>{}.[<]", "+".repeat(65));
+        let options = Options {
+            tape_size: 3,
+            bounds: Bounds::Guard,
+            ..Options::default()
+        };
+        for Ran {
+            engine,
+            level,
+            result,
+            output,
+        } in run_engines(source.as_bytes(), &options, b"")
+        {
+            // The scan checks the cells from the start of the loop, while the loop does at its end.
+            let position = if level >= ir::OptLevel::O1 { 70 } else { 72 };
+            assert!(
+                matches!(result, Err(Error::OutOfBounds { index: -1, position: p }) if p == position),
+                "{engine:?}, {level:?}: {result:?}"
+            );
+            assert_eq!(output, b"A", "{engine:?}, {level:?}");
+        }
+    }
+
+    #[test]
+    fn limits() {
+        /// A programme which never ends, along with the tape it runs on.
+        type Case = (&'static [u8], usize, Bounds, CellWidth);
+
+        let cases: [Case; 5] = [
+            (b"+[]", 1 << 16, Bounds::Wrap, CellWidth::U8),
+            (b"+[]", 16, Bounds::Guard, CellWidth::U16),
+            // Scans which never stop as every cell is non-zero.
+            (b"+>+>+>+[>]", 4, Bounds::Wrap, CellWidth::U8),
+            (b"+>+>+>+[<]", 4, Bounds::Wrap, CellWidth::U16),
+            (b"+>+>+>+[>>]", 4, Bounds::Wrap, CellWidth::U8),
+        ];
+
+        for (source, tape_size, bounds, cell_width) in cases {
+            let options = Options {
+                cell_width,
+                tape_size,
+                bounds,
+                max_steps: Some(1000),
+                ..Options::default()
+            };
+            for Ran {
+                engine,
+                level,
+                result,
+                ..
+            } in run_engines(source, &options, b"")
+            {
+                assert!(
+                    matches!(result, Err(Error::LimitExceeded(Limit::Steps(1000)))),
+                    "{engine:?}, {level:?}: {source:?} {result:?}"
+                );
+            }
+
+            let timeout = Duration::from_millis(10);
+            let options = Options {
+                max_steps: None,
+                timeout: Some(timeout),
+                ..options
+            };
+            for Ran {
+                engine,
+                level,
+                result,
+                ..
+            } in run_engines(source, &options, b"")
+            {
+                assert!(
+                    matches!(result, Err(Error::LimitExceeded(Limit::Time(t))) if t == timeout),
+                    "{engine:?}, {level:?}: {source:?} {result:?}"
+                );
+            }
+        }
+
+        // Every engine counts the 5 iterations of the loop.
+        // Optimising the loop away leaves nothing to count, so this only holds without the optimisations.
+        let program = Program::parse(b"+++++[-].", ir::OptLevel::O0).unwrap();
+        for engine in [Engine::Interpreter, Engine::Machine, Engine::Asm] {
+            for (max_steps, ends) in [(5, true), (4, false)] {
+                let options = Options {
+                    max_steps: Some(max_steps),
+                    ..Options::default()
+                };
+                let mut output = Vec::new();
+                let result = program.run_with(engine, &options, io::empty(), &mut output);
+                assert_eq!(result.is_ok(), ends, "{engine:?}, {max_steps}: {result:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn cancel() {
+        let cases: [(&[u8], usize); 4] = [
+            (b"+[]", 1 << 16),
+            // Scans which never stop as every cell is non-zero.
+            (b"+>+>+>+[>]", 4),
+            (b"+>+>+>+[<]", 4),
+            (b"+>+>+>+[>>]", 4),
+        ];
+
+        // A handle cancelled before the programme starts ends it at the first check.
+        let handle = CancelHandle::new();
+        handle.cancel();
+        for (source, tape_size) in cases {
+            let options = Options {
+                tape_size,
+                cancel: Some(handle.clone()),
+                ..Options::default()
+            };
+            for Ran {
+                engine,
+                level,
+                result,
+                ..
+            } in run_engines(source, &options, b"")
+            {
+                assert!(
+                    matches!(result, Err(Error::Cancelled)),
+                    "{engine:?}, {level:?}: {source:?} {result:?}"
+                );
+            }
+        }
+
+        // Programmes end as usual if they finish before looking at the handle.
+        let options = Options {
+            cancel: Some(handle),
+            ..Options::default()
+        };
+        for Ran {
+            engine,
+            level,
+            result,
+            output,
+        } in run_engines(b"+++.", &options, b"")
+        {
+            result.unwrap();
+            assert_eq!(output, [3], "{engine:?}, {level:?}");
+        }
+
+        // Cancelling from another thread ends the programme running.
+        for engine in [Engine::Interpreter, Engine::Machine, Engine::Asm] {
+            for (source, tape_size) in cases {
+                let program = Program::parse(source, ir::OptLevel::O3).unwrap();
+                let handle = CancelHandle::new();
+                let options = Options {
+                    tape_size,
+                    cancel: Some(handle.clone()),
+                    ..Options::default()
+                };
+                let run = thread::spawn(move || {
+                    program.run_with(engine, &options, io::empty(), io::sink())
+                });
+                thread::sleep(Duration::from_millis(10));
+                handle.cancel();
+                let result = run.join().unwrap();
+                assert!(
+                    matches!(result, Err(Error::Cancelled)),
+                    "{engine:?}: {source:?} {result:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn debugger() {
+        let program = Program::parse(b"+++[>++<-]>.,.", ir::OptLevel::O0).unwrap();
+        // The programme reads what follows the line of the command running `,`.
+        let commands = "b 4\nc\nt 2\nc\nn\nb\nd 4\nn\nn\ns 3\nc\nA";
+        let mut output = Vec::new();
+        let mut prompt = Vec::new();
+        program
+            .debug(
+                &Options::default(),
+                commands.as_bytes(),
+                &mut output,
+                &mut prompt,
+            )
+            .unwrap();
+        assert_eq!(output, b"\x06A");
+        assert_eq!(
+            String::from_utf8(prompt).unwrap(),
+            "\
+0: add 3 at +0 (pointer 0, cell 0)
+(bf) breakpoint at 4
+(bf) breakpoint at 4
+4: move +1 (pointer 0, cell 3)
+(bf) [0:3] 1:0 2:0
+(bf) breakpoint at 4
+4: move +1 (pointer 0, cell 2)
+(bf) 5: add 2 at +0 (pointer 1, cell 2)
+(bf) breakpoints at 4
+(bf) (bf) 7: move -1 (pointer 1, cell 4)
+(bf) 8: add 255 at +0 (pointer 0, cell 2)
+(bf) 4: move +1 (pointer 0, cell 1)
+(bf) the programme ended
+"
+        );
+
+        // Stepping over a loop runs all of it, and the end of the commands ends the programme.
+        let mut output = Vec::new();
+        let mut prompt = Vec::new();
+        program
+            .debug(
+                &Options::default(),
+                &b"s\nn\np"[..],
+                &mut output,
+                &mut prompt,
+            )
+            .unwrap();
+        assert!(output.is_empty());
+        assert_eq!(
+            String::from_utf8(prompt).unwrap(),
+            "\
+0: add 3 at +0 (pointer 0, cell 0)
+(bf) 3: start loop (pointer 0, cell 3)
+(bf) 10: move +1 (pointer 0, cell 0)
+(bf) pointer 0, cell 0
+(bf) \n"
+        );
+    }
+
+    #[test]
+    fn debug_char() {
+        static PROGRAM: &[u8] = b"+>++#[-]<#.";
+        // `#` is a comment unless the extension is on.
+        assert_eq!(
+            Program::parse(PROGRAM, ir::OptLevel::O0).unwrap(),
+            Program::parse(b"+>++ [-]< .", ir::OptLevel::O0).unwrap()
+        );
+        // It shows the cells, so changes before it aren't dead code, and the pointer moves to it.
+        let program = Program::parse_with(b"+>++#>", ir::OptLevel::O3, true).unwrap();
+        assert_eq!(
+            program.nodes(),
+            [
+                ir::Node::new(
+                    ir::NodeKind::Add {
+                        offset: 0,
+                        amount: 1
+                    },
+                    0..1
+                ),
+                ir::Node::new(
+                    ir::NodeKind::Add {
+                        offset: 1,
+                        amount: 2
+                    },
+                    2..4
+                ),
+                ir::Node::new(ir::NodeKind::Move { amount: 1 }, 1..2),
+                ir::Node::new(ir::NodeKind::Dump, 4..5),
+            ]
+        );
+
+        // The output and the cells shown stay the same on every engine, including when the pointer is off the tape.
+        let program = Program::parse_with(PROGRAM, ir::OptLevel::O3, true).unwrap();
+        for engine in [Engine::Interpreter, Engine::Machine, Engine::Asm] {
+            for bounds in [Bounds::Wrap, Bounds::Error, Bounds::Grow, Bounds::Guard] {
+                let diagnostics = Arc::new(Mutex::new(Vec::new()));
+                let options = Options {
+                    bounds,
+                    cell_width: CellWidth::U16,
+                    tape_size: 4,
+                    diagnostics: Diagnostics::new(diagnostics.clone()),
+                    ..Options::default()
+                };
+                let mut output = Vec::new();
+                program
+                    .run_with(engine, &options, io::empty(), &mut output)
+                    .unwrap();
+                assert_eq!(output, [1], "{engine:?}, {bounds:?}");
+                assert_eq!(
+                    String::from_utf8(diagnostics.lock().unwrap().clone()).unwrap(),
+                    "# at 4, pointer 1: 0:1 [1:2] 2:0 3:0\n# at 9, pointer 0: [0:1] 1:0 2:0 3:0\n",
+                    "{engine:?}, {bounds:?}"
+                );
+            }
+            for (source, expected) in [
+                ("<<#", "# at 2, pointer -2: 0:0 1:0 2:0 3:0 4:0 5:0 6:0\n"),
+                (
+                    &format!("{}#", "<".repeat(20)),
+                    "# at 20, pointer -20: off the tape\n",
+                ),
+            ] {
+                let program =
+                    Program::parse_with(source.as_bytes(), ir::OptLevel::O0, true).unwrap();
+                let diagnostics = Arc::new(Mutex::new(Vec::new()));
+                let options = Options {
+                    bounds: Bounds::Error,
+                    diagnostics: Diagnostics::new(diagnostics.clone()),
+                    ..Options::default()
+                };
+                program
+                    .run_with(engine, &options, io::empty(), io::sink())
+                    .unwrap();
+                assert_eq!(
+                    String::from_utf8(diagnostics.lock().unwrap().clone()).unwrap(),
+                    expected,
+                    "{engine:?}: {source}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn trace() {
+        let text = |bytes: &[u8], granularity| {
+            let mut text = Vec::new();
+            trace::print(bytes, &mut text, granularity).unwrap();
+            String::from_utf8(text).unwrap()
+        };
+
+        // The engines agree at the conditions of loops, including when the pointer goes off the tape.
+        let sources: [&[u8]; 3] = [b"++[>+++[>++<-]<-]>>.", b"+[[-]>+<]", b"+++[>+<-<]"];
+        for source in sources {
+            for level in [ir::OptLevel::O0, ir::OptLevel::O3] {
+                let program = Program::parse(source, level).unwrap();
+                for bounds in [Bounds::Wrap, Bounds::Error, Bounds::Grow, Bounds::Guard] {
+                    let options = Options {
+                        bounds,
+                        tape_size: 16,
+                        ..Options::default()
+                    };
+                    let traces =
+                        [Engine::Interpreter, Engine::Machine, Engine::Asm].map(|engine| {
+                            let mut output = Vec::new();
+                            let mut bytes = Vec::new();
+                            let result = program.trace(
+                                engine,
+                                &options,
+                                Granularity::Instructions,
+                                io::empty(),
+                                &mut output,
+                                &mut bytes,
+                            );
+                            (
+                                format!("{result:?}"),
+                                output,
+                                text(&bytes, Granularity::Blocks),
+                            )
+                        });
+                    assert_eq!(traces[0], traces[1], "{source:?}, {level:?}, {bounds:?}");
+                    assert_eq!(traces[0], traces[2], "{source:?}, {level:?}, {bounds:?}");
+                }
+            }
+        }
+
+        let program = Program::parse(b"+++[>+<-<]", ir::OptLevel::O0).unwrap();
+        let options = Options {
+            bounds: Bounds::Error,
+            ..Options::default()
+        };
+        let mut bytes = Vec::new();
+        let result = program.trace(
+            Engine::Interpreter,
+            &options,
+            Granularity::Instructions,
+            io::empty(),
+            io::sink(),
+            &mut bytes,
+        );
+        assert!(matches!(
+            result,
+            Err(Error::OutOfBounds {
+                position: 9,
+                index: -1
+            })
+        ));
+        assert_eq!(
+            text(&bytes, Granularity::Instructions),
+            "\
+0: add (pointer 0, cell 0)
+3: start loop (pointer 0, cell 3)
+4: move (pointer 0, cell 3)
+5: add (pointer 1, cell 0)
+6: move (pointer 1, cell 1)
+7: add (pointer 0, cell 3)
+8: move (pointer 0, cell 2)
+9: end loop (pointer -1, off the tape)
+"
+        );
+
+        assert_eq!(bytes[..5], *b"BFTR\0");
+        let reader = trace::Reader::new(&bytes[..]).unwrap();
+        assert_eq!(reader.granularity(), Granularity::Instructions);
+        let records = reader.collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(
+            records.last(),
+            Some(&trace::Record {
+                position: 9,
+                op: trace::Op::LoopEnd,
+                pointer: -1,
+                cell: None,
+            })
+        );
+        for record in records {
+            assert_eq!(trace::Record::from_bytes(&record.to_bytes()), Some(record));
+        }
+
+        // A trace cut in the middle of a record, or what isn't a trace, can't be read.
+        let cut = trace::Reader::new(&bytes[..bytes.len() - 1])
+            .unwrap()
+            .last();
+        assert_eq!(
+            cut.unwrap().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        let error = trace::Reader::new(&b"BFTR\x02"[..]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn asm_listing() {
+        static PROGRAM: &[u8] = b"++[>+<-]\n>.";
+        // Each block in the listing tells where it comes from in the source.
+
+        let program = Program::parse(PROGRAM, ir::OptLevel::O0).unwrap();
+        let listing = jit::asm::listing(program.nodes()).unwrap();
+        let notes: Vec<_> = listing
+            .lines()
+            .filter(|line| line.starts_with("; "))
+            .collect();
+        assert_eq!(
+            notes[1..],
+            [
+                "; prelude",
+                "; 0..2: Add { offset: 0, amount: 2 }",
+                "; 2..3: [",
+                "; 3..4: Move { amount: 1 }",
+                "; 4..5: Add { offset: 0, amount: 1 }",
+                "; 5..6: Move { amount: -1 }",
+                "; 6..7: Add { offset: 0, amount: -1 }",
+                "; 7..8: ]",
+                "; 9..10: Move { amount: 1 }",
+                "; 10..11: Output",
+                "; postlude",
+            ]
+        );
+    }
+
+    /// Builds the programmes of the other tests into executables at `path` with `build`,
+    /// and checks they behave the same when they run as processes, where the io is real.
+    #[cfg(unix)]
+    fn run_processes(name: &str, build: fn(&Program, &std::path::Path)) {
+        use std::process::{Command, Stdio};
+
+        let cases: [(&[u8], &[u8], &[u8]); 3] = [
+            (
+                include_bytes!("./numwarp.b"),
+                b"128.42-(171)",
+                include_bytes!("./numwarp.stdout"),
+            ),
+            (include_bytes!("./rot13.b"), b"~mlk zyx", b"~zyx mlk"),
+            (
+                b">,>+++++++++,>+++++++++++[<++++++<++++++<+>>>-]<<.>.<<-.>.>.<<.",
+                b"\n",
+                b"LB\nLB\n",
+            ),
+        ];
+
+        for (i, (program, input, output)) in cases.into_iter().enumerate() {
+            for level in ir::OptLevel::ALL {
+                let path = std::env::temp_dir().join(format!(
+                    "brainf_ck-{name}-{}-{i}-{level:?}",
+                    std::process::id()
+                ));
+                build(&Program::parse(program, level).unwrap(), &path);
+
+                let mut child = Command::new(&path)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .unwrap();
+                child.stdin.take().unwrap().write_all(input).unwrap();
+                let result = child.wait_with_output().unwrap();
+                std::fs::remove_file(&path).unwrap();
+
+                assert!(result.status.success());
+                assert_eq!(result.stdout, output);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn executable() {
+        run_processes("exe", |program, path| {
+            write(EmitType::Exe, None, program, path.to_str()).unwrap()
+        });
+    }
+
+    #[test]
+    fn wasm_text() {
+        // Every kind of node, some of which only the optimisations make.
+        let source = b"++[>,.<-]>[-]+++[->++>+<<]>+[>>]<#";
+        for level in ir::OptLevel::ALL {
+            let program = Program::parse(source, level).unwrap();
+            let text = wasm::text(program.nodes());
+            let parsed = wat::parse_str(&text).unwrap();
+            // The text parsed into the binary format is the same module,
+            // except that `wat` adds the names in the text as a custom section at the end.
+            let binary = wasm::binary(program.nodes());
+            assert_eq!(&parsed[..binary.len()], binary, "{level:?}:\n{text}");
+            assert_eq!(parsed[binary.len()], 0, "{level:?}:\n{text}");
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn c_source() {
+        // Needs a C compiler as `cc`, without which there's nothing to test.
+        let version = std::process::Command::new("cc").arg("--version").output();
+        if matches!(&version, Err(error) if error.kind() == io::ErrorKind::NotFound) {
+            eprintln!("skipping c_source as there's no `cc`");
+            return;
+        }
+        run_processes("c", |program, path| {
+            let source = path.with_extension("c");
+            write(EmitType::C, None, program, source.to_str()).unwrap();
+            let status = std::process::Command::new("cc")
+                .arg("-o")
+                .arg(path)
+                .arg(&source)
+                .status()
+                .unwrap();
+            std::fs::remove_file(&source).unwrap();
+            assert!(status.success());
+        });
+    }
 }
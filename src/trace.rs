@@ -0,0 +1,273 @@
+//! Traces of programmes running, to tell where the engines part ways when one of them goes wrong.
+//!
+//! [`Program::trace`](crate::Program::trace) writes a trace in a compact binary format:
+//! [`MAGIC`] and a byte for the [`Granularity`], followed by [`Record::SIZE`] bytes for each [`Record`].
+//! The interpreter can write a record for each instruction it runs, while the JIT compilers only write the records
+//! at the conditions of loops and at the end, which is where their basic blocks start.
+//! [`Reader`] reads a trace back, and [`print`] writes it out as text.
+//!
+//! ```
+//! use brainf_ck::{ir::OptLevel, trace::{self, Granularity}, Engine, Options, Program};
+//! use std::io;
+//!
+//! let program = Program::parse(b"++[>+<-]", OptLevel::O0)?;
+//! let mut bytes = Vec::new();
+//! let options = Options::default();
+//! program.trace(Engine::Interpreter, &options, Granularity::Blocks, io::empty(), io::sink(), &mut bytes)?;
+//! let mut text = Vec::new();
+//! trace::print(&bytes[..], &mut text, Granularity::Blocks)?;
+//! assert_eq!(
+//!     String::from_utf8(text).unwrap(),
+//!     "2: start loop (pointer 0, cell 2)\n\
+//!      7: end loop (pointer 0, cell 1)\n\
+//!      2: start loop (pointer 0, cell 1)\n\
+//!      7: end loop (pointer 0, cell 0)\n\
+//!      8: end (pointer 0, cell 0)\n"
+//! );
+//! # Ok::<(), brainf_ck::Error>(())
+//! ```
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+/// The bytes a trace starts with.
+pub const MAGIC: [u8; 4] = *b"BFTR";
+
+/// Which instructions a trace has the records of, from the finest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Granularity {
+    /// Every instruction run.
+    Instructions,
+    /// Only [`Op::LoopStart`], [`Op::LoopEnd`] and [`Op::End`], which the JIT compilers write
+    /// and the interpreter can write as well to compare with them.
+    Blocks,
+}
+
+/// What an instruction in a [`Record`] does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Op {
+    Move,
+    Add,
+    Set,
+    Scan,
+    MulAdd,
+    Output,
+    Input,
+    Dump,
+    /// The condition at `[`, which is checked before each iteration.
+    LoopStart,
+    /// The condition at `]`, which is checked after each iteration.
+    LoopEnd,
+    /// The end of the programme.
+    End,
+}
+
+impl Op {
+    const ALL: [Self; 11] = [
+        Self::Move,
+        Self::Add,
+        Self::Set,
+        Self::Scan,
+        Self::MulAdd,
+        Self::Output,
+        Self::Input,
+        Self::Dump,
+        Self::LoopStart,
+        Self::LoopEnd,
+        Self::End,
+    ];
+
+    /// Returns the op numbered `byte` in the binary format, if there is one.
+    pub(crate) fn from_u8(byte: u8) -> Option<Self> {
+        Self::ALL.get(byte as usize).copied()
+    }
+
+    /// Returns whether [`Granularity::Blocks`] keeps the records of this.
+    pub fn is_block(self) -> bool {
+        matches!(self, Self::LoopStart | Self::LoopEnd | Self::End)
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Move => "move",
+            Self::Add => "add",
+            Self::Set => "set",
+            Self::Scan => "scan",
+            Self::MulAdd => "multiply",
+            Self::Output => "output",
+            Self::Input => "input",
+            Self::Dump => "dump",
+            Self::LoopStart => "start loop",
+            Self::LoopEnd => "end loop",
+            Self::End => "end",
+        })
+    }
+}
+
+/// An instruction about to run, along with the pointer and the cell at it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// The byte offset in the source of the instruction, or the end of the last one for [`Op::End`].
+    pub position: u32,
+    pub op: Op,
+    /// The pointer, which is negative on the left of the tape.
+    pub pointer: i32,
+    /// The cell at the pointer, or `None` if the pointer is off the tape.
+    pub cell: Option<u32>,
+}
+
+impl Record {
+    /// The number of bytes of a record: the position, the op, the pointer and the cell, all little endian.
+    pub const SIZE: usize = 13;
+
+    /// The bit of the op set when the pointer is off the tape, in which case the cell is 0.
+    const OFF_TAPE: u8 = 0x80;
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..4].copy_from_slice(&self.position.to_le_bytes());
+        bytes[4] = self.op as u8
+            | if self.cell.is_none() {
+                Self::OFF_TAPE
+            } else {
+                0
+            };
+        bytes[5..9].copy_from_slice(&self.pointer.to_le_bytes());
+        bytes[9..].copy_from_slice(&self.cell.unwrap_or(0).to_le_bytes());
+        bytes
+    }
+
+    /// Returns the record in `bytes`, or `None` if the op is unknown.
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Option<Self> {
+        let word = |start: usize| bytes[start..start + 4].try_into().unwrap();
+        Some(Self {
+            position: u32::from_le_bytes(word(0)),
+            op: Op::from_u8(bytes[4] & !Self::OFF_TAPE)?,
+            pointer: i32::from_le_bytes(word(5)),
+            cell: (bytes[4] & Self::OFF_TAPE == 0).then(|| u32::from_le_bytes(word(9))),
+        })
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Self {
+            position,
+            op,
+            pointer,
+            cell,
+        } = self;
+        match cell {
+            Some(cell) => write!(f, "{position}: {op} (pointer {pointer}, cell {cell})"),
+            None => write!(f, "{position}: {op} (pointer {pointer}, off the tape)"),
+        }
+    }
+}
+
+/// Writes a trace into `writer`, leaving out the records finer than the granularity.
+/// It is unsized over the writer so that the engines can take any of them.
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+pub(crate) struct Writer<W: ?Sized> {
+    granularity: Granularity,
+    writer: W,
+}
+
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+impl<W: Write> Writer<W> {
+    /// Writes the start of a trace at `granularity` into `writer`.
+    pub(crate) fn new(mut writer: W, granularity: Granularity) -> io::Result<Self> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&[granularity as u8])?;
+        Ok(Self {
+            granularity,
+            writer,
+        })
+    }
+}
+
+#[cfg(any(feature = "interpreter", feature = "machine", feature = "asm"))]
+impl<W: Write + ?Sized> Writer<W> {
+    pub(crate) fn write(&mut self, record: &Record) -> io::Result<()> {
+        if self.granularity == Granularity::Blocks && !record.op.is_block() {
+            return Ok(());
+        }
+        self.writer.write_all(&record.to_bytes())
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads the records of a trace from a reader.
+pub struct Reader<R> {
+    reader: R,
+    granularity: Granularity,
+}
+
+impl<R: Read> Reader<R> {
+    /// Reads the start of a trace from `reader`, returning an error of [`io::ErrorKind::InvalidData`] if it isn't one.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; MAGIC.len() + 1];
+        reader.read_exact(&mut header)?;
+        let granularity = match header {
+            [magic @ .., 0] if magic == MAGIC => Granularity::Instructions,
+            [magic @ .., 1] if magic == MAGIC => Granularity::Blocks,
+            _ => return Err(invalid("not a trace")),
+        };
+        Ok(Self {
+            reader,
+            granularity,
+        })
+    }
+
+    pub fn granularity(&self) -> Granularity {
+        self.granularity
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Record>;
+
+    /// Returns the next record, or an error if the trace ends in the middle of one or has an unknown op.
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        let mut bytes = [0; Record::SIZE];
+        let mut read = 0;
+        while read < Record::SIZE {
+            match self.reader.read(&mut bytes[read..]) {
+                Ok(0) if read == 0 => return None,
+                Ok(0) => return Some(Err(io::ErrorKind::UnexpectedEof.into())),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        Some(Record::from_bytes(&bytes).ok_or_else(|| invalid("unknown op in the trace")))
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Writes the trace from `reader` into `writer` as text, a line for each record which `granularity` keeps.
+/// A trace of [`Granularity::Instructions`] written out at [`Granularity::Blocks`] has the same lines
+/// as one written at it, so that the traces from the interpreter and the JIT compilers can be compared.
+pub fn print(
+    reader: impl Read,
+    mut writer: impl Write,
+    granularity: Granularity,
+) -> io::Result<()> {
+    for record in Reader::new(reader)? {
+        let record = record?;
+        if granularity == Granularity::Instructions || record.op.is_block() {
+            writeln!(writer, "{record}")?;
+        }
+    }
+    writer.flush()
+}
@@ -0,0 +1,357 @@
+//! Compiles Brainf*ck into a WebAssembly module, for runtimes that cannot take native code.
+//!
+//! The module has exactly one page of memory, 64 KiB, which is the tape, exported as "memory".
+//! It imports `putchar` and `getchar` from "env", which follow the contract of the ones in `jit/mod.rs`.
+//! They take the address of the current cell in the memory and return 0 on io errors.
+//! The exported function "run" runs the programme, and returns 1, or 0 on io errors the same way.
+
+use crate::ir::{Node, NodeKind};
+use std::fmt::{self, Display};
+
+/// The local variable holding the pointer.
+const POINTER: u32 = 0;
+/// The local variable holding the address of a cell to change.
+const CELL: u32 = 1;
+/// The functions, imported ones first.
+const PUTCHAR: u32 = 0;
+const GETCHAR: u32 = 1;
+
+/// The instructions used in the module.
+#[derive(Clone, Copy)]
+enum Ins {
+    Block,
+    Loop,
+    If,
+    End,
+    Br { depth: u32 },
+    BrIf { depth: u32 },
+    Return,
+    Call { function: u32 },
+    LocalGet { local: u32 },
+    LocalSet { local: u32 },
+    LocalTee { local: u32 },
+    Load8U,
+    Store8,
+    Const { value: i32 },
+    Eqz,
+    Add,
+    Mul,
+    And,
+}
+
+impl Ins {
+    /// Writes the binary encoding of the instruction into `code`.
+    fn encode(self, code: &mut Vec<u8>) {
+        match self {
+            // 0x40 is the type of blocks taking and returning nothing.
+            Ins::Block => code.extend_from_slice(&[0x02, 0x40]),
+            Ins::Loop => code.extend_from_slice(&[0x03, 0x40]),
+            Ins::If => code.extend_from_slice(&[0x04, 0x40]),
+            Ins::End => code.push(0x0b),
+            Ins::Br { depth } => {
+                code.push(0x0c);
+                unsigned(code, depth);
+            }
+            Ins::BrIf { depth } => {
+                code.push(0x0d);
+                unsigned(code, depth);
+            }
+            Ins::Return => code.push(0x0f),
+            Ins::Call { function } => {
+                code.push(0x10);
+                unsigned(code, function);
+            }
+            Ins::LocalGet { local } => {
+                code.push(0x20);
+                unsigned(code, local);
+            }
+            Ins::LocalSet { local } => {
+                code.push(0x21);
+                unsigned(code, local);
+            }
+            Ins::LocalTee { local } => {
+                code.push(0x22);
+                unsigned(code, local);
+            }
+            // The memory operands are the alignment and the offset, both of which are 0.
+            Ins::Load8U => code.extend_from_slice(&[0x2d, 0, 0]),
+            Ins::Store8 => code.extend_from_slice(&[0x3a, 0, 0]),
+            Ins::Const { value } => {
+                code.push(0x41);
+                signed(code, value);
+            }
+            Ins::Eqz => code.push(0x45),
+            Ins::Add => code.push(0x6a),
+            Ins::Mul => code.push(0x6c),
+            Ins::And => code.push(0x71),
+        }
+    }
+}
+
+impl Display for Ins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let local = |local| if local == POINTER { "$p" } else { "$cell" };
+        match *self {
+            Ins::Block => write!(f, "block"),
+            Ins::Loop => write!(f, "loop"),
+            Ins::If => write!(f, "if"),
+            Ins::End => write!(f, "end"),
+            Ins::Br { depth } => write!(f, "br {depth}"),
+            Ins::BrIf { depth } => write!(f, "br_if {depth}"),
+            Ins::Return => write!(f, "return"),
+            Ins::Call { function } if function == PUTCHAR => write!(f, "call $putchar"),
+            Ins::Call { .. } => write!(f, "call $getchar"),
+            Ins::LocalGet { local: l } => write!(f, "local.get {}", local(l)),
+            Ins::LocalSet { local: l } => write!(f, "local.set {}", local(l)),
+            Ins::LocalTee { local: l } => write!(f, "local.tee {}", local(l)),
+            Ins::Load8U => write!(f, "i32.load8_u"),
+            Ins::Store8 => write!(f, "i32.store8"),
+            Ins::Const { value } => write!(f, "i32.const {value}"),
+            Ins::Eqz => write!(f, "i32.eqz"),
+            Ins::Add => write!(f, "i32.add"),
+            Ins::Mul => write!(f, "i32.mul"),
+            Ins::And => write!(f, "i32.and"),
+        }
+    }
+}
+
+/// Returns the binary format of the module for `program`.
+pub fn binary(program: &[Node]) -> Vec<u8> {
+    let mut code = Vec::new();
+    // Both locals are 32 bit integers (0x7f).
+    code.extend_from_slice(&[1, 2, 0x7f]);
+    for ins in compile(program) {
+        ins.encode(&mut code);
+    }
+
+    let mut module = b"\0asm".to_vec();
+    module.extend_from_slice(&1u32.to_le_bytes());
+    // 0x60 is a function type, followed by the parameters and the results.
+    #[rustfmt::skip]
+    section(&mut module, 1, &[
+        2, // types
+        0x60, 1, 0x7f, 1, 0x7f, // (i32) -> i32 for putchar and getchar
+        0x60, 0, 1, 0x7f, // () -> i32 for run
+    ]);
+    let mut imports = vec![2];
+    for name in [b"putchar", b"getchar"] {
+        name_of(&mut imports, b"env");
+        name_of(&mut imports, name);
+        // A function of the type 0.
+        imports.extend_from_slice(&[0x00, 0]);
+    }
+    section(&mut module, 2, &imports);
+    // A function of the type 1.
+    section(&mut module, 3, &[1, 1]);
+    // A memory of exactly one page, which can't grow.
+    section(&mut module, 5, &[1, 0x01, 1, 1]);
+    let mut exports = vec![2];
+    name_of(&mut exports, b"memory");
+    exports.extend_from_slice(&[0x02, 0]);
+    name_of(&mut exports, b"run");
+    exports.extend_from_slice(&[0x00, 2]);
+    section(&mut module, 7, &exports);
+    let mut codes = vec![1];
+    unsigned(&mut codes, code.len() as u32);
+    codes.extend_from_slice(&code);
+    section(&mut module, 10, &codes);
+    module
+}
+
+/// Returns the text format of the module for `program`.
+pub fn text(program: &[Node]) -> String {
+    let mut text = String::from(
+        r#"(module
+  (import "env" "putchar" (func $putchar (param i32) (result i32)))
+  (import "env" "getchar" (func $getchar (param i32) (result i32)))
+  (memory (export "memory") 1 1)
+  (func (export "run") (result i32)
+    (local $p i32)
+    (local $cell i32)
+"#,
+    );
+    let mut depth = 2;
+    for ins in compile(program) {
+        if let Ins::End = ins {
+            depth -= 1;
+        }
+        // The last `end` is for the function, written as the closing parenthesis.
+        if depth > 1 {
+            text.push_str(&"  ".repeat(depth));
+            text.push_str(&ins.to_string());
+            text.push('\n');
+        }
+        if let Ins::Block | Ins::Loop | Ins::If = ins {
+            depth += 1;
+        }
+    }
+    text.push_str("  )\n)\n");
+    text
+}
+
+/// Writes a section with the `id` and the `content` into `module`.
+fn section(module: &mut Vec<u8>, id: u8, content: &[u8]) {
+    module.push(id);
+    unsigned(module, content.len() as u32);
+    module.extend_from_slice(content);
+}
+
+/// Writes `name` prefixed with its length into `bytes`.
+fn name_of(bytes: &mut Vec<u8>, name: &[u8]) {
+    unsigned(bytes, name.len() as u32);
+    bytes.extend_from_slice(name);
+}
+
+/// Writes `value` in unsigned LEB128, which WebAssembly uses for integers.
+fn unsigned(bytes: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Writes `value` in signed LEB128.
+fn signed(bytes: &mut Vec<u8>, mut value: i32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        // Stop when the rest is only the sign, which the last byte has as its 7th bit.
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Returns the body of "run" for `program`.
+fn compile(program: &[Node]) -> Vec<Ins> {
+    let mut instructions = Vec::with_capacity(program.len() * 8);
+    lower(&mut instructions, program);
+    // Return 1 as no io errors happened.
+    instructions.extend([Ins::Const { value: 1 }, Ins::End]);
+    instructions
+}
+
+fn lower(instructions: &mut Vec<Ins>, nodes: &[Node]) {
+    for node in nodes {
+        match node.kind {
+            NodeKind::Move { amount } => {
+                instructions.push(Ins::LocalGet { local: POINTER });
+                wrap(instructions, amount);
+                instructions.push(Ins::LocalSet { local: POINTER });
+            }
+            NodeKind::Add { offset, amount } => {
+                load(instructions, offset);
+                instructions.extend([
+                    Ins::Const { value: amount },
+                    Ins::Add,
+                    // Storing only takes the lowest 8 bits, so it wraps around by itself.
+                    Ins::Store8,
+                ]);
+            }
+            NodeKind::Set { offset, value } => {
+                address(instructions, offset);
+                instructions.extend([Ins::Const { value }, Ins::Store8]);
+            }
+            NodeKind::Scan { stride } => {
+                instructions.extend([
+                    Ins::Block,
+                    Ins::Loop,
+                    Ins::LocalGet { local: POINTER },
+                    Ins::Load8U,
+                    Ins::Eqz,
+                    Ins::BrIf { depth: 1 },
+                    Ins::LocalGet { local: POINTER },
+                ]);
+                wrap(instructions, stride);
+                instructions.extend([
+                    Ins::LocalSet { local: POINTER },
+                    Ins::Br { depth: 0 },
+                    Ins::End,
+                    Ins::End,
+                ]);
+            }
+            NodeKind::MulAdd { offset, factor } => {
+                load(instructions, offset);
+                instructions.extend([
+                    Ins::LocalGet { local: POINTER },
+                    Ins::Load8U,
+                    Ins::Const { value: factor },
+                    Ins::Mul,
+                    Ins::Add,
+                    Ins::Store8,
+                ]);
+            }
+            NodeKind::Output | NodeKind::Input => {
+                let function = if let NodeKind::Output = node.kind {
+                    PUTCHAR
+                } else {
+                    GETCHAR
+                };
+                // Return 0 if the host function does.
+                instructions.extend([
+                    Ins::LocalGet { local: POINTER },
+                    Ins::Call { function },
+                    Ins::Eqz,
+                    Ins::If,
+                    Ins::Const { value: 0 },
+                    Ins::Return,
+                    Ins::End,
+                ]);
+            }
+            // The module has no host function to show the tape with.
+            NodeKind::Dump => {}
+            NodeKind::Loop { ref body } => {
+                instructions.extend([
+                    Ins::Block,
+                    Ins::Loop,
+                    Ins::LocalGet { local: POINTER },
+                    Ins::Load8U,
+                    Ins::Eqz,
+                    Ins::BrIf { depth: 1 },
+                ]);
+                lower(instructions, body);
+                instructions.extend([Ins::Br { depth: 0 }, Ins::End, Ins::End]);
+            }
+        }
+    }
+}
+
+/// Adds `amount` to the value on the stack, wrapping it around the tape.
+fn wrap(instructions: &mut Vec<Ins>, amount: i32) {
+    instructions.extend([
+        Ins::Const { value: amount },
+        Ins::Add,
+        Ins::Const { value: 0xffff },
+        Ins::And,
+    ]);
+}
+
+/// Pushes the address of the cell `offset` cells away.
+fn address(instructions: &mut Vec<Ins>, offset: i32) {
+    instructions.push(Ins::LocalGet { local: POINTER });
+    if offset != 0 {
+        wrap(instructions, offset);
+    }
+}
+
+/// Pushes the address of the cell `offset` cells away and then the value of it,
+/// so that the value can be changed and stored back.
+fn load(instructions: &mut Vec<Ins>, offset: i32) {
+    if offset == 0 {
+        instructions.extend([
+            Ins::LocalGet { local: POINTER },
+            Ins::LocalGet { local: POINTER },
+        ]);
+    } else {
+        address(instructions, offset);
+        instructions.extend([Ins::LocalTee { local: CELL }, Ins::LocalGet { local: CELL }]);
+    }
+    instructions.push(Ins::Load8U);
+}